@@ -0,0 +1,72 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Accessed page harvesting, for working set estimation.
+//!
+//! On x86_64, the accessed flags of EPT entries are set by the processor,
+//! which requires accessed and dirty flags to be enabled in the EPTP. On the
+//! other architectures, the flags are managed by software: an access to a page
+//! whose flag was cleared faults, and [`AddrSpace::handle_page_fault`] sets it
+//! again.
+
+use alloc::{vec, vec::Vec};
+
+use axerrno::{AxResult, ax_err};
+use bit_field::BitArray;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, is_aligned_4k};
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+use crate::GuestPhysAddr;
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Returns the pages in the given range accessed since the last call, and
+    /// clears their accessed flags.
+    ///
+    /// Bit `i` of the returned bitmap (bit `i % 64` of word `i / 64`) is set if
+    /// the page at `start + i * 4K` was accessed. A huge page has a single
+    /// flag, so all of its 4K pages are reported together. The TLB is flushed
+    /// once after all flags are cleared.
+    pub fn get_and_clear_accessed(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+    ) -> AxResult<Vec<u64>> {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let end = start + size;
+        let mut bitmap = vec![0u64; (size / PAGE_SIZE_4K).div_ceil(64)];
+        let mut addr = start;
+        while addr < end {
+            let Some((accessed, page_size)) = self.pt.test_and_clear_accessed(addr) else {
+                addr += PAGE_SIZE_4K;
+                continue;
+            };
+            let page_end = (addr.align_down(page_size) + page_size as usize).min(end);
+            if accessed {
+                for i in (addr - start) / PAGE_SIZE_4K..(page_end - start) / PAGE_SIZE_4K {
+                    bitmap.set_bit(i, true);
+                }
+            }
+            addr = page_end;
+        }
+        self.pt.flush_tlb(None);
+        Ok(bitmap)
+    }
+}
@@ -12,26 +12,94 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use memory_addr::{PageIter4K, PhysAddr};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PhysAddr};
 use page_table_multiarch::{MappingFlags, PageSize, PagingHandler};
 
 use super::Backend;
-use crate::{GuestPhysAddr, npt::NestedPageTable as PageTable};
+use crate::{
+    AxNumaHal, GuestPhysAddr, GuestPhysAddrRange, NestedFlags, npt::NestedPageTable as PageTable,
+};
+
+/// Huge page sizes tried by the allocation backend, largest first.
+const HUGE_PAGE_SIZES: [PageSize; 2] = [PageSize::Size1G, PageSize::Size2M];
+
+/// Placement of the frames of an allocation mapping on host NUMA nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumaPolicy {
+    /// Allocate frames from the node if possible, and from any node otherwise.
+    Preferred(usize),
+    /// Allocate frames from the node only.
+    Bind(usize),
+}
+
+/// A [`NumaPolicy`] bound to the node-aware allocator of the paging handler.
+#[derive(Clone, Copy)]
+pub struct NumaPlacement {
+    policy: NumaPolicy,
+    alloc_frames_on_node: fn(usize, usize, usize) -> Option<PhysAddr>,
+}
+
+impl NumaPlacement {
+    /// Returns the placement policy.
+    pub const fn policy(&self) -> NumaPolicy {
+        self.policy
+    }
+}
+
+impl core::fmt::Debug for NumaPlacement {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        self.policy.fmt(f)
+    }
+}
+
+impl<H: AxNumaHal> Backend<H> {
+    /// Creates a new allocation mapping backend, which allocates frames from
+    /// host NUMA nodes according to `policy`.
+    pub const fn new_alloc_numa(populate: bool, policy: NumaPolicy) -> Self {
+        Self::Alloc {
+            populate,
+            numa: Some(NumaPlacement {
+                policy,
+                alloc_frames_on_node: H::alloc_frames_on_node,
+            }),
+            swap: None,
+            _phantom: core::marker::PhantomData,
+        }
+    }
+}
 
 impl<H: PagingHandler> Backend<H> {
     /// Creates a new allocation mapping backend.
     pub const fn new_alloc(populate: bool) -> Self {
         Self::Alloc {
             populate,
+            numa: None,
+            swap: None,
             _phantom: core::marker::PhantomData,
         }
     }
 
+    /// Allocates `count` contiguous frames aligned to `align` bytes for an
+    /// allocation mapping, following its NUMA policy.
+    pub(super) fn alloc_frames_for_mapping(&self, count: usize, align: usize) -> Option<PhysAddr> {
+        let Self::Alloc {
+            numa: Some(numa), ..
+        } = self
+        else {
+            return H::alloc_frames(count, align);
+        };
+        match numa.policy {
+            NumaPolicy::Preferred(node) => (numa.alloc_frames_on_node)(count, align, node)
+                .or_else(|| H::alloc_frames(count, align)),
+            NumaPolicy::Bind(node) => (numa.alloc_frames_on_node)(count, align, node),
+        }
+    }
+
     pub(crate) fn map_alloc(
         &self,
         start: GuestPhysAddr,
         size: usize,
-        flags: MappingFlags,
+        flags: NestedFlags,
         pt: &mut PageTable<H>,
         populate: bool,
     ) -> bool {
@@ -42,28 +110,51 @@ impl<H: PagingHandler> Backend<H> {
             flags,
             populate
         );
+        let end = start + size;
+        let mut addr = start;
         if populate {
             // allocate all possible physical frames for populated mapping.
-            for addr in PageIter4K::new(start, start + size).unwrap() {
-                if H::alloc_frame()
+            while addr < end {
+                let range = GuestPhysAddrRange::new(addr, end);
+                if let Some(page_size) = self.map_huge_page(addr, range, flags, pt) {
+                    addr += page_size as usize;
+                    continue;
+                }
+                if self
+                    .alloc_frames_for_mapping(1, PAGE_SIZE_4K)
                     .and_then(|frame| pt.map(addr, frame, PageSize::Size4K, flags).ok())
                     .is_none()
                 {
                     return false;
                 }
+                addr += PAGE_SIZE_4K;
             }
-            true
         } else {
-            // Map to a empty entry for on-demand mapping.
-            pt.map_region(
-                start,
-                |_va| PhysAddr::from(0),
-                size,
-                MappingFlags::empty(),
-                false,
-            )
-            .is_ok()
+            // Map to a empty entry for on-demand mapping. Aligned blocks that
+            // can be backed by huge pages are left unmapped, so that the fault
+            // handler is able to map a huge page there.
+            while addr < end {
+                if addr.is_aligned(PageSize::Size2M) && end - addr >= PageSize::Size2M as usize {
+                    addr += PageSize::Size2M as usize;
+                    continue;
+                }
+                let next = (addr.align_down(PageSize::Size2M) + PageSize::Size2M as usize).min(end);
+                if pt
+                    .map_region(
+                        addr,
+                        |_va| PhysAddr::from(0),
+                        next - addr,
+                        MappingFlags::empty(),
+                        false,
+                    )
+                    .is_err()
+                {
+                    return false;
+                }
+                addr = next;
+            }
         }
+        true
     }
 
     pub(crate) fn unmap_alloc(
@@ -74,16 +165,34 @@ impl<H: PagingHandler> Backend<H> {
         _populate: bool,
     ) -> bool {
         debug!("unmap_alloc: [{:#x}, {:#x})", start, start + size);
-        for addr in PageIter4K::new(start, start + size).unwrap() {
+        let end = start + size;
+        let mut addr = start;
+        while addr < end {
+            // A page swapped out has no frame, only its swap slot is freed.
+            if let Some(store) = self.swap_store()
+                && let Some(slot) = pt.take_swap_slot(addr)
+            {
+                store.discard_slot(slot);
+                addr += PAGE_SIZE_4K;
+                continue;
+            }
+            // A huge page crossing the range boundaries is split first, so
+            // that only the frames in the range are freed.
+            if let Ok((_, _, page_size)) = pt.query(addr)
+                && page_size.is_huge()
+                && (!addr.is_aligned(page_size) || end - addr < page_size as usize)
+                && !pt.split_huge_page(addr)
+            {
+                return false;
+            }
             if let Ok((frame, _, page_size)) = pt.unmap(addr) {
-                // Deallocate the physical frame if there is a mapping in the
+                // Deallocate the physical frames if there is a mapping in the
                 // page table.
-                if page_size.is_huge() {
-                    return false;
-                }
-                H::dealloc_frame(frame);
+                H::dealloc_frames(frame, page_size as usize / PAGE_SIZE_4K);
+                addr += page_size as usize;
             } else {
                 // It's fine if the page is not mapped.
+                addr += PAGE_SIZE_4K;
             }
         }
         true
@@ -92,20 +201,73 @@ impl<H: PagingHandler> Backend<H> {
     pub(crate) fn handle_page_fault_alloc(
         &self,
         vaddr: GuestPhysAddr,
-        orig_flags: MappingFlags,
+        area_range: GuestPhysAddrRange,
+        orig_flags: NestedFlags,
         pt: &mut PageTable<H>,
-        populate: bool,
     ) -> bool {
-        if populate {
-            false // Populated mappings should not trigger page faults.
-        } else {
-            // Allocate a physical frame lazily and map it to the fault address.
-            // `vaddr` does not need to be aligned. It will be automatically
-            // aligned during `pt.remap` regardless of the page size.
-            let Some(frame) = H::alloc_frame() else {
-                return false;
+        if pt.query(vaddr).is_ok() {
+            // Already mapped, e.g., by another vCPU faulting on the same page.
+            return true;
+        }
+        if pt.swap_slot(vaddr).is_some() {
+            return self.swap_in_page(vaddr, orig_flags, pt);
+        }
+        // Back the whole aligned block with a huge page if possible.
+        if let Some(page_size) = self.map_huge_page(vaddr, area_range, orig_flags, pt) {
+            zero_frames::<H>(
+                pt.translate(vaddr.align_down(page_size)).unwrap(),
+                page_size as usize,
+            );
+            return true;
+        }
+        // Allocate a physical frame lazily and map it to the fault address.
+        // `vaddr` does not need to be aligned. It will be automatically
+        // aligned during `pt.map_or_remap_4k`.
+        let Some(frame) = self.alloc_frames_for_mapping(1, PAGE_SIZE_4K) else {
+            return false;
+        };
+        zero_frames::<H>(frame, PAGE_SIZE_4K);
+        if !pt.map_or_remap_4k(vaddr, frame, orig_flags) {
+            H::dealloc_frames(frame, 1);
+            return false;
+        }
+        true
+    }
+
+    /// Maps the largest huge page containing `vaddr` that lies within `range`
+    /// to newly allocated contiguous frames.
+    ///
+    /// Returns `None` if no such block is free in the page table, or
+    /// contiguous frames are not available.
+    fn map_huge_page(
+        &self,
+        vaddr: GuestPhysAddr,
+        range: GuestPhysAddrRange,
+        flags: NestedFlags,
+        pt: &mut PageTable<H>,
+    ) -> Option<PageSize> {
+        for page_size in HUGE_PAGE_SIZES {
+            let start = vaddr.align_down(page_size);
+            if start < range.start
+                || range.end - start < page_size as usize
+                || !pt.is_block_unused(start, page_size)
+            {
+                continue;
+            }
+            let count = page_size as usize / PAGE_SIZE_4K;
+            let Some(frame) = self.alloc_frames_for_mapping(count, page_size as usize) else {
+                continue;
             };
-            pt.remap(vaddr, frame, orig_flags)
+            if pt.map(start, frame, page_size, flags).is_ok() {
+                return Some(page_size);
+            }
+            H::dealloc_frames(frame, count);
         }
+        None
     }
 }
+
+/// Fills the physical memory at `paddr` with zeros.
+pub(super) fn zero_frames<H: PagingHandler>(paddr: PhysAddr, size: usize) {
+    unsafe { core::ptr::write_bytes(H::phys_to_virt(paddr).as_mut_ptr(), 0, size) };
+}
@@ -0,0 +1,176 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PageIter4K, PhysAddr};
+use page_table_multiarch::{MappingFlags, PageSize, PagingHandler};
+use spin::Mutex;
+
+use super::Backend;
+use crate::{GuestPhysAddr, NestedFlags, npt::NestedPageTable as PageTable};
+
+/// Reference counts of physical frames shared by copy-on-write mappings.
+///
+/// Only frames mapped by more than one address space are recorded. A frame
+/// that is not in the table is exclusively owned by its only mapping.
+#[derive(Default)]
+pub struct FrameRefTable {
+    refs: Mutex<BTreeMap<PhysAddr, usize>>,
+}
+
+impl FrameRefTable {
+    /// Creates a new empty reference table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records one more mapping of `frame`.
+    pub(crate) fn share(&self, frame: PhysAddr) {
+        *self.refs.lock().entry(frame).or_insert(1) += 1;
+    }
+
+    /// Drops one mapping of `frame`.
+    ///
+    /// Returns `true` if the caller held the last reference, and the frame
+    /// should be deallocated.
+    pub(crate) fn release(&self, frame: PhysAddr) -> bool {
+        let mut refs = self.refs.lock();
+        match refs.get_mut(&frame) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 1 {
+                    refs.remove(&frame);
+                }
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Returns whether `frame` is mapped by more than one address space.
+    pub(crate) fn is_shared(&self, frame: PhysAddr) -> bool {
+        self.refs.lock().contains_key(&frame)
+    }
+
+    /// Returns the number of frames currently shared.
+    pub fn shared_frames(&self) -> usize {
+        self.refs.lock().len()
+    }
+}
+
+impl<H: PagingHandler> Backend<H> {
+    /// Creates a new copy-on-write mapping backend.
+    pub const fn new_cow(frame_refs: Arc<FrameRefTable>) -> Self {
+        Self::Cow {
+            frame_refs,
+            _phantom: core::marker::PhantomData,
+        }
+    }
+
+    pub(crate) fn map_cow(&self, start: GuestPhysAddr, size: usize, pt: &mut PageTable<H>) -> bool {
+        debug!("map_cow: [{:#x}, {:#x})", start, start + size);
+        // Pages that are already present are kept as is, the others are mapped
+        // to empty entries for on-demand mapping.
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            if pt.query(addr).is_err()
+                && !pt.map_or_remap_4k(addr, PhysAddr::from(0), MappingFlags::empty())
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(crate) fn unmap_cow(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        pt: &mut PageTable<H>,
+        frame_refs: &FrameRefTable,
+    ) -> bool {
+        debug!("unmap_cow: [{:#x}, {:#x})", start, start + size);
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            if let Ok((frame, _, page_size)) = pt.unmap(addr) {
+                if page_size.is_huge() {
+                    return false;
+                }
+                // Only the last user of a shared frame deallocates it. The
+                // frame may come from a NUMA node of an allocation mapping.
+                if frame_refs.release(frame) {
+                    H::dealloc_frames(frame, 1);
+                }
+            }
+        }
+        true
+    }
+
+    pub(crate) fn handle_page_fault_cow(
+        &self,
+        vaddr: GuestPhysAddr,
+        orig_flags: NestedFlags,
+        access_flags: MappingFlags,
+        pt: &mut PageTable<H>,
+        frame_refs: &FrameRefTable,
+    ) -> bool {
+        match pt.query(vaddr) {
+            Ok((paddr, flags, PageSize::Size4K)) => {
+                if !access_flags.contains(MappingFlags::WRITE)
+                    || flags.contains(MappingFlags::WRITE)
+                {
+                    return false;
+                }
+                let frame = paddr.align_down_4k();
+                if !frame_refs.is_shared(frame) {
+                    // The other users are gone, reuse the frame directly.
+                    return pt.remap(vaddr, frame, orig_flags);
+                }
+                let Some(new_frame) = H::alloc_frame() else {
+                    return false;
+                };
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        H::phys_to_virt(frame).as_ptr(),
+                        H::phys_to_virt(new_frame).as_mut_ptr(),
+                        PAGE_SIZE_4K,
+                    );
+                }
+                if !pt.remap(vaddr, new_frame, orig_flags) {
+                    H::dealloc_frame(new_frame);
+                    return false;
+                }
+                // The other users may have copied the frame too since it was
+                // found shared, so whether this was the last one is only known
+                // when releasing it.
+                if frame_refs.release(frame) {
+                    H::dealloc_frames(frame, 1);
+                }
+                true
+            }
+            Ok(_) => false,
+            Err(_) => {
+                // Never populated, allocate a private frame lazily.
+                let Some(frame) = H::alloc_frame() else {
+                    return false;
+                };
+                super::alloc::zero_frames::<H>(frame, PAGE_SIZE_4K);
+                if !pt.map_or_remap_4k(vaddr, frame, orig_flags) {
+                    H::dealloc_frame(frame);
+                    return false;
+                }
+                true
+            }
+        }
+    }
+}
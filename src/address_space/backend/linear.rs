@@ -13,10 +13,10 @@
 // limitations under the License.
 
 use memory_addr::PhysAddr;
-use page_table_multiarch::{MappingFlags, PagingHandler};
+use page_table_multiarch::PagingHandler;
 
 use super::Backend;
-use crate::{GuestPhysAddr, npt::NestedPageTable as PageTable};
+use crate::{GuestPhysAddr, NestedFlags, npt::NestedPageTable as PageTable};
 
 impl<H: PagingHandler> Backend<H> {
     /// Creates a new linear mapping backend.
@@ -28,7 +28,7 @@ impl<H: PagingHandler> Backend<H> {
         &self,
         start: GuestPhysAddr,
         size: usize,
-        flags: MappingFlags,
+        flags: NestedFlags,
         pt: &mut PageTable<H>,
         pa_va_offset: usize,
     ) -> bool {
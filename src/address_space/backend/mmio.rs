@@ -0,0 +1,47 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use page_table_multiarch::PagingHandler;
+
+use super::Backend;
+use crate::{GuestPhysAddr, npt::NestedPageTable as PageTable};
+
+impl<H: PagingHandler> Backend<H> {
+    /// Creates a new trapping MMIO region backend with the given identifier.
+    pub const fn new_mmio(id: usize) -> Self {
+        Self::Mmio { id }
+    }
+
+    pub(crate) fn map_mmio(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        _pt: &mut PageTable<H>,
+        id: usize,
+    ) -> bool {
+        debug!("map_mmio: [{:#x}, {:#x}) id={}", start, start + size, id);
+        // Nothing is mapped, so that every access traps.
+        true
+    }
+
+    pub(crate) fn unmap_mmio(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        _pt: &mut PageTable<H>,
+    ) -> bool {
+        debug!("unmap_mmio: [{:#x}, {:#x})", start, start + size);
+        true
+    }
+}
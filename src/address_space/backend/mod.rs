@@ -14,22 +14,42 @@
 
 //! Memory mapping backends.
 
+use ::alloc::sync::Arc;
 use memory_set::MappingBackend;
 use page_table_multiarch::{MappingFlags, PagingHandler};
 
-use crate::{GuestPhysAddr, npt::NestedPageTable as PageTable};
+use crate::{GuestPhysAddr, GuestPhysAddrRange, NestedFlags, npt::NestedPageTable as PageTable};
 
 mod alloc;
+mod cow;
 mod linear;
+mod mmio;
+mod rom;
+mod shared;
+mod swap;
+
+pub use alloc::{NumaPlacement, NumaPolicy};
+pub use cow::FrameRefTable;
+pub(crate) use rom::load_rom;
+pub use shared::SharedMemory;
+pub use swap::{MemorySwapStore, SwapStore};
 
 /// A unified enum type for different memory mapping backends.
 ///
-/// Currently, two backends are implemented:
+/// Currently, six backends are implemented:
 ///
 /// - **Linear**: used for linear mappings. The target physical frames are
 ///   contiguous and their addresses should be known when creating the mapping.
 /// - **Allocation**: used in general, or for lazy mappings. The target physical
 ///   frames are obtained from the global allocator.
+/// - **Copy-on-write**: used for address spaces created by forking. The target
+///   physical frames are shared read-only until one of the users writes them.
+/// - **Shared**: used for memory shared between address spaces. The target
+///   physical frames are owned by a reference-counted [`SharedMemory`].
+/// - **MMIO**: used for emulated device regions. Nothing is mapped, so that
+///   every access traps to the hypervisor.
+/// - **ROM**: used for firmware images. The target physical frames hold a copy
+///   of the image and are never mapped writable.
 pub enum Backend<H: PagingHandler> {
     /// Linear mapping backend.
     ///
@@ -45,60 +65,161 @@ pub enum Backend<H: PagingHandler> {
     /// If `populate` is `true`, all physical frames are allocated when the
     /// mapping is created, and no page faults are triggered during the memory
     /// access. Otherwise, the physical frames are allocated on demand (by
-    /// handling page faults).
+    /// handling page faults). Pages of a populated mapping released later,
+    /// e.g., by the balloon, are allocated on demand as well. Frames allocated
+    /// on demand are zeroed.
+    ///
+    /// Aligned 2M and 1G blocks within the mapping are backed by huge pages
+    /// when contiguous frames are available, and by 4K pages otherwise. A huge
+    /// page partially unmapped or protected is split into 4K pages of the same
+    /// frames, which are then freed one by one, so the paging handler must
+    /// accept freeing part of a contiguous allocation.
+    ///
+    /// Pages of a swappable mapping can be swapped out to a [`SwapStore`],
+    /// and are read back on the next access.
     Alloc {
         /// Whether to populate the physical frames when creating the mapping.
         populate: bool,
+        /// The host NUMA node to allocate the physical frames from, if any.
+        numa: Option<NumaPlacement>,
+        /// The store to swap out pages to, if the mapping is swappable.
+        swap: Option<Arc<dyn SwapStore>>,
+        /// A phantom data for the paging handler.
+        _phantom: core::marker::PhantomData<H>,
+    },
+    /// Copy-on-write mapping backend.
+    ///
+    /// Frames are shared read-only between the forked address spaces. A write
+    /// fault copies the frame privately and remaps it writable, and a frame is
+    /// deallocated only after its last user unmaps it. Pages that were never
+    /// populated are allocated on demand like the lazy allocation backend.
+    Cow {
+        /// Reference counts of the frames shared with other address spaces.
+        frame_refs: Arc<FrameRefTable>,
         /// A phantom data for the paging handler.
         _phantom: core::marker::PhantomData<H>,
     },
+    /// Shared memory mapping backend.
+    ///
+    /// The virtual address `start + offset` is mapped to the frame at `offset`
+    /// in `shm`. Unmapping never deallocates the frames, they are freed when
+    /// the last reference to `shm` is dropped.
+    Shared {
+        /// The shared memory object.
+        shm: Arc<SharedMemory<H>>,
+        /// The virtual address at which the shared memory starts.
+        start: GuestPhysAddr,
+    },
+    /// Trapping MMIO region backend.
+    ///
+    /// The region is reserved without any backing. Page faults in it are
+    /// reported as MMIO accesses to the region `id` (see
+    /// [`PageFaultOutcome`](crate::PageFaultOutcome)).
+    Mmio {
+        /// The identifier of the region, chosen by the creator.
+        id: usize,
+    },
+    /// ROM backend.
+    ///
+    /// Like the shared memory backend, the virtual address `start + offset` is
+    /// mapped to the frame at `offset` in `rom`, but always without write
+    /// permission. Writes fault, and are reported as ROM writes (see
+    /// [`PageFaultOutcome`](crate::PageFaultOutcome)).
+    Rom {
+        /// The frames holding the ROM image.
+        rom: Arc<SharedMemory<H>>,
+        /// The virtual address at which the ROM starts.
+        start: GuestPhysAddr,
+    },
 }
 
 impl<H: PagingHandler> Clone for Backend<H> {
     fn clone(&self) -> Self {
-        match *self {
-            Self::Linear { pa_va_offset } => Self::Linear { pa_va_offset },
-            Self::Alloc { populate, .. } => Self::Alloc {
+        match self {
+            Self::Linear { pa_va_offset } => Self::Linear {
+                pa_va_offset: *pa_va_offset,
+            },
+            Self::Alloc {
                 populate,
+                numa,
+                swap,
+                ..
+            } => Self::Alloc {
+                populate: *populate,
+                numa: *numa,
+                swap: swap.clone(),
                 _phantom: core::marker::PhantomData,
             },
+            Self::Cow { frame_refs, .. } => Self::new_cow(frame_refs.clone()),
+            Self::Shared { shm, start } => Self::new_shared(shm.clone(), *start),
+            Self::Mmio { id } => Self::new_mmio(*id),
+            Self::Rom { rom, start } => Self::new_rom(rom.clone(), *start),
         }
     }
 }
 
 impl<H: PagingHandler> MappingBackend for Backend<H> {
     type Addr = GuestPhysAddr;
-    type Flags = MappingFlags;
+    type Flags = NestedFlags;
     type PageTable = PageTable<H>;
 
     fn map(
         &self,
         start: GuestPhysAddr,
         size: usize,
-        flags: MappingFlags,
+        flags: NestedFlags,
         pt: &mut PageTable<H>,
     ) -> bool {
-        match *self {
-            Self::Linear { pa_va_offset } => self.map_linear(start, size, flags, pt, pa_va_offset),
-            Self::Alloc { populate, .. } => self.map_alloc(start, size, flags, pt, populate),
+        match self {
+            Self::Linear { pa_va_offset } => self.map_linear(start, size, flags, pt, *pa_va_offset),
+            Self::Alloc { populate, .. } => self.map_alloc(start, size, flags, pt, *populate),
+            Self::Cow { .. } => self.map_cow(start, size, pt),
+            Self::Shared {
+                shm,
+                start: shm_start,
+            } => self.map_shared(start, size, flags, pt, shm, *shm_start),
+            Self::Mmio { id } => self.map_mmio(start, size, pt, *id),
+            Self::Rom {
+                rom,
+                start: rom_start,
+            } => self.map_shared(
+                start,
+                size,
+                flags - MappingFlags::WRITE,
+                pt,
+                rom,
+                *rom_start,
+            ),
         }
     }
 
     fn unmap(&self, start: GuestPhysAddr, size: usize, pt: &mut PageTable<H>) -> bool {
-        match *self {
-            Self::Linear { pa_va_offset } => self.unmap_linear(start, size, pt, pa_va_offset),
-            Self::Alloc { populate, .. } => self.unmap_alloc(start, size, pt, populate),
+        match self {
+            Self::Linear { pa_va_offset } => self.unmap_linear(start, size, pt, *pa_va_offset),
+            Self::Alloc { populate, .. } => self.unmap_alloc(start, size, pt, *populate),
+            Self::Cow { frame_refs, .. } => self.unmap_cow(start, size, pt, frame_refs),
+            Self::Shared { .. } | Self::Rom { .. } => self.unmap_shared(start, size, pt),
+            Self::Mmio { .. } => self.unmap_mmio(start, size, pt),
         }
     }
 
+    // The TLB is not flushed here, callers of `MemorySet::protect` flush it
+    // once after all areas are updated.
     fn protect(
         &self,
         start: GuestPhysAddr,
         size: usize,
-        new_flags: MappingFlags,
+        new_flags: NestedFlags,
         page_table: &mut PageTable<H>,
     ) -> bool {
-        page_table.protect_region(start, size, new_flags)
+        let new_flags = match self {
+            // Frames may be shared, write permission is granted by the write
+            // fault handler.
+            Self::Cow { .. } => new_flags - MappingFlags::WRITE,
+            Self::Rom { .. } => new_flags - MappingFlags::WRITE,
+            _ => new_flags,
+        };
+        page_table.protect_region_deferred(start, size, new_flags)
     }
 }
 
@@ -106,13 +227,47 @@ impl<H: PagingHandler> Backend<H> {
     pub(crate) fn handle_page_fault(
         &self,
         vaddr: GuestPhysAddr,
-        orig_flags: MappingFlags,
+        area_range: GuestPhysAddrRange,
+        orig_flags: NestedFlags,
+        access_flags: MappingFlags,
         page_table: &mut PageTable<H>,
     ) -> bool {
-        match *self {
-            Self::Linear { .. } => false, // Linear mappings should not trigger page faults.
-            Self::Alloc { populate, .. } => {
-                self.handle_page_fault_alloc(vaddr, orig_flags, page_table, populate)
+        match self {
+            // Linear and shared mappings should not trigger page faults, and
+            // MMIO accesses and ROM writes are emulated by the caller.
+            Self::Linear { .. } | Self::Shared { .. } | Self::Mmio { .. } | Self::Rom { .. } => {
+                false
+            }
+            Self::Alloc { .. } => {
+                self.handle_page_fault_alloc(vaddr, area_range, orig_flags, page_table)
+            }
+            Self::Cow { frame_refs, .. } => {
+                self.handle_page_fault_cow(vaddr, orig_flags, access_flags, page_table, frame_refs)
+            }
+        }
+    }
+
+    /// Frees the frames mapped in the given range, and maps the range on
+    /// demand again like a lazy allocation mapping.
+    ///
+    /// Returns `false` if the frames are not owned by the mapping.
+    pub(crate) fn discard(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        page_table: &mut PageTable<H>,
+    ) -> bool {
+        match self {
+            Self::Linear { .. } | Self::Shared { .. } | Self::Mmio { .. } | Self::Rom { .. } => {
+                false
+            }
+            Self::Alloc { .. } => {
+                self.unmap_alloc(start, size, page_table, false)
+                    && self.map_alloc(start, size, MappingFlags::empty().into(), page_table, false)
+            }
+            Self::Cow { frame_refs, .. } => {
+                self.unmap_cow(start, size, page_table, frame_refs)
+                    && self.map_cow(start, size, page_table)
             }
         }
     }
@@ -0,0 +1,50 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::sync::Arc;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{PAGE_SIZE_4K, align_up_4k};
+use page_table_multiarch::PagingHandler;
+
+use super::{Backend, SharedMemory};
+use crate::GuestPhysAddr;
+
+impl<H: PagingHandler> Backend<H> {
+    /// Creates a new ROM backend, with the first byte of `rom` mapped at
+    /// `start`.
+    pub const fn new_rom(rom: Arc<SharedMemory<H>>, start: GuestPhysAddr) -> Self {
+        Self::Rom { rom, start }
+    }
+}
+
+/// Allocates frames for a ROM image and loads `data` into them. The rest of
+/// the last page is zeroed.
+pub(crate) fn load_rom<H: PagingHandler>(data: &[u8]) -> AxResult<Arc<SharedMemory<H>>> {
+    if data.is_empty() {
+        return ax_err!(InvalidInput, "empty ROM image");
+    }
+    let rom = SharedMemory::<H>::new(align_up_4k(data.len()))?;
+    for (i, chunk) in data.chunks(PAGE_SIZE_4K).enumerate() {
+        let frame = rom.frame(i * PAGE_SIZE_4K).unwrap();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                chunk.as_ptr(),
+                H::phys_to_virt(frame).as_mut_ptr(),
+                chunk.len(),
+            )
+        };
+    }
+    Ok(rom)
+}
@@ -0,0 +1,121 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::marker::PhantomData;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::{PAGE_SIZE_4K, PhysAddr, is_aligned_4k};
+use page_table_multiarch::{PageSize, PagingHandler};
+
+use super::Backend;
+use crate::{GuestPhysAddr, NestedFlags, npt::NestedPageTable as PageTable};
+
+/// A set of physical frames that can be mapped into multiple address spaces.
+///
+/// The frames are allocated when the object is created, and deallocated when
+/// the last reference is dropped, i.e., after every address space mapping it
+/// has unmapped it.
+pub struct SharedMemory<H: PagingHandler> {
+    frames: Vec<PhysAddr>,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: PagingHandler> SharedMemory<H> {
+    /// Allocates a zeroed shared memory object of `size` bytes.
+    pub fn new(size: usize) -> AxResult<Arc<Self>> {
+        if size == 0 || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "shared memory size not aligned");
+        }
+        let mut shm = Self {
+            frames: Vec::with_capacity(size / PAGE_SIZE_4K),
+            _phantom: PhantomData,
+        };
+        for _ in 0..size / PAGE_SIZE_4K {
+            // Frames allocated so far are released by `drop` on failure.
+            let frame = H::alloc_frame()
+                .ok_or_else(|| ax_err_type!(NoMemory, "allocate shared frame failed"))?;
+            unsafe { core::ptr::write_bytes(H::phys_to_virt(frame).as_mut_ptr(), 0, PAGE_SIZE_4K) };
+            shm.frames.push(frame);
+        }
+        Ok(Arc::new(shm))
+    }
+
+    /// Returns the size of the shared memory in bytes.
+    pub fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE_4K
+    }
+
+    /// Returns the physical frame backing the page at `offset`.
+    pub fn frame(&self, offset: usize) -> Option<PhysAddr> {
+        self.frames.get(offset / PAGE_SIZE_4K).copied()
+    }
+}
+
+impl<H: PagingHandler> Drop for SharedMemory<H> {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            H::dealloc_frame(frame);
+        }
+    }
+}
+
+impl<H: PagingHandler> Backend<H> {
+    /// Creates a new shared memory mapping backend, with the first byte of
+    /// `shm` mapped at `start`.
+    pub const fn new_shared(shm: Arc<SharedMemory<H>>, start: GuestPhysAddr) -> Self {
+        Self::Shared { shm, start }
+    }
+
+    pub(crate) fn map_shared(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: NestedFlags,
+        pt: &mut PageTable<H>,
+        shm: &SharedMemory<H>,
+        shm_start: GuestPhysAddr,
+    ) -> bool {
+        debug!(
+            "map_shared: [{:#x}, {:#x}) {:?}",
+            start,
+            start + size,
+            flags
+        );
+        let offset = start - shm_start;
+        for page_offset in (0..size).step_by(PAGE_SIZE_4K) {
+            let Some(frame) = shm.frame(offset + page_offset) else {
+                return false;
+            };
+            if pt
+                .map(start + page_offset, frame, PageSize::Size4K, flags)
+                .is_err()
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(crate) fn unmap_shared(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        pt: &mut PageTable<H>,
+    ) -> bool {
+        debug!("unmap_shared: [{:#x}, {:#x})", start, start + size);
+        // The frames are owned by the shared memory object, not by the mapping.
+        pt.unmap_region(start, size).is_ok()
+    }
+}
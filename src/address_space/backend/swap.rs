@@ -0,0 +1,179 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::PAGE_SIZE_4K;
+use page_table_multiarch::PagingHandler;
+use spin::Mutex;
+
+use super::Backend;
+use crate::{GuestPhysAddr, NestedFlags, npt::NestedPageTable as PageTable};
+
+/// A backing store for pages swapped out of allocation mappings.
+///
+/// Each swapped out page occupies a slot of the store until it is read back
+/// or discarded.
+pub trait SwapStore: Send + Sync {
+    /// Writes the page to a free slot, and returns the slot.
+    fn write_page(&self, page: &[u8; PAGE_SIZE_4K]) -> AxResult<usize>;
+    /// Reads the page saved in `slot`.
+    fn read_page(&self, slot: usize, page: &mut [u8; PAGE_SIZE_4K]) -> AxResult;
+    /// Frees `slot`, its contents are no longer needed.
+    fn discard_slot(&self, slot: usize);
+}
+
+/// A [`SwapStore`] keeping the pages in host memory, optionally limited to a
+/// number of slots.
+pub struct MemorySwapStore {
+    slots: Mutex<Vec<Option<Box<[u8; PAGE_SIZE_4K]>>>>,
+    max_slots: usize,
+}
+
+impl MemorySwapStore {
+    /// Creates an empty store of at most `max_slots` pages.
+    pub const fn new(max_slots: usize) -> Self {
+        Self {
+            slots: Mutex::new(Vec::new()),
+            max_slots,
+        }
+    }
+
+    /// Returns the number of slots in use.
+    pub fn used_slots(&self) -> usize {
+        self.slots
+            .lock()
+            .iter()
+            .filter(|slot| slot.is_some())
+            .count()
+    }
+}
+
+impl SwapStore for MemorySwapStore {
+    fn write_page(&self, page: &[u8; PAGE_SIZE_4K]) -> AxResult<usize> {
+        let mut slots = self.slots.lock();
+        let slot = match slots.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None if slots.len() < self.max_slots => {
+                slots.push(None);
+                slots.len() - 1
+            }
+            None => return ax_err!(StorageFull, "swap store full"),
+        };
+        slots[slot] = Some(Box::new(*page));
+        Ok(slot)
+    }
+
+    fn read_page(&self, slot: usize, page: &mut [u8; PAGE_SIZE_4K]) -> AxResult {
+        let slots = self.slots.lock();
+        let saved = slots
+            .get(slot)
+            .and_then(Option::as_ref)
+            .ok_or_else(|| ax_err_type!(NotFound, "swap slot not in use"))?;
+        page.copy_from_slice(saved.as_ref());
+        Ok(())
+    }
+
+    fn discard_slot(&self, slot: usize) {
+        if let Some(saved) = self.slots.lock().get_mut(slot) {
+            *saved = None;
+        }
+    }
+}
+
+impl<H: PagingHandler> Backend<H> {
+    /// Creates a new allocation mapping backend, whose pages can be swapped
+    /// out to `store`.
+    pub const fn new_alloc_swappable(populate: bool, store: Arc<dyn SwapStore>) -> Self {
+        Self::Alloc {
+            populate,
+            numa: None,
+            swap: Some(store),
+            _phantom: core::marker::PhantomData,
+        }
+    }
+
+    /// Returns the swap store of a swappable allocation mapping.
+    pub(crate) fn swap_store(&self) -> Option<&Arc<dyn SwapStore>> {
+        match self {
+            Self::Alloc { swap, .. } => swap.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Writes the page at `vaddr` to the swap store, and frees its frame.
+    ///
+    /// Returns `false` if the page is not mapped, a huge page containing it is
+    /// split first. If the page cannot be unmapped or its slot recorded, the
+    /// slot is freed and the page stays mapped. The TLB is not flushed.
+    pub(crate) fn swap_out_page(
+        &self,
+        vaddr: GuestPhysAddr,
+        pt: &mut PageTable<H>,
+    ) -> AxResult<bool> {
+        let Some(store) = self.swap_store() else {
+            return ax_err!(InvalidInput, "mapping not swappable");
+        };
+        let Ok((_, _, page_size)) = pt.query(vaddr) else {
+            return Ok(false);
+        };
+        if page_size.is_huge() && !pt.split_huge_page(vaddr) {
+            return ax_err!(NoMemory, "failed to split huge page");
+        }
+        let (frame, flags, _) = pt.query_nested(vaddr).unwrap();
+        let page = unsafe { &*(H::phys_to_virt(frame).as_ptr() as *const [u8; PAGE_SIZE_4K]) };
+        let slot = store.write_page(page)?;
+        if pt.unmap(vaddr).is_err() {
+            store.discard_slot(slot);
+            return ax_err!(BadState, "failed to unmap swapped out page");
+        }
+        // The last level table is kept by `unmap`, so the slot should be
+        // recorded. Otherwise the page is mapped again.
+        if !pt.set_swap_slot(vaddr, slot) {
+            store.discard_slot(slot);
+            pt.map_or_remap_4k(vaddr, frame, flags);
+            return ax_err!(BadState, "failed to record swap slot");
+        }
+        H::dealloc_frames(frame, 1);
+        Ok(true)
+    }
+
+    /// Reads the page at `vaddr` back from the swap store into a new frame,
+    /// and maps it with `flags`.
+    ///
+    /// Returns `true` without doing anything if the page is not swapped out.
+    pub(crate) fn swap_in_page(
+        &self,
+        vaddr: GuestPhysAddr,
+        flags: NestedFlags,
+        pt: &mut PageTable<H>,
+    ) -> bool {
+        let (Some(store), Some(slot)) = (self.swap_store(), pt.swap_slot(vaddr)) else {
+            return true;
+        };
+        let Some(frame) = self.alloc_frames_for_mapping(1, PAGE_SIZE_4K) else {
+            return false;
+        };
+        let page =
+            unsafe { &mut *(H::phys_to_virt(frame).as_mut_ptr() as *mut [u8; PAGE_SIZE_4K]) };
+        if store.read_page(slot, page).is_err() || !pt.map_or_remap_4k(vaddr, frame, flags) {
+            H::dealloc_frames(frame, 1);
+            return false;
+        }
+        pt.take_swap_slot(vaddr);
+        store.discard_slot(slot);
+        true
+    }
+}
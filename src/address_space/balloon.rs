@@ -0,0 +1,87 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Memory ballooning.
+//!
+//! Pages given up by the guest are unmapped and their frames are returned to
+//! the allocator. The areas containing them stay mapped, and a ballooned page
+//! is faulted in again with a new frame if the guest accesses it.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K};
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, Backend};
+use crate::{GuestPhysAddr, GuestPhysAddrRange};
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Inflates the balloon with the given pages, freeing their frames.
+    ///
+    /// Each address must be 4K-aligned and belong to an allocation or
+    /// copy-on-write mapping, and must not be watched or pinned. Pages already
+    /// in the balloon are skipped. No page is released if any of the addresses
+    /// is invalid.
+    pub fn balloon_inflate(&mut self, gpa_list: &[GuestPhysAddr]) -> AxResult {
+        for &gpa in gpa_list {
+            if !gpa.is_aligned_4k() {
+                return ax_err!(InvalidInput, "address not aligned");
+            }
+            if !self.va_range.contains(gpa) {
+                return ax_err!(InvalidInput, "address out of range");
+            }
+            match self.areas.find(gpa).map(|area| area.backend()) {
+                Some(Backend::Alloc { .. } | Backend::Cow { .. }) => {}
+                Some(_) => return ax_err!(InvalidInput, "page cannot be ballooned"),
+                None => return ax_err!(InvalidInput, "page not mapped"),
+            }
+            self.check_releasable(GuestPhysAddrRange::from_start_size(gpa, PAGE_SIZE_4K))?;
+        }
+
+        self.batch(|aspace| {
+            for &gpa in gpa_list {
+                if aspace.ballooned.contains(&gpa) {
+                    continue;
+                }
+                let area = aspace.areas.find(gpa).unwrap();
+                if !area.backend().discard(gpa, PAGE_SIZE_4K, &mut aspace.pt) {
+                    return ax_err!(BadState, "failed to release ballooned page");
+                }
+                aspace.ballooned.insert(gpa);
+            }
+            Ok(())
+        })
+    }
+
+    /// Deflates the balloon, giving the given pages back to the guest.
+    ///
+    /// The pages are mapped to new frames on their next access. Pages not in
+    /// the balloon, including those already accessed by the guest, are
+    /// skipped.
+    pub fn balloon_deflate(&mut self, gpa_list: &[GuestPhysAddr]) -> AxResult {
+        if gpa_list.iter().any(|gpa| !gpa.is_aligned_4k()) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        for gpa in gpa_list {
+            self.ballooned.remove(gpa);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of ballooned pages in the area containing `gpa`.
+    pub fn ballooned_pages(&self, gpa: GuestPhysAddr) -> usize {
+        self.areas.find(gpa).map_or(0, |area| {
+            self.ballooned.range(area.start()..area.end()).count()
+        })
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shared and private pages of confidential guests.
+//!
+//! Every page is either shared with the host or private to the guest. All
+//! pages are shared initially. The host cannot access private pages through
+//! [`AddrSpace::translate_and_get_limit`] or
+//! [`AddrSpace::translated_byte_buffer`].
+
+use alloc::vec;
+
+use axerrno::{AxResult, ax_err};
+use bit_field::BitArray;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PageIter4K, is_aligned_4k};
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, Backend};
+use crate::{GuestPhysAddr, GuestPhysAddrRange};
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Converts the private pages in the given range to shared pages.
+    ///
+    /// See [`convert_to_private`](Self::convert_to_private).
+    pub fn convert_to_shared(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        self.convert_pages(start, size, false)
+    }
+
+    /// Converts the shared pages in the given range to private pages.
+    ///
+    /// The range must be mapped by allocation or copy-on-write mappings, and
+    /// must not contain pinned or watched pages. The frame of each converted
+    /// page is freed, and a populated page is remapped to a new zeroed frame,
+    /// so that no contents survive the conversion. If no frame is left, the
+    /// page is populated on the next access instead. Pages already of the
+    /// target kind are left as they are. No page is converted if the range
+    /// is invalid.
+    pub fn convert_to_private(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        self.convert_pages(start, size, true)
+    }
+
+    /// Returns whether the page containing `gpa` is private to the guest.
+    pub fn is_private(&self, gpa: GuestPhysAddr) -> bool {
+        self.va_range.contains(gpa)
+            && !self.private.is_empty()
+            && self.private.get_bit(self.page_index(gpa))
+    }
+
+    fn convert_pages(&mut self, start: GuestPhysAddr, size: usize, private: bool) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            match self.areas.find(addr).map(|area| area.backend()) {
+                Some(Backend::Alloc { .. } | Backend::Cow { .. }) => {}
+                Some(_) => return ax_err!(InvalidInput, "page cannot be converted"),
+                None => return ax_err!(InvalidInput, "page not mapped"),
+            }
+        }
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        self.check_releasable(range)?;
+        // Discarding a part of a huge page splits it, which is the only step
+        // of the conversion that can fail, so do it before converting any.
+        self.split_huge_pages(range)?;
+
+        if self.private.is_empty() {
+            self.private = vec![0; (self.size() / PAGE_SIZE_4K).div_ceil(64)];
+        }
+        self.batch(|aspace| {
+            for addr in PageIter4K::new(start, start + size).unwrap() {
+                if aspace.is_private(addr) == private {
+                    continue;
+                }
+                let populated = aspace.translate(addr).is_some();
+                aspace.discard(addr, PAGE_SIZE_4K)?;
+                let index = aspace.page_index(addr);
+                aspace.private.set_bit(index, private);
+                if populated {
+                    aspace.handle_page_fault(addr, MappingFlags::empty());
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns the number of bytes accessible by the host from `gpa` to the
+    /// next private page, or `None` if the page of `gpa` is private.
+    pub(super) fn shared_limit(&self, gpa: GuestPhysAddr, limit: usize) -> Option<usize> {
+        if self.private.is_empty() {
+            return Some(limit);
+        }
+        let end = (gpa + limit).min(self.end());
+        let mut addr = gpa.align_down_4k();
+        while addr < end {
+            if self.private.get_bit(self.page_index(addr)) {
+                return (addr > gpa).then(|| addr - gpa);
+            }
+            addr += PAGE_SIZE_4K;
+        }
+        Some(limit)
+    }
+
+    fn page_index(&self, gpa: GuestPhysAddr) -> usize {
+        (gpa - self.base()) / PAGE_SIZE_4K
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dirty page logging.
+//!
+//! On x86_64, dirty pages are tracked by the dirty flags of EPT entries, which
+//! requires accessed and dirty flags to be enabled in the EPTP. On the other
+//! architectures, pages in the logged range are write-protected, and the first
+//! write to each page is recorded by [`AddrSpace::handle_page_fault`].
+
+use alloc::{vec, vec::Vec};
+
+use axerrno::{AxResult, ax_err};
+use bit_field::BitArray;
+#[cfg(target_arch = "x86_64")]
+use memory_addr::PageIter4K;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, is_aligned_4k};
+use page_table_multiarch::PagingHandler;
+
+use super::AddrSpace;
+#[cfg(not(target_arch = "x86_64"))]
+use super::{Backend, MappingFlags, NestedFlags, overlapping_areas};
+#[cfg(not(target_arch = "x86_64"))]
+use crate::npt::NestedPageTable as PageTable;
+use crate::{GuestPhysAddr, GuestPhysAddrRange};
+
+/// State of an active dirty page log.
+pub(super) struct DirtyLog {
+    range: GuestPhysAddrRange,
+    /// Pages written since the last harvest, one bit per 4K page.
+    #[cfg(not(target_arch = "x86_64"))]
+    bitmap: Vec<u64>,
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Starts logging writes to the pages in the given range.
+    ///
+    /// Huge pages in the range are split into 4K pages, and no huge pages are
+    /// mapped there while logging, so that dirty pages are reported in 4K
+    /// granularity. Only one range can be
+    /// logged at a time.
+    pub fn start_dirty_log(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        if self.dirty_log.is_some() {
+            return ax_err!(AlreadyExists, "dirty log already started");
+        }
+
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        self.split_huge_pages(range)?;
+
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                // Discard the dirty flags set before logging starts.
+                for addr in PageIter4K::new(start, start + size).unwrap() {
+                    self.pt.test_and_clear_dirty(addr);
+                }
+                self.pt.flush_tlb(None);
+                self.dirty_log = Some(DirtyLog { range });
+            } else {
+                self.write_protect(range);
+                self.dirty_log = Some(DirtyLog {
+                    range,
+                    bitmap: vec![0; (size / PAGE_SIZE_4K).div_ceil(64)],
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the pages in the given range written since logging started or
+    /// since the last call, and resets their state.
+    ///
+    /// Bit `i` of the returned bitmap (bit `i % 64` of word `i / 64`) is set if
+    /// the page at `start + i * 4K` is dirty. The range must be within the
+    /// range passed to [`start_dirty_log`](Self::start_dirty_log).
+    pub fn get_and_clear_dirty_log(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+    ) -> AxResult<Vec<u64>> {
+        let Some(log) = self.dirty_log.as_mut() else {
+            return ax_err!(BadState, "dirty log not started");
+        };
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        if !log.range.contains_range(range) {
+            return ax_err!(InvalidInput, "address out of dirty log range");
+        }
+
+        let mut bitmap = vec![0u64; (size / PAGE_SIZE_4K).div_ceil(64)];
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                for (i, addr) in PageIter4K::new(start, start + size).unwrap().enumerate() {
+                    if self.pt.test_and_clear_dirty(addr) {
+                        bitmap.set_bit(i, true);
+                    }
+                }
+                self.pt.flush_tlb(None);
+            } else {
+                let first = (start - log.range.start) / PAGE_SIZE_4K;
+                for i in 0..size / PAGE_SIZE_4K {
+                    if log.bitmap.get_bit(first + i) {
+                        bitmap.set_bit(i, true);
+                        log.bitmap.set_bit(first + i, false);
+                    }
+                }
+                self.write_protect(range);
+            }
+        }
+        Ok(bitmap)
+    }
+
+    /// Stops dirty page logging.
+    ///
+    /// Write permissions removed for logging are restored. Huge pages split
+    /// for logging stay mapped with 4K pages.
+    pub fn stop_dirty_log(&mut self) -> AxResult {
+        match self.dirty_log.take() {
+            None => ax_err!(BadState, "dirty log not started"),
+            #[cfg(target_arch = "x86_64")]
+            Some(_) => Ok(()),
+            #[cfg(not(target_arch = "x86_64"))]
+            Some(log) => {
+                let mut restored = Vec::new();
+                for (area, range) in overlapping_areas(&self.areas, log.range) {
+                    // Copy-on-write mappings regain write permission on the
+                    // next write fault, shared frames must stay read-only.
+                    if area.flags().flags.contains(MappingFlags::WRITE)
+                        && !matches!(area.backend(), Backend::Cow { .. })
+                    {
+                        self.pt
+                            .protect_region(range.start, range.size(), area.flags());
+                        restored.push((range, area.flags()));
+                    }
+                }
+                for (range, flags) in restored {
+                    self.update_watch_flags(range, |_| flags);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes the write permission of every present page in `range`.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub(super) fn write_protect(&mut self, range: GuestPhysAddrRange) {
+        for (area, range) in overlapping_areas(&self.areas, range) {
+            if area.flags().flags.contains(MappingFlags::WRITE) {
+                self.pt.protect_region(
+                    range.start,
+                    range.size(),
+                    area.flags() - MappingFlags::WRITE,
+                );
+            }
+        }
+        self.update_watch_flags(range, |flags| flags - MappingFlags::WRITE);
+    }
+}
+
+impl DirtyLog {
+    /// Returns the logged range.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub(super) fn range(&self) -> GuestPhysAddrRange {
+        self.range
+    }
+
+    /// Returns whether the page at `vaddr` is being logged.
+    pub(super) fn contains(&self, vaddr: GuestPhysAddr) -> bool {
+        self.range.contains(vaddr)
+    }
+
+    /// Handles a page fault in the logged range.
+    ///
+    /// Write faults on pages write-protected for logging are recorded and the
+    /// write permission is restored. Other faults are passed to the backend,
+    /// and pages mapped by it are write-protected until the first write.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub(super) fn handle_page_fault<H: PagingHandler>(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        area_range: GuestPhysAddrRange,
+        orig_flags: NestedFlags,
+        access_flags: MappingFlags,
+        backend: &Backend<H>,
+        pt: &mut PageTable<H>,
+    ) -> bool {
+        let page = vaddr.align_down_4k();
+        let write_protected = pt
+            .query(page)
+            .is_ok_and(|(_, flags, _)| !flags.contains(MappingFlags::WRITE));
+        let is_write = access_flags.contains(MappingFlags::WRITE);
+
+        let handled = if is_write && write_protected && !matches!(backend, Backend::Cow { .. }) {
+            pt.protect_region(page, PAGE_SIZE_4K, orig_flags)
+        } else {
+            backend.handle_page_fault(vaddr, area_range, orig_flags, access_flags, pt)
+        };
+        if handled {
+            if is_write {
+                self.bitmap
+                    .set_bit((page - self.range.start) / PAGE_SIZE_4K, true);
+            } else if orig_flags.flags.contains(MappingFlags::WRITE) {
+                pt.protect_region(page, PAGE_SIZE_4K, orig_flags - MappingFlags::WRITE);
+            }
+        }
+        handled
+    }
+}
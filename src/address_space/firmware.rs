@@ -0,0 +1,298 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Guest memory maps for firmware tables, derived from the mapped areas.
+//!
+//! Each mapped area is described as RAM, except trapping MMIO regions which
+//! are described as MMIO, and ROM regions which are described as reserved.
+//! Region tags given by the caller override the kind of the parts of the
+//! areas they cover.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, is_aligned_4k};
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, Backend, overlapping_areas};
+use crate::GuestPhysAddrRange;
+
+/// The kind of a guest memory region in firmware tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    /// Usable RAM.
+    Ram,
+    /// RAM reserved for the firmware or the hypervisor.
+    Reserved,
+    /// RAM holding ACPI tables.
+    Acpi,
+    /// Device memory.
+    Mmio,
+}
+
+/// An entry of an x86 E820 memory map, laid out as in `boot_params`.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct E820Entry {
+    /// The start address of the region.
+    pub addr: u64,
+    /// The size of the region in bytes.
+    pub size: u64,
+    /// The type of the region, one of the `E820_*` constants.
+    pub kind: u32,
+}
+
+/// E820 type of usable RAM.
+pub const E820_RAM: u32 = 1;
+/// E820 type of reserved regions.
+pub const E820_RESERVED: u32 = 2;
+/// E820 type of reclaimable ACPI tables.
+pub const E820_ACPI: u32 = 3;
+
+/// The `/memory` and `/reserved-memory` nodes of a guest device tree.
+///
+/// Both nodes use two address cells and two size cells. The [`Display`]
+/// implementation prints them in device tree source syntax.
+///
+/// [`Display`]: fmt::Display
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdtMemoryNodes {
+    /// The `reg` ranges of the `/memory` node, including reserved RAM.
+    pub memory: Vec<GuestPhysAddrRange>,
+    /// The children of the `/reserved-memory` node, all of them `no-map`.
+    pub reserved: Vec<GuestPhysAddrRange>,
+}
+
+impl FdtMemoryNodes {
+    /// Encodes the `reg` property of the `/memory` node.
+    pub fn memory_reg(&self) -> Vec<u8> {
+        fdt_reg(&self.memory)
+    }
+}
+
+/// Encodes the ranges as a `reg` property with two address cells and two
+/// size cells.
+pub fn fdt_reg(ranges: &[GuestPhysAddrRange]) -> Vec<u8> {
+    ranges
+        .iter()
+        .flat_map(|range| {
+            let start = range.start.as_usize() as u64;
+            let size = range.size() as u64;
+            start.to_be_bytes().into_iter().chain(size.to_be_bytes())
+        })
+        .collect()
+}
+
+fn fmt_reg(f: &mut fmt::Formatter, range: &GuestPhysAddrRange) -> fmt::Result {
+    let start = range.start.as_usize() as u64;
+    let size = range.size() as u64;
+    write!(
+        f,
+        "<{:#x} {:#x} {:#x} {:#x}>",
+        start >> 32,
+        start as u32,
+        size >> 32,
+        size as u32
+    )
+}
+
+impl fmt::Display for FdtMemoryNodes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(first) = self.memory.first() {
+            writeln!(f, "memory@{:x} {{", first.start.as_usize())?;
+            writeln!(f, "\tdevice_type = \"memory\";")?;
+            write!(f, "\treg = ")?;
+            for (i, range) in self.memory.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                fmt_reg(f, range)?;
+            }
+            writeln!(f, ";")?;
+            writeln!(f, "}};")?;
+        }
+        if !self.reserved.is_empty() {
+            writeln!(f, "reserved-memory {{")?;
+            writeln!(f, "\t#address-cells = <2>;")?;
+            writeln!(f, "\t#size-cells = <2>;")?;
+            writeln!(f, "\tranges;")?;
+            for range in &self.reserved {
+                writeln!(f, "\treserved@{:x} {{", range.start.as_usize())?;
+                write!(f, "\t\treg = ")?;
+                fmt_reg(f, range)?;
+                writeln!(f, ";")?;
+                writeln!(f, "\t\tno-map;")?;
+                writeln!(f, "\t}};")?;
+            }
+            writeln!(f, "}};")?;
+        }
+        Ok(())
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Generates the E820 memory map of the guest.
+    ///
+    /// RAM is reported as usable, reserved RAM and MMIO as reserved, and ACPI
+    /// tables as reclaimable. See [`memory_regions`](Self::memory_regions) for
+    /// the requirements on `tags`.
+    pub fn e820_table(
+        &self,
+        tags: &[(GuestPhysAddrRange, MemoryRegionKind)],
+    ) -> AxResult<Vec<E820Entry>> {
+        let mut table: Vec<E820Entry> = Vec::new();
+        for (range, kind) in self.memory_regions(tags)? {
+            let kind = match kind {
+                MemoryRegionKind::Ram => E820_RAM,
+                MemoryRegionKind::Reserved | MemoryRegionKind::Mmio => E820_RESERVED,
+                MemoryRegionKind::Acpi => E820_ACPI,
+            };
+            let entry = E820Entry {
+                addr: range.start.as_usize() as u64,
+                size: range.size() as u64,
+                kind,
+            };
+            match table.last_mut() {
+                Some(last) if last.kind == kind && last.addr + last.size == entry.addr => {
+                    last.size += entry.size;
+                }
+                _ => table.push(entry),
+            }
+        }
+        Ok(table)
+    }
+
+    /// Generates the `/memory` and `/reserved-memory` device tree nodes of the
+    /// guest.
+    ///
+    /// All RAM is listed in `/memory`, and reserved RAM and ACPI tables in
+    /// `/reserved-memory` as well. MMIO regions are left out. See
+    /// [`memory_regions`](Self::memory_regions) for the requirements on
+    /// `tags`.
+    pub fn fdt_memory_nodes(
+        &self,
+        tags: &[(GuestPhysAddrRange, MemoryRegionKind)],
+    ) -> AxResult<FdtMemoryNodes> {
+        let mut nodes = FdtMemoryNodes {
+            memory: Vec::new(),
+            reserved: Vec::new(),
+        };
+        for (range, kind) in self.memory_regions(tags)? {
+            match kind {
+                MemoryRegionKind::Mmio => continue,
+                MemoryRegionKind::Reserved | MemoryRegionKind::Acpi => nodes.reserved.push(range),
+                MemoryRegionKind::Ram => {}
+            }
+            match nodes.memory.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => nodes.memory.push(range),
+            }
+        }
+        Ok(nodes)
+    }
+
+    /// Returns the mapped regions of the guest with their kinds, in ascending
+    /// address order. Adjacent regions of the same kind are merged.
+    ///
+    /// Only the plugged blocks of hotplug regions are described, unplugged
+    /// blocks have no backing and are left out like unmapped memory. Areas
+    /// mapped with `DEVICE`, such as passthrough device memory, are described
+    /// as MMIO.
+    ///
+    /// Each tag must be 4K-aligned, must not overlap other tags, and must be
+    /// covered by mapped areas entirely, so that the map cannot describe memory
+    /// that does not exist.
+    pub fn memory_regions(
+        &self,
+        tags: &[(GuestPhysAddrRange, MemoryRegionKind)],
+    ) -> AxResult<Vec<(GuestPhysAddrRange, MemoryRegionKind)>> {
+        let mut tags = tags.to_vec();
+        tags.sort_by_key(|(range, _)| range.start);
+        for (i, (range, _)) in tags.iter().enumerate() {
+            if !range.start.is_aligned_4k() || !is_aligned_4k(range.size()) {
+                return ax_err!(InvalidInput, "region tag not aligned");
+            }
+            if i > 0 && tags[i - 1].0.end > range.start {
+                return ax_err!(InvalidInput, "region tags overlap");
+            }
+            let mut covered = range.start;
+            for (_, part) in overlapping_areas(&self.areas, *range) {
+                if part.start != covered {
+                    break;
+                }
+                covered = part.end;
+            }
+            if range.is_empty() || covered != range.end {
+                return ax_err!(InvalidInput, "region tag not mapped");
+            }
+        }
+
+        let mut regions: Vec<(GuestPhysAddrRange, MemoryRegionKind)> = Vec::new();
+        let mut push = |range: GuestPhysAddrRange, kind| match regions.last_mut() {
+            Some((last, last_kind)) if *last_kind == kind && last.end == range.start => {
+                last.end = range.end;
+            }
+            _ => regions.push((range, kind)),
+        };
+        let mut parts = Vec::new();
+        for area in self.areas.iter() {
+            let default_kind = match area.backend() {
+                Backend::Mmio { .. } => MemoryRegionKind::Mmio,
+                _ if area.flags().flags.contains(MappingFlags::DEVICE) => MemoryRegionKind::Mmio,
+                Backend::Rom { .. } => MemoryRegionKind::Reserved,
+                _ => MemoryRegionKind::Ram,
+            };
+            match self.hotplug_region(area.start()) {
+                Some(region) => {
+                    for plugged in region.plugged_ranges() {
+                        let start = plugged.start.max(area.start());
+                        let end = plugged.end.min(area.end());
+                        if start < end {
+                            parts.push((GuestPhysAddrRange::new(start, end), default_kind));
+                        }
+                    }
+                }
+                None => parts.push((area.va_range(), default_kind)),
+            }
+        }
+
+        let mut tags = tags.iter().peekable();
+        for (part, default_kind) in parts {
+            let mut cursor = part.start;
+            while let Some((range, kind)) = tags.peek() {
+                if range.start >= part.end {
+                    break;
+                }
+                // Tags may cover unplugged blocks, which are skipped.
+                if range.end > cursor {
+                    let start = range.start.max(cursor);
+                    if cursor < start {
+                        push(GuestPhysAddrRange::new(cursor, start), default_kind);
+                    }
+                    cursor = range.end.min(part.end);
+                    push(GuestPhysAddrRange::new(start, cursor), *kind);
+                    if range.end > part.end {
+                        break;
+                    }
+                }
+                tags.next();
+            }
+            if cursor < part.end {
+                push(GuestPhysAddrRange::new(cursor, part.end), default_kind);
+            }
+        }
+        Ok(regions)
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Software walker of guest stage-1 page tables.
+//!
+//! The guest page tables are read through the nested mappings of the address
+//! space, so they must live in populated guest memory. The walker does not
+//! depend on the host architecture.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::MemoryAddr;
+use page_table_multiarch::PagingHandler;
+
+use super::{AddrSpace, MappingFlags};
+use crate::{GuestPhysAddr, GuestVirtAddr};
+
+/// Paging modes of guest stage-1 page tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestPagingMode {
+    /// x86_64 4-level paging, with 48-bit virtual addresses.
+    X86Level4,
+    /// x86_64 5-level paging, with 57-bit virtual addresses.
+    X86Level5,
+    /// AArch64 stage-1 translation with the 4K granule.
+    ///
+    /// `va_bits` is `64 - TnSZ` of the translation regime, from 25 to 48. The
+    /// root should be taken from `TTBR0_EL1` or `TTBR1_EL1`, according to the
+    /// upper bits of the address being translated.
+    Aarch64 {
+        /// The number of significant virtual address bits.
+        va_bits: u8,
+    },
+    /// RISC-V Sv39 paging.
+    Sv39,
+    /// RISC-V Sv48 paging.
+    Sv48,
+}
+
+const ENTRY_COUNT: u64 = 512;
+
+mod x86 {
+    pub const PRESENT: u64 = 1 << 0;
+    pub const WRITABLE: u64 = 1 << 1;
+    pub const USER: u64 = 1 << 2;
+    pub const ACCESSED: u64 = 1 << 5;
+    pub const DIRTY: u64 = 1 << 6;
+    pub const HUGE_PAGE: u64 = 1 << 7;
+    pub const NO_EXECUTE: u64 = 1 << 63;
+    pub const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+}
+
+mod aarch64 {
+    pub const VALID: u64 = 1 << 0;
+    pub const TABLE_OR_PAGE: u64 = 1 << 1;
+    pub const AP_EL0: u64 = 1 << 6;
+    pub const AP_RO: u64 = 1 << 7;
+    pub const AF: u64 = 1 << 10;
+    pub const PXN: u64 = 1 << 53;
+    pub const UXN: u64 = 1 << 54;
+    pub const PXN_TABLE: u64 = 1 << 59;
+    pub const UXN_TABLE: u64 = 1 << 60;
+    pub const AP_NO_EL0_TABLE: u64 = 1 << 61;
+    pub const AP_RO_TABLE: u64 = 1 << 62;
+    pub const ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+}
+
+mod riscv {
+    pub const V: u64 = 1 << 0;
+    pub const R: u64 = 1 << 1;
+    pub const W: u64 = 1 << 2;
+    pub const X: u64 = 1 << 3;
+    pub const U: u64 = 1 << 4;
+    pub const A: u64 = 1 << 6;
+    pub const D: u64 = 1 << 7;
+    pub const PPN_MASK: u64 = (1 << 44) - 1;
+}
+
+/// Checks that the bits of `va` above `bits` are copies of the highest
+/// significant bit.
+fn check_canonical(va: u64, bits: usize) -> AxResult {
+    match (va as i64) >> (bits - 1) {
+        0 | -1 => Ok(()),
+        _ => ax_err!(InvalidInput, "non-canonical guest virtual address"),
+    }
+}
+
+/// Checks the access permissions of a leaf entry.
+fn check_access(
+    access: MappingFlags,
+    readable: bool,
+    writable: bool,
+    executable: bool,
+) -> AxResult {
+    if (access.contains(MappingFlags::READ) && !readable)
+        || (access.contains(MappingFlags::WRITE) && !writable)
+        || (access.contains(MappingFlags::EXECUTE) && !executable)
+    {
+        return ax_err!(PermissionDenied, "guest page access denied");
+    }
+    Ok(())
+}
+
+/// The walked guest entries whose accessed or dirty flags are not set yet, with
+/// the flags to set in them.
+type AdUpdates = [Option<(GuestPhysAddr, u64)>; 5];
+
+/// Returns the address of the byte at `va` in the page of `page_shift` bits
+/// starting at `base`.
+fn page_addr(base: u64, va: u64, page_shift: usize) -> GuestPhysAddr {
+    let offset_mask = (1 << page_shift) - 1;
+    GuestPhysAddr::from_usize(((base & !offset_mask) | (va & offset_mask)) as usize)
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Translates a guest virtual address by walking the guest page tables
+    /// rooted at `root`.
+    ///
+    /// `access` is the access to check, a combination of `READ`, `WRITE` and
+    /// `EXECUTE`, with `USER` for accesses from the user mode. If `update_ad`
+    /// is `true`, the accessed flags, and on writes the dirty flag, are set in
+    /// the guest entries like the hardware walker does. Otherwise the guest
+    /// page tables are left untouched.
+    ///
+    /// The flags are only written through writable nested mappings. Guest page
+    /// tables in pages mapped read-only, e.g., copy-on-write or merged pages,
+    /// are faulted in for writing first, as a guest write would.
+    ///
+    /// Returns the following errors:
+    ///
+    /// - [`InvalidInput`](axerrno::AxError::InvalidInput): `gva` is not a
+    ///   canonical address of `mode`, or `mode` is invalid.
+    /// - [`BadAddress`](axerrno::AxError::BadAddress): a guest page table is
+    ///   not in mapped guest memory, or is not writable by the guest while
+    ///   flags have to be set in it, e.g., in a ROM region.
+    /// - [`NotFound`](axerrno::AxError::NotFound): an entry is not present.
+    /// - [`InvalidData`](axerrno::AxError::InvalidData): an entry is
+    ///   malformed, e.g., with reserved bits set.
+    /// - [`PermissionDenied`](axerrno::AxError::PermissionDenied): the page
+    ///   does not permit the access.
+    pub fn translate_guest_virt(
+        &mut self,
+        root: GuestPhysAddr,
+        mode: GuestPagingMode,
+        gva: GuestVirtAddr,
+        access: MappingFlags,
+        update_ad: bool,
+    ) -> AxResult<GuestPhysAddr> {
+        let va = gva.as_usize() as u64;
+        let (gpa, updates) = match mode {
+            GuestPagingMode::X86Level4 => self.walk_x86(root, 4, va, access),
+            GuestPagingMode::X86Level5 => self.walk_x86(root, 5, va, access),
+            GuestPagingMode::Aarch64 { va_bits } => {
+                self.walk_aarch64(root, va_bits as usize, va, access)
+            }
+            GuestPagingMode::Sv39 => self.walk_riscv(root, 3, va, access),
+            GuestPagingMode::Sv48 => self.walk_riscv(root, 4, va, access),
+        }?;
+        if update_ad {
+            for (entry, flags) in updates.into_iter().flatten() {
+                self.set_guest_entry_flags(entry, flags)?;
+            }
+        }
+        Ok(gpa)
+    }
+
+    /// Returns the address and the value of the entry `index` of the guest
+    /// page table at `table`.
+    fn guest_entry(&self, table: GuestPhysAddr, index: u64) -> AxResult<(GuestPhysAddr, u64)> {
+        let addr = table.align_down_4k() + index as usize * 8;
+        let paddr = self
+            .translate(addr)
+            .ok_or_else(|| ax_err_type!(BadAddress, "guest page table not mapped"))?;
+        let entry = unsafe { &*H::phys_to_virt(paddr).as_ptr().cast::<AtomicU64>() };
+        Ok((addr, entry.load(Ordering::Acquire)))
+    }
+
+    /// Sets `flags` in the guest entry at `addr`.
+    ///
+    /// The frame behind a read-only nested mapping may be shared with other
+    /// guests, or be ROM, so the page is faulted in for writing first.
+    fn set_guest_entry_flags(&mut self, addr: GuestPhysAddr, flags: u64) -> AxResult {
+        let writable = |aspace: &Self| match aspace.pt.query(addr) {
+            Ok((paddr, mapping_flags, _)) if mapping_flags.contains(MappingFlags::WRITE) => {
+                Some(paddr)
+            }
+            _ => None,
+        };
+        let mut paddr = writable(self);
+        if paddr.is_none() && self.handle_page_fault(addr, MappingFlags::WRITE) {
+            paddr = writable(self);
+        }
+        let Some(paddr) = paddr else {
+            return ax_err!(BadAddress, "guest page table not writable");
+        };
+        let entry = unsafe { &*H::phys_to_virt(paddr).as_ptr().cast::<AtomicU64>() };
+        entry.fetch_or(flags, Ordering::AcqRel);
+        Ok(())
+    }
+
+    fn walk_x86(
+        &self,
+        root: GuestPhysAddr,
+        levels: usize,
+        va: u64,
+        access: MappingFlags,
+    ) -> AxResult<(GuestPhysAddr, AdUpdates)> {
+        use x86::*;
+
+        check_canonical(va, 12 + 9 * levels)?;
+        let mut table = root;
+        let mut updates = AdUpdates::default();
+        let (mut writable, mut user, mut executable) = (true, true, true);
+        for level in (0..levels).rev() {
+            let shift = 12 + 9 * level;
+            let (entry, pte) = self.guest_entry(table, (va >> shift) % ENTRY_COUNT)?;
+            if pte & PRESENT == 0 {
+                return ax_err!(NotFound, "guest page not present");
+            }
+            if pte & ACCESSED == 0 {
+                updates[level] = Some((entry, ACCESSED));
+            }
+            writable &= pte & WRITABLE != 0;
+            user &= pte & USER != 0;
+            executable &= pte & NO_EXECUTE == 0;
+
+            let huge = pte & HUGE_PAGE != 0;
+            if huge && level > 2 {
+                return ax_err!(InvalidData, "reserved bit set in guest page table");
+            }
+            if level == 0 || huge {
+                if access.contains(MappingFlags::USER) && !user {
+                    return ax_err!(PermissionDenied, "guest page access denied");
+                }
+                check_access(access, true, writable, executable)?;
+                if access.contains(MappingFlags::WRITE) && pte & DIRTY == 0 {
+                    updates[level] = Some((entry, (ACCESSED | DIRTY) & !pte));
+                }
+                return Ok((page_addr(pte & ADDR_MASK, va, shift), updates));
+            }
+            table = GuestPhysAddr::from_usize((pte & ADDR_MASK) as usize);
+        }
+        unreachable!()
+    }
+
+    fn walk_aarch64(
+        &self,
+        root: GuestPhysAddr,
+        va_bits: usize,
+        va: u64,
+        access: MappingFlags,
+    ) -> AxResult<(GuestPhysAddr, AdUpdates)> {
+        use aarch64::*;
+
+        if !(25..=48).contains(&va_bits) {
+            return ax_err!(InvalidInput, "invalid guest virtual address size");
+        }
+        // Addresses of both the lower and the upper ranges are accepted.
+        check_canonical(va, va_bits)?;
+        let levels = (va_bits - 12).div_ceil(9);
+        let mut table = root;
+        let (mut writable, mut el0, mut pxn, mut uxn) = (true, true, false, false);
+        for level in (0..levels).rev() {
+            let shift = 12 + 9 * level;
+            let index_bits = (va_bits - shift).min(9);
+            let (entry, desc) = self.guest_entry(table, (va >> shift) & ((1 << index_bits) - 1))?;
+            if desc & VALID == 0 {
+                return ax_err!(NotFound, "guest page not present");
+            }
+            let is_table = desc & TABLE_OR_PAGE != 0;
+            if level > 0 && is_table {
+                writable &= desc & AP_RO_TABLE == 0;
+                el0 &= desc & AP_NO_EL0_TABLE == 0;
+                pxn |= desc & PXN_TABLE != 0;
+                uxn |= desc & UXN_TABLE != 0;
+                table = GuestPhysAddr::from_usize((desc & ADDR_MASK) as usize);
+                continue;
+            }
+            // Blocks are not allowed at level 0 with the 4K granule, and the
+            // last level only contains pages.
+            if level == 3 || (level == 0 && !is_table) {
+                return ax_err!(InvalidData, "invalid guest block descriptor");
+            }
+
+            writable &= desc & AP_RO == 0;
+            el0 &= desc & AP_EL0 != 0;
+            pxn |= desc & PXN != 0;
+            uxn |= desc & UXN != 0;
+            let executable = if access.contains(MappingFlags::USER) {
+                if !el0 {
+                    return ax_err!(PermissionDenied, "guest page access denied");
+                }
+                !uxn
+            } else {
+                !pxn
+            };
+            check_access(access, true, writable, executable)?;
+            let mut updates = AdUpdates::default();
+            if desc & AF == 0 {
+                updates[0] = Some((entry, AF));
+            }
+            return Ok((page_addr(desc & ADDR_MASK, va, shift), updates));
+        }
+        unreachable!()
+    }
+
+    fn walk_riscv(
+        &self,
+        root: GuestPhysAddr,
+        levels: usize,
+        va: u64,
+        access: MappingFlags,
+    ) -> AxResult<(GuestPhysAddr, AdUpdates)> {
+        use riscv::*;
+
+        check_canonical(va, 12 + 9 * levels)?;
+        let mut table = root;
+        for level in (0..levels).rev() {
+            let shift = 12 + 9 * level;
+            let (entry, pte) = self.guest_entry(table, (va >> shift) % ENTRY_COUNT)?;
+            if pte & V == 0 {
+                return ax_err!(NotFound, "guest page not present");
+            }
+            if pte & (R | W) == W {
+                return ax_err!(InvalidData, "reserved permissions in guest page table");
+            }
+            let paddr = ((pte >> 10) & PPN_MASK) << 12;
+            if pte & (R | X) == 0 {
+                if level == 0 {
+                    return ax_err!(InvalidData, "guest page table too deep");
+                }
+                table = GuestPhysAddr::from_usize(paddr as usize);
+                continue;
+            }
+            if paddr & ((1 << shift) - 1) != 0 {
+                return ax_err!(InvalidData, "misaligned guest superpage");
+            }
+
+            // The supervisor may access user pages (with `SUM` set), but never
+            // execute them.
+            let user_access = access.contains(MappingFlags::USER);
+            if (user_access && pte & U == 0)
+                || (!user_access && pte & U != 0 && access.contains(MappingFlags::EXECUTE))
+            {
+                return ax_err!(PermissionDenied, "guest page access denied");
+            }
+            check_access(access, pte & R != 0, pte & W != 0, pte & X != 0)?;
+            let dirty = if access.contains(MappingFlags::WRITE) {
+                D
+            } else {
+                0
+            };
+            let mut updates = AdUpdates::default();
+            if pte & (A | dirty) != A | dirty {
+                updates[0] = Some((entry, (A | dirty) & !pte));
+            }
+            return Ok((page_addr(paddr, va, shift), updates));
+        }
+        unreachable!()
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Block-granular memory hotplug, and page pinning.
+//!
+//! A hotplug region is an allocation mapping divided into fixed-size blocks,
+//! each of which is either plugged or unplugged. Plugged blocks are backed by
+//! frames like ordinary memory, while unplugged blocks have no backing and
+//! guest accesses to them are not handled.
+
+use alloc::{vec, vec::Vec};
+
+use axerrno::{AxResult, ax_err};
+use bit_field::BitArray;
+use memory_addr::{MemoryAddr, PageIter4K, is_aligned_4k};
+use memory_set::MemoryArea;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, Backend};
+use crate::{GuestPhysAddr, GuestPhysAddrRange, mapping_err_to_ax_err};
+
+/// State of a hotplug region.
+#[derive(Clone)]
+pub(super) struct HotplugRegion {
+    range: GuestPhysAddrRange,
+    block_size: usize,
+    /// Plugged blocks, one bit per block.
+    plugged: Vec<u64>,
+}
+
+impl HotplugRegion {
+    fn block_index(&self, gpa: GuestPhysAddr) -> usize {
+        (gpa - self.range.start) / self.block_size
+    }
+
+    pub(super) fn range(&self) -> GuestPhysAddrRange {
+        self.range
+    }
+
+    pub(super) fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        self.range.contains(gpa)
+    }
+
+    pub(super) fn is_plugged(&self, gpa: GuestPhysAddr) -> bool {
+        self.plugged.get_bit(self.block_index(gpa))
+    }
+
+    /// Returns the range of the block containing `gpa`.
+    pub(super) fn block_range(&self, gpa: GuestPhysAddr) -> GuestPhysAddrRange {
+        let start = self.range.start + self.block_index(gpa) * self.block_size;
+        GuestPhysAddrRange::from_start_size(start, self.block_size)
+    }
+
+    /// Returns the ranges of the plugged blocks, with adjacent blocks merged.
+    pub(super) fn plugged_ranges(&self) -> Vec<GuestPhysAddrRange> {
+        let mut ranges: Vec<GuestPhysAddrRange> = Vec::new();
+        let mut block = self.range.start;
+        while block < self.range.end {
+            let end = block + self.block_size;
+            if self.is_plugged(block) {
+                match ranges.last_mut() {
+                    Some(last) if last.end == block => last.end = end,
+                    _ => ranges.push(GuestPhysAddrRange::new(block, end)),
+                }
+            }
+            block = end;
+        }
+        ranges
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Add a new hotplug region of `size` bytes, divided into blocks of
+    /// `block_size` bytes.
+    ///
+    /// `block_size` must be a power of two and a multiple of 4K, and both
+    /// `start` and `size` must be aligned to it. All blocks are unplugged
+    /// initially.
+    pub fn map_hotplug(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        block_size: usize,
+        flags: MappingFlags,
+    ) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !block_size.is_power_of_two() || !is_aligned_4k(block_size) {
+            return ax_err!(InvalidInput, "invalid block size");
+        }
+        if !start.is_aligned(block_size) || !size.is_multiple_of(block_size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let area = MemoryArea::new(start, size, flags.into(), Backend::new_alloc(false));
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        self.hotplug.insert(
+            start,
+            HotplugRegion {
+                range: GuestPhysAddrRange::from_start_size(start, size),
+                block_size,
+                plugged: vec![0; (size / block_size).div_ceil(64)],
+            },
+        );
+        Ok(())
+    }
+
+    /// Plugs `count` blocks starting at `start`, and maps their backing.
+    ///
+    /// Blocks already plugged are left as they are. Nothing is plugged if the
+    /// backing cannot be allocated.
+    pub fn plug_blocks(&mut self, start: GuestPhysAddr, count: usize) -> AxResult {
+        let (first, block_size) = self.hotplug_blocks(start, count)?;
+        let region = self.hotplug.get_mut(&first).unwrap();
+        let index = region.block_index(start);
+        let new: Vec<usize> = (index..index + count)
+            .filter(|&i| !region.plugged.get_bit(i))
+            .collect();
+        for &i in &new {
+            region.plugged.set_bit(i, true);
+        }
+
+        for &i in &new {
+            let block = first + i * block_size;
+            for addr in PageIter4K::new(block, block + block_size).unwrap() {
+                if self.translate(addr).is_none()
+                    && !self.handle_page_fault(addr, MappingFlags::empty())
+                {
+                    self.unplug(first, &new)?;
+                    return ax_err!(NoMemory, "failed to map plugged blocks");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unplugs `count` blocks starting at `start`, and frees their backing.
+    ///
+    /// Fails without unplugging anything if any of the blocks contains pinned
+    /// pages. Blocks already unplugged are left as they are. If the backing of
+    /// a block cannot be freed, e.g., because it contains watched pages, the
+    /// blocks before it are unplugged and the others are left plugged.
+    pub fn unplug_blocks(&mut self, start: GuestPhysAddr, count: usize) -> AxResult {
+        let (first, block_size) = self.hotplug_blocks(start, count)?;
+        if self
+            .pinned
+            .range(start..start + count * block_size)
+            .next()
+            .is_some()
+        {
+            return ax_err!(ResourceBusy, "blocks contain pinned pages");
+        }
+
+        let region = &self.hotplug[&first];
+        let index = region.block_index(start);
+        let plugged: Vec<usize> = (index..index + count)
+            .filter(|&i| region.plugged.get_bit(i))
+            .collect();
+        self.unplug(first, &plugged)
+    }
+
+    /// Returns the size in bytes of the plugged blocks in the hotplug region
+    /// containing `gpa`, or `None` if there is no such region.
+    pub fn plugged_size(&self, gpa: GuestPhysAddr) -> Option<usize> {
+        self.hotplug_region(gpa).map(|region| {
+            let count: u32 = region.plugged.iter().map(|word| word.count_ones()).sum();
+            count as usize * region.block_size
+        })
+    }
+
+    /// Pins the pages in the given range, mapping those not mapped yet.
+    ///
+    /// Pins are counted, a page stays pinned until it is unpinned as many
+    /// times as it was pinned. Blocks of hotplug regions containing pinned
+    /// pages cannot be unplugged.
+    pub fn pin_pages(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            if self.translate(addr).is_none()
+                && !self.handle_page_fault(addr, MappingFlags::empty())
+            {
+                return ax_err!(BadAddress, "failed to map pinned page");
+            }
+        }
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            *self.pinned.entry(addr).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    /// Unpins the pages in the given range. Pages not pinned are skipped.
+    pub fn unpin_pages(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            if let Some(count) = self.pinned.get_mut(&addr) {
+                *count -= 1;
+                if *count == 0 {
+                    self.pinned.remove(&addr);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails with `ResourceBusy` if any page in `range` is pinned.
+    pub(super) fn check_unpinned(&self, range: GuestPhysAddrRange) -> AxResult {
+        if self.pinned.range(range.start..range.end).next().is_some() {
+            return ax_err!(ResourceBusy, "pages pinned");
+        }
+        Ok(())
+    }
+
+    /// Returns the hotplug region containing `gpa`.
+    pub(super) fn hotplug_region(&self, gpa: GuestPhysAddr) -> Option<&HotplugRegion> {
+        self.hotplug
+            .range(..=gpa)
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| region.contains(gpa))
+    }
+
+    /// Checks that `count` blocks starting at `start` lie in a hotplug region,
+    /// and returns the start and the block size of the region.
+    fn hotplug_blocks(
+        &self,
+        start: GuestPhysAddr,
+        count: usize,
+    ) -> AxResult<(GuestPhysAddr, usize)> {
+        let Some(region) = self.hotplug_region(start) else {
+            return ax_err!(InvalidInput, "address not in a hotplug region");
+        };
+        if !start.is_aligned(region.block_size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        if count > (region.range.end - start) / region.block_size {
+            return ax_err!(InvalidInput, "blocks out of range");
+        }
+        Ok((region.range.start, region.block_size))
+    }
+
+    /// Frees the backing of the given blocks of a hotplug region, and marks
+    /// them unplugged.
+    ///
+    /// Stops at the first block whose backing cannot be freed, e.g., because
+    /// it contains watched pages. That block and the following ones are left
+    /// plugged.
+    fn unplug(&mut self, region_start: GuestPhysAddr, blocks: &[usize]) -> AxResult {
+        let block_size = self.hotplug[&region_start].block_size;
+        self.batch(|aspace| {
+            for &i in blocks {
+                let block = region_start + i * block_size;
+                aspace.discard(block, block_size)?;
+                aspace
+                    .ballooned
+                    .retain(|&gpa| !(block..block + block_size).contains(&gpa));
+                let region = aspace.hotplug.get_mut(&region_start).unwrap();
+                region.plugged.set_bit(i, false);
+            }
+            Ok(())
+        })
+    }
+}
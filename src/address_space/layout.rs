@@ -0,0 +1,161 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Listing of the mapped areas.
+
+use alloc::{format, string::String};
+use core::fmt;
+
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K};
+use memory_set::MemoryArea;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, Backend};
+use crate::{GuestPhysAddrRange, MemAttr, npt::NestedPageTable as PageTable};
+
+/// The kind of backend of a mapped area, see [`Backend`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Linear mapping, `vaddr - paddr` is `pa_va_offset`.
+    Linear {
+        /// `vaddr - paddr`.
+        pa_va_offset: usize,
+    },
+    /// Allocation mapping, populated when created or lazily.
+    Alloc {
+        /// Whether the frames were populated when creating the mapping.
+        populate: bool,
+    },
+    /// Copy-on-write mapping.
+    Cow,
+    /// Shared memory mapping.
+    Shared,
+    /// Trapping MMIO region.
+    Mmio {
+        /// The identifier of the region.
+        id: usize,
+    },
+    /// ROM region.
+    Rom,
+}
+
+/// Information about a mapped area, yielded by [`AddrSpace::areas`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AreaInfo {
+    /// The guest physical address range of the area.
+    pub range: GuestPhysAddrRange,
+    /// The mapping flags of the area.
+    pub flags: MappingFlags,
+    /// The memory attributes of the area.
+    pub mem_attr: MemAttr,
+    /// The kind of backend of the area.
+    pub kind: BackendKind,
+    /// The number of 4K pages of the area currently backed by frames.
+    pub populated: usize,
+}
+
+impl AreaInfo {
+    fn new<H: PagingHandler>(area: &MemoryArea<Backend<H>>, pt: &PageTable<H>) -> Self {
+        let kind = match area.backend() {
+            Backend::Linear { pa_va_offset } => BackendKind::Linear {
+                pa_va_offset: *pa_va_offset,
+            },
+            Backend::Alloc { populate, .. } => BackendKind::Alloc {
+                populate: *populate,
+            },
+            Backend::Cow { .. } => BackendKind::Cow,
+            Backend::Shared { .. } => BackendKind::Shared,
+            Backend::Mmio { id } => BackendKind::Mmio { id: *id },
+            Backend::Rom { .. } => BackendKind::Rom,
+        };
+        Self {
+            range: area.va_range(),
+            flags: area.flags().flags,
+            mem_attr: area.flags().mem_attr,
+            kind,
+            populated: populated_pages(area.va_range(), pt),
+        }
+    }
+}
+
+/// Counts the 4K pages in the range mapped by present entries.
+fn populated_pages<H: PagingHandler>(range: GuestPhysAddrRange, pt: &PageTable<H>) -> usize {
+    let mut count = 0;
+    let mut addr = range.start;
+    while addr < range.end {
+        let next = match pt.query(addr) {
+            Ok((_, _, page_size)) => {
+                let next = (addr.align_down(page_size) + page_size as usize).min(range.end);
+                count += (next - addr) / PAGE_SIZE_4K;
+                next
+            }
+            Err(_) => addr + PAGE_SIZE_4K,
+        };
+        addr = next;
+    }
+    count
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Returns an iterator over the mapped areas, in ascending address order.
+    pub fn areas(&self) -> impl Iterator<Item = AreaInfo> + '_ {
+        self.areas.iter().map(|area| AreaInfo::new(area, &self.pt))
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Linear { pa_va_offset } => write!(f, "linear({pa_va_offset:#x})"),
+            Self::Alloc { populate: true } => write!(f, "alloc(populate)"),
+            Self::Alloc { populate: false } => write!(f, "alloc(lazy)"),
+            Self::Cow => write!(f, "cow"),
+            Self::Shared => write!(f, "shared"),
+            Self::Mmio { id } => write!(f, "mmio({id})"),
+            Self::Rom => write!(f, "rom"),
+        }
+    }
+}
+
+/// Prints the mapped areas as a table, one area per line with its range,
+/// permissions, backend, and populated size.
+impl<H: PagingHandler> fmt::Display for AddrSpace<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<33} {:<5} {:<24} {:>10}",
+            "range", "perm", "backend", "populated"
+        )?;
+        for info in self.areas() {
+            let flag = |flag, c| if info.flags.contains(flag) { c } else { '-' };
+            let perm = [
+                flag(MappingFlags::READ, 'r'),
+                flag(MappingFlags::WRITE, 'w'),
+                flag(MappingFlags::EXECUTE, 'x'),
+                flag(MappingFlags::DEVICE, 'd'),
+                flag(MappingFlags::UNCACHED, 'u'),
+            ];
+            writeln!(
+                f,
+                "{:#016x}-{:#016x} {} {:<24} {:>9}K",
+                info.range.start.as_usize(),
+                info.range.end.as_usize(),
+                perm.iter().collect::<String>(),
+                format!("{}", info.kind),
+                info.populated * PAGE_SIZE_4K / 1024,
+            )?;
+        }
+        Ok(())
+    }
+}
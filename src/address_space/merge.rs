@@ -0,0 +1,213 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Same-page merging.
+//!
+//! Allocation mappings taking part in merging are turned into copy-on-write
+//! mappings sharing the reference table of the [`PageMerger`]. Identical pages
+//! are then mapped read-only to a single frame, and a write to one of them
+//! copies it privately again (see [`AddrSpace::handle_page_fault`]).
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+
+use axerrno::AxResult;
+use memory_addr::{PAGE_SIZE_4K, PageIter4K, PhysAddr};
+use page_table_multiarch::{MappingFlags, PageSize, PagingHandler};
+
+use super::{AddrSpace, Backend, FrameRefTable};
+use crate::{GuestPhysAddr, NestedFlags};
+
+/// Statistics of a merge pass.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    /// The number of pages mapped to a frame shared with identical pages.
+    pub merged_pages: usize,
+    /// The number of bytes of the frames freed by merging.
+    pub saved_bytes: usize,
+}
+
+/// Merges identical pages of allocation mappings across address spaces.
+///
+/// Allocation mappings with a NUMA policy or a swap store are not merged,
+/// since copy-on-write mappings keep neither. Pinned pages, pages private to
+/// a confidential guest, and huge pages mapped after the mappings joined
+/// merging are skipped.
+#[derive(Default)]
+pub struct PageMerger {
+    frame_refs: Arc<FrameRefTable>,
+}
+
+/// A frame that identical pages are merged onto, and one of its mappings.
+struct Candidate {
+    frame: PhysAddr,
+    space: usize,
+    gpa: GuestPhysAddr,
+    ro_flags: NestedFlags,
+}
+
+impl PageMerger {
+    /// Creates a new page merger.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of frames currently shared by merged pages.
+    pub fn shared_frames(&self) -> usize {
+        self.frame_refs.shared_frames()
+    }
+
+    /// Runs a merge pass over the populated pages of the given address spaces.
+    ///
+    /// Pages merged by earlier passes stay merged, and newly populated pages
+    /// identical to them are merged onto the same frames.
+    pub fn merge<H: PagingHandler>(
+        &self,
+        spaces: &mut [&mut AddrSpace<H>],
+    ) -> AxResult<MergeStats> {
+        for space in spaces.iter_mut() {
+            space.join_merging(&self.frame_refs)?;
+        }
+
+        let mut stats = MergeStats::default();
+        let mut candidates: BTreeMap<u64, Vec<Candidate>> = BTreeMap::new();
+        for i in 0..spaces.len() {
+            for (gpa, frame, ro_flags) in self.mergeable_pages(spaces[i]) {
+                let bucket = candidates.entry(page_hash::<H>(frame)).or_default();
+                if bucket.iter().any(|c| c.frame == frame) {
+                    // Already merged.
+                    continue;
+                }
+                let target = bucket.iter().position(|c| {
+                    // Compare after write-protecting both pages, so that the
+                    // contents cannot change until they are merged.
+                    if !self.frame_refs.is_shared(c.frame) {
+                        write_protect(spaces[c.space], c.gpa, c.ro_flags);
+                    }
+                    write_protect(spaces[i], gpa, ro_flags);
+                    page_eq::<H>(c.frame, frame)
+                });
+                let Some(target) = target else {
+                    bucket.push(Candidate {
+                        frame,
+                        space: i,
+                        gpa,
+                        ro_flags,
+                    });
+                    continue;
+                };
+
+                let target = bucket[target].frame;
+                if !spaces[i].pt.remap(gpa, target, ro_flags) {
+                    // The page keeps its own frame.
+                    continue;
+                }
+                self.frame_refs.share(target);
+                spaces[i].pt.flush_tlb(Some(gpa));
+                if self.frame_refs.release(frame) {
+                    H::dealloc_frame(frame);
+                    stats.saved_bytes += PAGE_SIZE_4K;
+                }
+                stats.merged_pages += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Returns the pages that can be merged in the address space, with their
+    /// frames and read-only mapping flags.
+    fn mergeable_pages<H: PagingHandler>(
+        &self,
+        space: &AddrSpace<H>,
+    ) -> Vec<(GuestPhysAddr, PhysAddr, NestedFlags)> {
+        let mut pages = Vec::new();
+        for area in space.areas.iter() {
+            match area.backend() {
+                Backend::Cow { frame_refs, .. } if Arc::ptr_eq(frame_refs, &self.frame_refs) => {}
+                _ => continue,
+            }
+            let ro_flags = area.flags() - MappingFlags::WRITE;
+            for gpa in PageIter4K::new(area.start(), area.end()).unwrap() {
+                if let Ok((frame, _, PageSize::Size4K)) = space.pt.query(gpa)
+                    && !space.pinned.contains_key(&gpa)
+                    && !space.is_private(gpa)
+                {
+                    pages.push((gpa, frame, ro_flags));
+                }
+            }
+        }
+        pages
+    }
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Turns the allocation mappings that can be merged into copy-on-write
+    /// mappings sharing `frame_refs`.
+    fn join_merging(&mut self, frame_refs: &Arc<FrameRefTable>) -> AxResult {
+        let joining = |backend: &Backend<H>| {
+            matches!(
+                backend,
+                Backend::Alloc {
+                    numa: None,
+                    swap: None,
+                    ..
+                }
+            )
+        };
+        let ranges: Vec<_> = self
+            .areas
+            .iter()
+            .filter(|area| joining(area.backend()))
+            .map(|area| area.va_range())
+            .collect();
+        // Frames are shared in 4K granularity, and huge frames must be freed
+        // by the allocation backend.
+        for &range in &ranges {
+            self.split_huge_pages(range)?;
+        }
+        // Replace the backends like `fork` does, leaving the other areas as
+        // they are.
+        self.replace_backends(
+            ranges
+                .into_iter()
+                .map(|range| (range.start, Backend::new_cow(frame_refs.clone())))
+                .collect(),
+        )
+    }
+}
+
+fn write_protect<H: PagingHandler>(
+    space: &mut AddrSpace<H>,
+    gpa: GuestPhysAddr,
+    ro_flags: NestedFlags,
+) {
+    space.pt.protect_region(gpa, PAGE_SIZE_4K, ro_flags);
+    space.pt.flush_tlb(Some(gpa));
+}
+
+fn page_bytes<'a, H: PagingHandler>(frame: PhysAddr) -> &'a [u8] {
+    unsafe { core::slice::from_raw_parts(H::phys_to_virt(frame).as_ptr(), PAGE_SIZE_4K) }
+}
+
+fn page_eq<H: PagingHandler>(a: PhysAddr, b: PhysAddr) -> bool {
+    page_bytes::<H>(a) == page_bytes::<H>(b)
+}
+
+/// FNV-1a hash of the page contents.
+fn page_hash<H: PagingHandler>(frame: PhysAddr) -> u64 {
+    page_bytes::<H>(frame)
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+        })
+}
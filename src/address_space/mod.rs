@@ -12,29 +12,74 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use alloc::vec::Vec;
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
 use core::fmt;
 
 use axerrno::{AxResult, ax_err};
-use memory_addr::{MemoryAddr, PhysAddr, is_aligned_4k};
-use memory_set::{MemoryArea, MemorySet};
-use page_table_multiarch::PagingHandler;
+use bit_field::BitArray;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PageIter4K, PhysAddr, is_aligned_4k};
+use memory_set::{MappingBackend, MemoryArea, MemorySet};
+use page_table_multiarch::{PageSize, PagingHandler};
 
 use crate::npt::NestedPageTable as PageTable;
-use crate::{GuestPhysAddr, GuestPhysAddrRange, mapping_err_to_ax_err};
+use crate::npt::Stage2Context;
+use crate::{GuestPhysAddr, GuestPhysAddrRange, MemAttr, NestedFlags, mapping_err_to_ax_err};
 
+mod accessed;
 mod backend;
+mod balloon;
+mod confidential;
+mod dirty_log;
+mod firmware;
+mod guest_walk;
+mod hotplug;
+mod layout;
+mod merge;
+mod numa;
+mod snapshot;
+mod swap;
+mod watch;
 
-pub use backend::Backend;
+pub use backend::{
+    Backend, FrameRefTable, MemorySwapStore, NumaPlacement, NumaPolicy, SharedMemory, SwapStore,
+};
+pub use firmware::{
+    E820_ACPI, E820_RAM, E820_RESERVED, E820Entry, FdtMemoryNodes, MemoryRegionKind, fdt_reg,
+};
+pub use guest_walk::GuestPagingMode;
+pub use layout::{AreaInfo, BackendKind};
+pub use merge::{MergeStats, PageMerger};
 pub use page_table_entry::MappingFlags;
+pub use snapshot::{SnapshotReader, SnapshotWriter};
+pub use watch::WatchpointHit;
 
 /// The virtual memory address space.
 pub struct AddrSpace<H: PagingHandler> {
     va_range: GuestPhysAddrRange,
     areas: MemorySet<Backend<H>>,
     pt: PageTable<H>,
+    dirty_log: Option<dirty_log::DirtyLog>,
+    ballooned: BTreeSet<GuestPhysAddr>,
+    hotplug: BTreeMap<GuestPhysAddr, hotplug::HotplugRegion>,
+    pinned: BTreeMap<GuestPhysAddr, usize>,
+    /// Private pages of confidential guests, one bit per 4K page. Empty if
+    /// all pages are shared.
+    private: Vec<u64>,
+    watch: watch::Watchpoints,
 }
 
+// Address spaces are shared between CPUs, e.g., behind a lock.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    const fn assert_addr_space<H: PagingHandler + Send + Sync>() {
+        assert_send_sync::<AddrSpace<H>>();
+    }
+};
+
 impl<H: PagingHandler> AddrSpace<H> {
     /// Returns the address space base.
     pub const fn base(&self) -> GuestPhysAddr {
@@ -61,6 +106,50 @@ impl<H: PagingHandler> AddrSpace<H> {
         self.pt.root_paddr()
     }
 
+    /// Returns the stage-2 translation context of the address space: the EPT
+    /// pointer on x86, `VTTBR_EL2` and `VTCR_EL2` on AArch64, and `hgatp` on
+    /// RISC-V.
+    ///
+    /// It is built from the levels and the root of the page table and the
+    /// [VMID](Self::vmid), and should be used instead of assembling the
+    /// registers from [`page_table_root`](Self::page_table_root).
+    pub fn stage2_context(&self) -> Stage2Context {
+        self.pt.stage2_context()
+    }
+
+    /// Returns the VMID of the address space.
+    pub const fn vmid(&self) -> u16 {
+        self.pt.vmid()
+    }
+
+    /// Sets the VMID of the address space.
+    ///
+    /// On AArch64, TLB flushes of the address space only invalidate the
+    /// entries tagged with this VMID, so it must match the VMID the guest runs
+    /// with. It is 0 by default, and not inherited by [`fork`](Self::fork).
+    pub fn set_vmid(&mut self, vmid: u16) {
+        self.pt.set_vmid(vmid);
+    }
+
+    /// Flushes the TLB entries of the page containing `gpa`, or all entries of
+    /// the address space if `gpa` is `None`.
+    pub fn flush_tlb(&self, gpa: Option<GuestPhysAddr>) {
+        self.pt.flush_tlb(gpa);
+    }
+
+    /// Runs `f` as a batch of changes to the address space, such as
+    /// [`map_linear`](Self::map_linear), [`unmap`](Self::unmap) and
+    /// [`protect`](Self::protect) calls.
+    ///
+    /// The TLB flushes of the changes are collected and issued once at the
+    /// end of the batch.
+    pub fn batch<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        self.pt.begin_batch();
+        let res = f(self);
+        self.pt.end_batch();
+        res
+    }
+
     /// Checks if the address space contains the given address range.
     pub fn contains_range(&self, start: GuestPhysAddr, size: usize) -> bool {
         self.va_range
@@ -68,11 +157,41 @@ impl<H: PagingHandler> AddrSpace<H> {
     }
 
     /// Creates a new empty address space with the architecture default page table level.
+    ///
+    /// The address space must lie within the guest physical addresses the page
+    /// table translates: the low 39 bits with 3 levels, and the low 48 bits
+    /// with 4 levels.
     pub fn new_empty(level: usize, base: GuestPhysAddr, size: usize) -> AxResult<Self> {
+        Self::new_empty_with_fwb(level, base, size, false)
+    }
+
+    /// Like [`new_empty`](Self::new_empty), but the stage-2 memory attributes
+    /// use the FEAT_S2FWB encoding if `fwb`, where stage 2 can force the
+    /// write-back memory type.
+    ///
+    /// `fwb` must match `HCR_EL2.FWB` when the address space is in use, and is
+    /// inherited by [`fork`](Self::fork). It is ignored by architectures other
+    /// than AArch64.
+    pub fn new_empty_with_fwb(
+        level: usize,
+        base: GuestPhysAddr,
+        size: usize,
+        fwb: bool,
+    ) -> AxResult<Self> {
+        let pt = PageTable::<H>::new_with_fwb(level, fwb)?;
+        if (base.as_usize() as u128 + size as u128) > 1 << pt.addr_bits() {
+            return ax_err!(InvalidInput, "address space exceeds the page table");
+        }
         Ok(Self {
             va_range: GuestPhysAddrRange::from_start_size(base, size),
             areas: MemorySet::new(),
-            pt: PageTable::<H>::new(level)?,
+            pt,
+            dirty_log: None,
+            ballooned: BTreeSet::new(),
+            hotplug: BTreeMap::new(),
+            pinned: BTreeMap::new(),
+            private: Vec::new(),
+            watch: watch::Watchpoints::default(),
         })
     }
 
@@ -96,7 +215,7 @@ impl<H: PagingHandler> AddrSpace<H> {
         }
 
         let offset = start_vaddr.as_usize() - start_paddr.as_usize();
-        let area = MemoryArea::new(start_vaddr, size, flags, Backend::new_linear(offset));
+        let area = MemoryArea::new(start_vaddr, size, flags.into(), Backend::new_linear(offset));
         self.areas
             .map(area, &mut self.pt, false)
             .map_err(mapping_err_to_ax_err)?;
@@ -125,15 +244,97 @@ impl<H: PagingHandler> AddrSpace<H> {
             return ax_err!(InvalidInput, "address not aligned");
         }
 
-        let area = MemoryArea::new(start, size, flags, Backend::new_alloc(populate));
+        let area = MemoryArea::new(start, size, flags.into(), Backend::new_alloc(populate));
         self.areas
             .map(area, &mut self.pt, false)
             .map_err(mapping_err_to_ax_err)?;
         Ok(())
     }
 
-    /// Removes mappings within the specified virtual address range.
-    pub fn unmap(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+    /// Add a new shared memory mapping.
+    ///
+    /// The whole `shm` is mapped at `start`. The same shared memory object can
+    /// be mapped into several address spaces, at different addresses and with
+    /// different `flags`. Its frames are deallocated after the last mapping is
+    /// removed and the last reference is dropped.
+    ///
+    /// See [`Backend`] for more details about the mapping backends.
+    pub fn map_shared(
+        &mut self,
+        start: GuestPhysAddr,
+        shm: &Arc<SharedMemory<H>>,
+        flags: MappingFlags,
+    ) -> AxResult {
+        let size = shm.size();
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let area = MemoryArea::new(
+            start,
+            size,
+            flags.into(),
+            Backend::new_shared(shm.clone(), start),
+        );
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        Ok(())
+    }
+
+    /// Add a new ROM region holding a copy of `data`.
+    ///
+    /// The region is rounded up to whole pages, the rest of the last page is
+    /// zeroed. It is never mapped writable, whatever `flags` says, and write
+    /// faults in it are reported as [`PageFaultOutcome::RomWrite`] by
+    /// [`handle_page_fault_ext`](Self::handle_page_fault_ext).
+    ///
+    /// See [`Backend`] for more details about the mapping backends.
+    pub fn map_rom(&mut self, start: GuestPhysAddr, data: &[u8], flags: MappingFlags) -> AxResult {
+        let size = data.len().align_up_4k();
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        self.map_rom_frames(start, backend::load_rom::<H>(data)?, flags)
+    }
+
+    /// Maps ROM frames already loaded with the image at `start`, read-only.
+    ///
+    /// The range is checked by the caller.
+    fn map_rom_frames(
+        &mut self,
+        start: GuestPhysAddr,
+        rom: Arc<SharedMemory<H>>,
+        flags: MappingFlags,
+    ) -> AxResult {
+        let flags = flags - MappingFlags::WRITE;
+        let area = MemoryArea::new(
+            start,
+            rom.size(),
+            flags.into(),
+            Backend::new_rom(rom, start),
+        );
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        Ok(())
+    }
+
+    /// Add a new trapping MMIO region.
+    ///
+    /// The region is reserved without any backing memory, and page faults in
+    /// it are reported as MMIO accesses to the region `id` by
+    /// [`handle_page_fault_ext`](Self::handle_page_fault_ext).
+    ///
+    /// See [`Backend`] for more details about the mapping backends.
+    pub fn map_mmio(&mut self, start: GuestPhysAddr, size: usize, id: usize) -> AxResult {
         if !self.contains_range(start, size) {
             return ax_err!(InvalidInput, "address out of range");
         }
@@ -141,15 +342,391 @@ impl<H: PagingHandler> AddrSpace<H> {
             return ax_err!(InvalidInput, "address not aligned");
         }
 
+        let area = MemoryArea::new(
+            start,
+            size,
+            MappingFlags::empty().into(),
+            Backend::new_mmio(id),
+        );
         self.areas
-            .unmap(start, size, &mut self.pt)
+            .map(area, &mut self.pt, false)
             .map_err(mapping_err_to_ax_err)?;
         Ok(())
     }
 
+    /// Removes mappings within the specified virtual address range.
+    ///
+    /// Hotplug regions can only be removed as a whole.
+    pub fn unmap(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        if self
+            .hotplug
+            .values()
+            .any(|region| range.overlaps(region.range()) && !range.contains_range(region.range()))
+        {
+            return ax_err!(InvalidInput, "cannot unmap part of a hotplug region");
+        }
+        self.batch(|aspace| {
+            aspace.remove_watchpoints_in(range)?;
+            aspace
+                .areas
+                .unmap(start, size, &mut aspace.pt)
+                .map_err(mapping_err_to_ax_err)
+        })?;
+        self.ballooned.retain(|&gpa| !range.contains(gpa));
+        self.pinned.retain(|&gpa, _| !range.contains(gpa));
+        self.hotplug
+            .retain(|_, region| !range.contains_range(region.range()));
+        if !self.private.is_empty() {
+            for addr in PageIter4K::new(start, start + size).unwrap() {
+                let index = (addr - self.base()) / PAGE_SIZE_4K;
+                self.private.set_bit(index, false);
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the access permissions of the mappings within the specified
+    /// virtual address range.
+    ///
+    /// Only the `READ`, `WRITE` and `EXECUTE` bits of `flags` are applied, the
+    /// other attributes of the mappings are kept, and at least one of them
+    /// must be set. Areas partially covered by the range are split. The TLB is
+    /// flushed once after all mappings are updated.
+    pub fn protect(&mut self, start: GuestPhysAddr, size: usize, flags: MappingFlags) -> AxResult {
+        const PERM: MappingFlags = MappingFlags::READ
+            .union(MappingFlags::WRITE)
+            .union(MappingFlags::EXECUTE);
+
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        // A non-present entry does not keep its frame, use `unmap` instead.
+        if !flags.intersects(PERM) {
+            return ax_err!(InvalidInput, "no access permissions");
+        }
+
+        self.batch(|aspace| {
+            // Huge pages crossing the range boundaries cannot be protected
+            // partially, map them with 4K pages instead.
+            for boundary in [start, start + size] {
+                if let Some(area) = aspace.areas.find(boundary)
+                    && area.start() < boundary
+                    && !aspace.pt.split_huge_page(boundary)
+                {
+                    return ax_err!(BadState, "failed to split huge page");
+                }
+            }
+
+            let result = aspace.areas.protect(
+                start,
+                size,
+                |old| {
+                    let new = old.with_flags((old.flags - PERM) | (flags & PERM));
+                    (new != old).then_some(new)
+                },
+                &mut aspace.pt,
+            );
+            aspace.update_watch_flags(GuestPhysAddrRange::from_start_size(start, size), |old| {
+                old.with_flags((old.flags - PERM) | (flags & PERM))
+            });
+            #[cfg(not(target_arch = "x86_64"))]
+            if let Some(log) = &aspace.dirty_log {
+                // Keep the pages in the dirty log write-protected.
+                aspace.write_protect(log.range());
+            }
+            aspace.pt.flush_tlb(None);
+            result.map_err(mapping_err_to_ax_err)
+        })
+    }
+
+    /// Changes the memory attributes of the mappings within the specified
+    /// virtual address range.
+    ///
+    /// The access permissions are kept. Areas partially covered by the range
+    /// are split, and the TLB is flushed once after all mappings are updated.
+    pub fn set_mem_attr(&mut self, start: GuestPhysAddr, size: usize, attr: MemAttr) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        self.batch(|aspace| {
+            for boundary in [start, start + size] {
+                if let Some(area) = aspace.areas.find(boundary)
+                    && area.start() < boundary
+                    && !aspace.pt.split_huge_page(boundary)
+                {
+                    return ax_err!(BadState, "failed to split huge page");
+                }
+            }
+
+            let result = aspace.areas.protect(
+                start,
+                size,
+                |old| {
+                    (old.mem_attr != attr).then_some(NestedFlags {
+                        mem_attr: attr,
+                        ..old
+                    })
+                },
+                &mut aspace.pt,
+            );
+            aspace.update_watch_flags(GuestPhysAddrRange::from_start_size(start, size), |old| {
+                NestedFlags {
+                    mem_attr: attr,
+                    ..old
+                }
+            });
+            #[cfg(not(target_arch = "x86_64"))]
+            if let Some(log) = &aspace.dirty_log {
+                aspace.write_protect(log.range());
+            }
+            aspace.pt.flush_tlb(None);
+            result.map_err(mapping_err_to_ax_err)
+        })
+    }
+
+    /// Drops the contents of the pages within the specified virtual address
+    /// range.
+    ///
+    /// The frames of allocation and copy-on-write mappings in the range are
+    /// freed, and the pages are mapped on demand again, so that the next
+    /// access faults in a new zeroed frame. Fails without discarding anything
+    /// if the range overlaps linear or shared memory mappings, or watched or
+    /// pinned pages.
+    pub fn discard(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        self.check_releasable(range)?;
+        for (area, _) in overlapping_areas(&self.areas, range) {
+            match area.backend() {
+                Backend::Linear { .. } => {
+                    return ax_err!(InvalidInput, "cannot discard linear mappings");
+                }
+                Backend::Shared { .. } => {
+                    return ax_err!(InvalidInput, "cannot discard shared memory mappings");
+                }
+                Backend::Mmio { .. } => {
+                    return ax_err!(InvalidInput, "cannot discard MMIO regions");
+                }
+                Backend::Rom { .. } => {
+                    return ax_err!(InvalidInput, "cannot discard ROM regions");
+                }
+                Backend::Alloc { .. } | Backend::Cow { .. } => {}
+            }
+        }
+        self.batch(|aspace| {
+            for (area, range) in overlapping_areas(&aspace.areas, range) {
+                if !area
+                    .backend()
+                    .discard(range.start, range.size(), &mut aspace.pt)
+                {
+                    return ax_err!(BadState, "failed to discard pages");
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Fails with `ResourceBusy` if the frames of any page in `range` must be
+    /// kept, i.e., the page is watched or pinned.
+    pub(super) fn check_releasable(&self, range: GuestPhysAddrRange) -> AxResult {
+        if self.watch.overlaps(range) {
+            return ax_err!(ResourceBusy, "pages watched");
+        }
+        self.check_unpinned(range)
+    }
+
     /// Removes all mappings in the address space.
     pub fn clear(&mut self) {
-        self.areas.clear(&mut self.pt).unwrap();
+        self.batch(|aspace| {
+            // Watched pages without permissions are not mapped, map them back
+            // so that their frames are freed with the areas.
+            aspace.remove_watchpoints_in(aspace.va_range).unwrap();
+            aspace.areas.clear(&mut aspace.pt).unwrap();
+        });
+        self.ballooned.clear();
+        self.hotplug.clear();
+        self.pinned.clear();
+        self.private.clear();
+        self.watch.clear();
+    }
+
+    /// Forks the address space into a copy-on-write clone.
+    ///
+    /// Linear and shared mappings are mapped to the same physical frames in the
+    /// new address space. Allocation mappings in both address spaces become
+    /// copy-on-write mappings: the populated frames are shared read-only, and
+    /// are copied privately on the first write fault (see
+    /// [`handle_page_fault`](Self::handle_page_fault)). Copy-on-write mappings
+    /// have no NUMA policy, so the frames copied or allocated for them later
+    /// come from any node. Pages swapped out are read back before forking.
+    /// Fails with `ResourceBusy` if pages of
+    /// allocation or copy-on-write mappings are pinned, including watched
+    /// pages.
+    ///
+    /// On failure, the address space is left as it was before the call.
+    pub fn fork(&mut self) -> AxResult<Self> {
+        // A write to a shared frame moves the page to a new frame, which must
+        // not happen to pinned pages. Watched pages are pinned as well, so the
+        // pages write-protected below are never armed.
+        for area in self.areas.iter() {
+            if matches!(area.backend(), Backend::Alloc { .. } | Backend::Cow { .. }) {
+                self.check_unpinned(area.va_range())?;
+            }
+        }
+        self.swap_in(self.base(), self.size())?;
+        let mut child =
+            Self::new_empty_with_fwb(self.pt.level(), self.base(), self.size(), self.pt.fwb())?;
+        child.ballooned = self.ballooned.clone();
+        child.hotplug = self.hotplug.clone();
+        child.private = self.private.clone();
+
+        let mut protected = Vec::new();
+        if let Err(err) = self.batch(|aspace| aspace.fork_into(&mut child, &mut protected)) {
+            // The child drops its references to the shared frames, then our
+            // pages are made writable again.
+            drop(child);
+            for (addr, flags) in protected {
+                self.pt.protect_region(addr, PAGE_SIZE_4K, flags);
+            }
+            self.pt.flush_tlb(None);
+            return Err(err);
+        }
+        Ok(child)
+    }
+
+    /// Maps the areas into `child`, sharing the frames of allocation and
+    /// copy-on-write areas, and turns our allocation areas into copy-on-write
+    /// areas.
+    ///
+    /// Our pages write-protected so far are recorded in `protected` with their
+    /// original flags, so that the caller can roll back on failure.
+    fn fork_into(
+        &mut self,
+        child: &mut Self,
+        protected: &mut Vec<(GuestPhysAddr, NestedFlags)>,
+    ) -> AxResult {
+        let mut replaced = Vec::new();
+        for area in self.areas.iter() {
+            let backend = match area.backend() {
+                Backend::Linear { .. }
+                | Backend::Shared { .. }
+                | Backend::Mmio { .. }
+                | Backend::Rom { .. } => area.backend().clone(),
+                Backend::Alloc { .. } => Backend::new_cow(Arc::new(FrameRefTable::new())),
+                Backend::Cow { frame_refs, .. } => Backend::new_cow(frame_refs.clone()),
+            };
+            child
+                .areas
+                .map(
+                    MemoryArea::new(area.start(), area.size(), area.flags(), backend.clone()),
+                    &mut child.pt,
+                    false,
+                )
+                .map_err(mapping_err_to_ax_err)?;
+
+            let Backend::Cow { frame_refs, .. } = &backend else {
+                continue;
+            };
+            let ro_flags = area.flags() - MappingFlags::WRITE;
+            for addr in PageIter4K::new(area.start(), area.end()).unwrap() {
+                let Ok((_, _, page_size)) = self.pt.query(addr) else {
+                    continue;
+                };
+                // Frames are shared in 4K granularity.
+                if page_size.is_huge() && !self.pt.split_huge_page(addr) {
+                    return ax_err!(BadState, "failed to split huge page");
+                }
+                let (frame, flags, _) = self.pt.query_nested(addr).unwrap();
+                if !child.pt.remap(addr, frame, ro_flags) {
+                    return ax_err!(BadState, "failed to map shared frame");
+                }
+                frame_refs.share(frame);
+                self.pt.protect_region(addr, PAGE_SIZE_4K, ro_flags);
+                protected.push((addr, flags));
+            }
+            if matches!(area.backend(), Backend::Alloc { .. }) {
+                replaced.push((area.start(), backend));
+            }
+        }
+        self.pt.flush_tlb(None);
+        self.replace_backends(replaced)
+    }
+
+    /// Replaces the backends of the allocation or copy-on-write areas at the
+    /// given start addresses with copy-on-write backends, keeping the pages
+    /// mapped by the areas.
+    fn replace_backends(&mut self, backends: Vec<(GuestPhysAddr, Backend<H>)>) -> AxResult {
+        if backends.is_empty() {
+            return Ok(());
+        }
+        // The copy-on-write backend keeps the pages that are present, and maps
+        // the others on demand. Once their entries exist, mapping the areas
+        // again cannot fail.
+        for (start, backend) in &backends {
+            let area = self.areas.find(*start).unwrap();
+            if !backend.map(*start, area.size(), area.flags(), &mut self.pt) {
+                return ax_err!(NoMemory, "failed to map area");
+            }
+        }
+        // `MemorySet` has no mutable access to the areas. Remove them against
+        // an empty page table, so that our entries are left untouched, and
+        // insert them again with the new backends.
+        let mut detached = PageTable::<H>::new(self.pt.level())?;
+        for (start, backend) in backends {
+            let area = self.areas.find(start).unwrap();
+            let (size, flags) = (area.size(), area.flags());
+            self.areas
+                .unmap(start, size, &mut detached)
+                .map_err(mapping_err_to_ax_err)?;
+            self.areas
+                .map(
+                    MemoryArea::new(start, size, flags, backend),
+                    &mut self.pt,
+                    false,
+                )
+                .map_err(mapping_err_to_ax_err)?;
+        }
+        Ok(())
+    }
+
+    /// Splits the huge pages overlapping `range` into 4K pages.
+    fn split_huge_pages(&mut self, range: GuestPhysAddrRange) -> AxResult {
+        for (_, range) in overlapping_areas(&self.areas, range) {
+            let mut addr = range.start;
+            while addr < range.end {
+                match self.pt.query(addr) {
+                    Ok((_, _, page_size)) if page_size.is_huge() => {
+                        if !self.pt.split_huge_page(addr) {
+                            return ax_err!(BadState, "failed to split huge page");
+                        }
+                        addr = addr.align_down(page_size) + page_size as usize;
+                    }
+                    _ => addr += PAGE_SIZE_4K,
+                }
+            }
+        }
+        Ok(())
     }
 
     /// Handles a page fault at the given address.
@@ -159,21 +736,101 @@ impl<H: PagingHandler> AddrSpace<H> {
     /// Returns `true` if the page fault is handled successfully (not a real
     /// fault).
     pub fn handle_page_fault(&mut self, vaddr: GuestPhysAddr, access_flags: MappingFlags) -> bool {
+        if self.handle_watch_fault(vaddr, access_flags).is_some() {
+            // Hits are reported to the watchpoint callback.
+            return true;
+        }
+        let handled = self.handle_mapped_fault(vaddr, access_flags);
+        if handled {
+            // The page may be remapped with its original flags.
+            self.rearm_watch_page(vaddr.align_down_4k());
+        }
+        handled
+    }
+
+    fn handle_mapped_fault(&mut self, vaddr: GuestPhysAddr, access_flags: MappingFlags) -> bool {
         if !self.va_range.contains(vaddr) {
             return false;
         }
+        let block = match self.hotplug_region(vaddr) {
+            // Unplugged blocks of hotplug regions have no backing.
+            Some(region) if !region.is_plugged(vaddr) => return false,
+            Some(region) => Some(region.block_range(vaddr)),
+            None => None,
+        };
         if let Some(area) = self.areas.find(vaddr) {
             let orig_flags = area.flags();
-            if !orig_flags.contains(access_flags) {
+            if !orig_flags.flags.contains(access_flags) {
                 return false;
             }
-            area.backend()
-                .handle_page_fault(vaddr, orig_flags, &mut self.pt)
+            #[cfg(not(target_arch = "x86_64"))]
+            if self.pt.set_accessed(vaddr) {
+                // The accessed flag was cleared by harvesting, see
+                // `get_and_clear_accessed`.
+                return true;
+            }
+            // Accessing a ballooned page takes it out of the balloon.
+            self.ballooned.remove(&vaddr.align_down_4k());
+            // Huge pages in hotplug regions must not cover the unplugged
+            // blocks around the faulting one.
+            let mut area_range = block.unwrap_or(area.va_range());
+            if let Some(log) = self.dirty_log.as_mut()
+                && log.contains(vaddr)
+            {
+                // Logged pages are tracked in 4K granularity, never map huge
+                // pages there.
+                area_range =
+                    GuestPhysAddrRange::from_start_size(vaddr.align_down_4k(), PAGE_SIZE_4K);
+                #[cfg(not(target_arch = "x86_64"))]
+                return log.handle_page_fault(
+                    vaddr,
+                    area_range,
+                    orig_flags,
+                    access_flags,
+                    area.backend(),
+                    &mut self.pt,
+                );
+            }
+            area.backend().handle_page_fault(
+                vaddr,
+                area_range,
+                orig_flags,
+                access_flags,
+                &mut self.pt,
+            )
         } else {
             false
         }
     }
 
+    /// Handles a page fault at the given address, and tells the caller how
+    /// to proceed.
+    ///
+    /// Unlike [`handle_page_fault`](Self::handle_page_fault), faults in
+    /// trapping MMIO regions are reported as [`PageFaultOutcome::Mmio`], so
+    /// that they can be routed to device emulation.
+    pub fn handle_page_fault_ext(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        access_flags: MappingFlags,
+    ) -> PageFaultOutcome {
+        match self.areas.find(vaddr).map(|area| area.backend()) {
+            Some(Backend::Mmio { id }) => return PageFaultOutcome::Mmio { id: *id },
+            Some(Backend::Rom { .. }) if access_flags.contains(MappingFlags::WRITE) => {
+                return PageFaultOutcome::RomWrite { addr: vaddr };
+            }
+            _ => {}
+        }
+        if let Some(outcome) = self.handle_watch_fault(vaddr, access_flags) {
+            return outcome;
+        }
+        if self.handle_page_fault(vaddr, access_flags) {
+            PageFaultOutcome::Handled
+        } else {
+            PageFaultOutcome::Unhandled
+        }
+    }
+
     /// Translates the given `VirtAddr` into `PhysAddr`.
     ///
     /// Returns `None` if the virtual address is out of range or not mapped.
@@ -188,11 +845,13 @@ impl<H: PagingHandler> AddrSpace<H> {
                 phys_addr
             })
             .ok()
+            .or_else(|| self.watch.frame(vaddr))
     }
 
     /// Translate&Copy the given `VirtAddr` with LENGTH len to a mutable u8 Vec through page table.
     ///
-    /// Returns `None` if the virtual address is out of range or not mapped.
+    /// Returns `None` if the virtual address is out of range, the range
+    /// contains pages not mapped, e.g., swapped out, or private pages.
     pub fn translated_byte_buffer(
         &self,
         vaddr: GuestPhysAddr,
@@ -211,6 +870,10 @@ impl<H: PagingHandler> AddrSpace<H> {
                 return None;
             }
 
+            if self.shared_limit(vaddr, len) != Some(len) {
+                return None;
+            }
+
             let mut start = vaddr;
             let end = start + len;
 
@@ -223,7 +886,12 @@ impl<H: PagingHandler> AddrSpace<H> {
 
             let mut v = Vec::new();
             while start < end {
-                let (start_paddr, _, page_size) = self.page_table().query(start).unwrap();
+                // Watched pages without permissions are unmapped, but keep
+                // their frames.
+                let (start_paddr, page_size) = match self.page_table().query(start) {
+                    Ok((paddr, _, page_size)) => (paddr, page_size),
+                    Err(_) => (self.watch.frame(start)?, PageSize::Size4K),
+                };
                 let mut end_va = start.align_down(page_size) + page_size.into();
                 end_va = end_va.min(end);
 
@@ -244,22 +912,82 @@ impl<H: PagingHandler> AddrSpace<H> {
     /// Translates the given `VirtAddr` into `PhysAddr`,
     /// and returns the size of the `MemoryArea` corresponding to the target vaddr.
     ///
-    /// Returns `None` if the virtual address is out of range or not mapped.
+    /// Returns `None` if the virtual address is out of range, not mapped, or
+    /// private. The size is limited to the next private page, if any.
     pub fn translate_and_get_limit(&self, vaddr: GuestPhysAddr) -> Option<(PhysAddr, usize)> {
         if !self.va_range.contains(vaddr) {
             return None;
         }
         if let Some(area) = self.areas.find(vaddr) {
+            let limit = self.shared_limit(vaddr, area.size())?;
             self.pt
                 .query(vaddr)
-                .map(|(phys_addr, _, _)| (phys_addr, area.size()))
+                .map(|(phys_addr, _, _)| (phys_addr, limit))
                 .ok()
+                .or_else(|| {
+                    let limit = limit.min(PAGE_SIZE_4K - vaddr.align_offset_4k());
+                    Some((self.watch.frame(vaddr)?, limit))
+                })
         } else {
             None
         }
     }
 }
 
+/// The outcome of handling a nested page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultOutcome {
+    /// The fault is resolved, the faulting access can be retried.
+    Handled,
+    /// The address belongs to a trapping MMIO region, the access should be
+    /// emulated.
+    Mmio {
+        /// The identifier of the region.
+        id: usize,
+    },
+    /// The address belongs to a ROM region and the access is a write, which
+    /// should be emulated or ignored.
+    RomWrite {
+        /// The faulting address.
+        addr: GuestPhysAddr,
+    },
+    /// The access hits a watchpoint, which is reported to the watchpoint
+    /// callback. The page is accessible until
+    /// [`AddrSpace::rearm_watchpoints`] is called.
+    Watchpoint {
+        /// The identifier of the watchpoint.
+        id: usize,
+        /// The faulting address.
+        addr: GuestPhysAddr,
+    },
+    /// The access is to a watched page but not to the watched bytes. The page
+    /// is accessible until [`AddrSpace::rearm_watchpoints`] is called.
+    WatchStep {
+        /// The faulting address.
+        addr: GuestPhysAddr,
+    },
+    /// The fault cannot be resolved, e.g., the address is not mapped or the
+    /// access is not permitted.
+    Unhandled,
+}
+
+/// Returns the areas overlapping `range`, together with the overlapped part of
+/// each area.
+fn overlapping_areas<H: PagingHandler>(
+    areas: &MemorySet<Backend<H>>,
+    range: GuestPhysAddrRange,
+) -> impl Iterator<Item = (&MemoryArea<Backend<H>>, GuestPhysAddrRange)> {
+    areas
+        .iter()
+        .skip_while(move |area| area.end() <= range.start)
+        .take_while(move |area| area.start() < range.end)
+        .map(move |area| {
+            let start = area.start().max(range.start);
+            let end = area.end().min(range.end);
+            (area, GuestPhysAddrRange::new(start, end))
+        })
+}
+
 impl<H: PagingHandler> fmt::Debug for AddrSpace<H> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("AddrSpace")
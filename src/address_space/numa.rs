@@ -0,0 +1,65 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NUMA-aware allocation mappings.
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, is_aligned_4k};
+use memory_set::MemoryArea;
+use page_table_multiarch::MappingFlags;
+
+use super::{AddrSpace, Backend, NumaPolicy};
+use crate::{AxNumaHal, GuestPhysAddr, mapping_err_to_ax_err};
+
+impl<H: AxNumaHal> AddrSpace<H> {
+    /// Add a new allocation mapping whose frames are allocated from host NUMA
+    /// nodes according to `policy`, both when populating and on page faults.
+    ///
+    /// With [`NumaPolicy::Bind`], populating the mapping fails if the node
+    /// runs out of frames.
+    ///
+    /// See [`Backend`] for more details about the mapping backends.
+    pub fn map_alloc_numa(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        populate: bool,
+        policy: NumaPolicy,
+    ) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let area = MemoryArea::new(
+            start,
+            size,
+            flags.into(),
+            Backend::new_alloc_numa(populate, policy),
+        );
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        Ok(())
+    }
+
+    /// Returns the host NUMA node of the frame backing `gpa`, or `None` if it
+    /// is not mapped.
+    pub fn gpa_to_node(&self, gpa: GuestPhysAddr) -> Option<usize> {
+        self.translate(gpa).and_then(H::node_of)
+    }
+}
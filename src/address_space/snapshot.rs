@@ -0,0 +1,339 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshot and restore of address spaces.
+
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PageIter4K, PhysAddr};
+use page_table_multiarch::PagingHandler;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+
+use super::{AddrSpace, Backend, MappingFlags, SharedMemory};
+use crate::{GuestPhysAddr, MemAttr, MemoryType};
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"AXAS";
+const SNAPSHOT_VERSION: u32 = 3;
+
+const KIND_LINEAR: u8 = 0;
+const KIND_ALLOC: u8 = 1;
+const KIND_MMIO: u8 = 2;
+const KIND_ROM: u8 = 3;
+
+/// Memory types in the order of their snapshot encoding, which is one plus
+/// the index, or zero for none.
+const MEM_TYPES: [MemoryType; 6] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::WriteCombining,
+    MemoryType::Uncached,
+    MemoryType::DeviceNGnRnE,
+    MemoryType::DeviceNGnRE,
+];
+
+/// A sink of snapshot data.
+pub trait SnapshotWriter {
+    /// Writes the whole buffer.
+    fn write_all(&mut self, buf: &[u8]) -> AxResult;
+}
+
+/// A source of snapshot data.
+pub trait SnapshotReader {
+    /// Reads exactly enough bytes to fill the buffer.
+    fn read_exact(&mut self, buf: &mut [u8]) -> AxResult;
+}
+
+impl SnapshotWriter for alloc::vec::Vec<u8> {
+    fn write_all(&mut self, buf: &[u8]) -> AxResult {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl SnapshotReader for &[u8] {
+    fn read_exact(&mut self, buf: &mut [u8]) -> AxResult {
+        if self.len() < buf.len() {
+            return ax_err!(UnexpectedEof, "snapshot truncated");
+        }
+        let (head, tail) = self.split_at(buf.len());
+        buf.copy_from_slice(head);
+        *self = tail;
+        Ok(())
+    }
+}
+
+fn write_u64<W: SnapshotWriter>(writer: &mut W, value: u64) -> AxResult {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u64<R: SnapshotReader>(reader: &mut R) -> AxResult<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_u32<R: SnapshotReader>(reader: &mut R) -> AxResult<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u8<R: SnapshotReader>(reader: &mut R) -> AxResult<u8> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn write_mem_attr<W: SnapshotWriter>(writer: &mut W, attr: MemAttr) -> AxResult {
+    let mem_type = attr
+        .mem_type
+        .map_or(0, |ty| MEM_TYPES.iter().position(|&t| t == ty).unwrap() + 1);
+    writer.write_all(&[
+        mem_type as u8,
+        attr.ignore_pat as u8,
+        attr.raw.is_some() as u8,
+        attr.raw.unwrap_or(0),
+    ])
+}
+
+fn read_mem_attr<R: SnapshotReader>(reader: &mut R) -> AxResult<MemAttr> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    let mem_type = match buf[0] {
+        0 => None,
+        ty => Some(
+            *MEM_TYPES
+                .get(ty as usize - 1)
+                .ok_or_else(|| ax_err_type!(InvalidData, "invalid memory type"))?,
+        ),
+    };
+    if buf[1] > 1 || buf[2] > 1 {
+        return ax_err!(InvalidData, "invalid memory attributes");
+    }
+    Ok(MemAttr {
+        mem_type,
+        ignore_pat: buf[1] != 0,
+        raw: (buf[2] != 0).then_some(buf[3]),
+    })
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Saves the layout of the address space and the contents of its
+    /// populated allocation pages to `writer`.
+    ///
+    /// The snapshot is a stream of little-endian fields:
+    ///
+    /// - Header: magic `b"AXAS"`, format version (`u32`), page table level
+    ///   (`u32`), base and size of the address space (`u64` each), and the
+    ///   number of areas (`u64`).
+    /// - For each area: start, size and [`MappingFlags`] bits (`u64` each), the
+    ///   [`MemAttr`] as the memory type (`u8`, zero for none or one plus its
+    ///   index in declaration order), `ignore_pat` (`u8`), and whether a raw
+    ///   attribute is given and its value (`u8` each), then the backend kind
+    ///   (`u8`) and the backend specific payload:
+    ///   - Linear: `pa_va_offset` (`u64`). The mapped memory is not saved.
+    ///   - Allocation: `populate` (`u8`) and the number of saved pages (`u64`),
+    ///     followed by the offset (`u64`) and the 4K contents of every
+    ///     populated page. Pages never faulted in are skipped, and pages swapped
+    ///     out are read from the swap store.
+    ///   - MMIO: the region identifier (`u64`).
+    ///   - ROM: the contents of the region.
+    ///
+    /// Copy-on-write and swappable mappings are saved as allocation mappings.
+    /// Shared memory mappings, hotplug regions and private pages cannot be
+    /// saved, address spaces with them fail with `Unsupported`.
+    pub fn snapshot<W: SnapshotWriter>(&self, writer: &mut W) -> AxResult {
+        if !self.hotplug.is_empty() {
+            return ax_err!(Unsupported, "cannot snapshot hotplug regions");
+        }
+        if self.private.iter().any(|&word| word != 0) {
+            return ax_err!(Unsupported, "cannot snapshot private pages");
+        }
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&(self.pt.level() as u32).to_le_bytes())?;
+        write_u64(writer, self.base().as_usize() as u64)?;
+        write_u64(writer, self.size() as u64)?;
+        write_u64(writer, self.areas.len() as u64)?;
+
+        for area in self.areas.iter() {
+            write_u64(writer, area.start().as_usize() as u64)?;
+            write_u64(writer, area.size() as u64)?;
+            write_u64(writer, area.flags().flags.bits() as u64)?;
+            write_mem_attr(writer, area.flags().mem_attr)?;
+            let populate = match area.backend() {
+                Backend::Linear { pa_va_offset } => {
+                    writer.write_all(&[KIND_LINEAR])?;
+                    write_u64(writer, *pa_va_offset as u64)?;
+                    continue;
+                }
+                Backend::Alloc { populate, .. } => *populate,
+                Backend::Cow { .. } => false,
+                Backend::Shared { .. } => {
+                    return ax_err!(Unsupported, "cannot snapshot shared memory mappings");
+                }
+                Backend::Mmio { id } => {
+                    writer.write_all(&[KIND_MMIO])?;
+                    write_u64(writer, *id as u64)?;
+                    continue;
+                }
+                Backend::Rom { rom, start } => {
+                    writer.write_all(&[KIND_ROM])?;
+                    for offset in (0..area.size()).step_by(PAGE_SIZE_4K) {
+                        let frame = rom.frame(area.start() - *start + offset).unwrap();
+                        writer.write_all(unsafe {
+                            core::slice::from_raw_parts(
+                                H::phys_to_virt(frame).as_ptr(),
+                                PAGE_SIZE_4K,
+                            )
+                        })?;
+                    }
+                    continue;
+                }
+            };
+            writer.write_all(&[KIND_ALLOC, populate as u8])?;
+
+            let swap = area.backend().swap_store();
+            let pages = || {
+                PageIter4K::new(area.start(), area.end())
+                    .unwrap()
+                    .filter(|&addr| {
+                        self.translate(addr).is_some()
+                            || (swap.is_some() && self.pt.swap_slot(addr).is_some())
+                    })
+            };
+            write_u64(writer, pages().count() as u64)?;
+            for addr in pages() {
+                write_u64(writer, (addr - area.start()) as u64)?;
+                if let Some(paddr) = self.translate(addr) {
+                    writer.write_all(unsafe {
+                        core::slice::from_raw_parts(H::phys_to_virt(paddr).as_ptr(), PAGE_SIZE_4K)
+                    })?;
+                } else {
+                    let mut page = [0; PAGE_SIZE_4K];
+                    swap.unwrap()
+                        .read_page(self.pt.swap_slot(addr).unwrap(), &mut page)?;
+                    writer.write_all(&page)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates an address space from a snapshot saved by
+    /// [`snapshot`](Self::snapshot).
+    ///
+    /// The snapshot must be created by the same version of this crate. Linear
+    /// mappings are mapped to the recorded physical addresses again.
+    pub fn restore<R: SnapshotReader>(reader: &mut R) -> AxResult<Self> {
+        Self::restore_with_fwb(reader, false)
+    }
+
+    /// Like [`restore`](Self::restore), but creates the address space with
+    /// [`new_empty_with_fwb`](Self::new_empty_with_fwb).
+    pub fn restore_with_fwb<R: SnapshotReader>(reader: &mut R, fwb: bool) -> AxResult<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return ax_err!(InvalidData, "invalid snapshot magic");
+        }
+        if read_u32(reader)? != SNAPSHOT_VERSION {
+            return ax_err!(Unsupported, "unsupported snapshot version");
+        }
+        let level = read_u32(reader)? as usize;
+        let base = GuestPhysAddr::from_usize(read_u64(reader)? as usize);
+        let size = read_u64(reader)? as usize;
+        let mut aspace = Self::new_empty_with_fwb(level, base, size, fwb)?;
+
+        // Counts and sizes come from the stream, so they are checked against
+        // the address space before they drive loops or allocations.
+        let area_count = read_u64(reader)?;
+        if area_count > (aspace.size() / PAGE_SIZE_4K) as u64 {
+            return ax_err!(InvalidData, "invalid area count");
+        }
+        for _ in 0..area_count {
+            let start = GuestPhysAddr::from_usize(read_u64(reader)? as usize);
+            let size = read_u64(reader)? as usize;
+            if !aspace.contains_range(start, size) {
+                return ax_err!(InvalidData, "invalid area range");
+            }
+            let flags = MappingFlags::from_bits(read_u64(reader)? as usize)
+                .ok_or_else(|| ax_err_type!(InvalidData, "invalid mapping flags"))?;
+            let mem_attr = read_mem_attr(reader)?;
+            match read_u8(reader)? {
+                KIND_LINEAR => {
+                    let pa_va_offset = read_u64(reader)? as usize;
+                    let paddr = PhysAddr::from_usize(start.as_usize().wrapping_sub(pa_va_offset));
+                    aspace.map_linear(start, paddr, size, flags)?;
+                }
+                KIND_ALLOC => {
+                    aspace.map_alloc(start, size, flags, read_u8(reader)? != 0)?;
+                    let page_count = read_u64(reader)?;
+                    if page_count > (size / PAGE_SIZE_4K) as u64 {
+                        return ax_err!(InvalidData, "invalid page count");
+                    }
+                    for _ in 0..page_count {
+                        let offset = read_u64(reader)? as usize;
+                        if offset >= size || !offset.is_aligned_4k() {
+                            return ax_err!(InvalidData, "invalid page offset");
+                        }
+                        let addr = start + offset;
+                        let frame = match aspace.pt.query(addr) {
+                            Ok((paddr, ..)) => paddr,
+                            Err(_) => {
+                                let frame = H::alloc_frame().ok_or_else(|| {
+                                    ax_err_type!(NoMemory, "allocate physical frame failed")
+                                })?;
+                                if !aspace.pt.map_or_remap_4k(addr, frame, flags) {
+                                    H::dealloc_frame(frame);
+                                    return ax_err!(BadState, "failed to map restored page");
+                                }
+                                frame
+                            }
+                        };
+                        reader.read_exact(unsafe {
+                            core::slice::from_raw_parts_mut(
+                                H::phys_to_virt(frame).as_mut_ptr(),
+                                PAGE_SIZE_4K,
+                            )
+                        })?;
+                    }
+                }
+                KIND_MMIO => aspace.map_mmio(start, size, read_u64(reader)? as usize)?,
+                KIND_ROM => {
+                    // Read straight into the ROM frames, which are the memory
+                    // of the area, rather than through a buffer of its size.
+                    if size == 0 || !start.is_aligned_4k() || !size.is_aligned_4k() {
+                        return ax_err!(InvalidData, "invalid area range");
+                    }
+                    let rom = SharedMemory::<H>::new(size)?;
+                    for offset in (0..size).step_by(PAGE_SIZE_4K) {
+                        let frame = rom.frame(offset).unwrap();
+                        reader.read_exact(unsafe {
+                            core::slice::from_raw_parts_mut(
+                                H::phys_to_virt(frame).as_mut_ptr(),
+                                PAGE_SIZE_4K,
+                            )
+                        })?;
+                    }
+                    aspace.map_rom_frames(start, rom, flags)?;
+                }
+                _ => return ax_err!(InvalidData, "invalid backend kind"),
+            }
+            if !mem_attr.is_default() {
+                aspace.set_mem_attr(start, size, mem_attr)?;
+            }
+        }
+        Ok(aspace)
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Swapping of guest pages.
+//!
+//! Pages of swappable allocation mappings chosen by the host are written to
+//! the [`SwapStore`] of the mapping and their frames are freed. The nested page
+//! table records the swap slots of swapped out pages, and the pages are read
+//! back by [`AddrSpace::handle_page_fault`] on their next access.
+
+use alloc::sync::Arc;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, PageIter4K, is_aligned_4k};
+use memory_set::MemoryArea;
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, Backend, SwapStore, overlapping_areas};
+use crate::{GuestPhysAddr, GuestPhysAddrRange, mapping_err_to_ax_err};
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Add a new allocation mapping whose pages can be swapped out to
+    /// `store` by [`swap_out`](Self::swap_out).
+    ///
+    /// See [`Backend`] for more details about the mapping backends.
+    pub fn map_alloc_swappable(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        populate: bool,
+        store: Arc<dyn SwapStore>,
+    ) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let area = MemoryArea::new(
+            start,
+            size,
+            flags.into(),
+            Backend::new_alloc_swappable(populate, store),
+        );
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        Ok(())
+    }
+
+    /// Swaps out the given pages, and returns the number of pages swapped out.
+    ///
+    /// Each address must be 4K-aligned, belong to a swappable mapping, and
+    /// be neither pinned nor private to a confidential guest. Pages not mapped, including those already swapped out,
+    /// are skipped. No page is swapped out if any of the addresses is invalid.
+    pub fn swap_out(&mut self, gpa_list: &[GuestPhysAddr]) -> AxResult<usize> {
+        for &gpa in gpa_list {
+            if !gpa.is_aligned_4k() {
+                return ax_err!(InvalidInput, "address not aligned");
+            }
+            if !self.va_range.contains(gpa) {
+                return ax_err!(InvalidInput, "address out of range");
+            }
+            if self
+                .areas
+                .find(gpa)
+                .is_none_or(|area| area.backend().swap_store().is_none())
+            {
+                return ax_err!(InvalidInput, "page not swappable");
+            }
+            if self.pinned.contains_key(&gpa) {
+                return ax_err!(ResourceBusy, "page pinned");
+            }
+            if self.is_private(gpa) {
+                return ax_err!(PermissionDenied, "page private");
+            }
+        }
+
+        self.batch(|aspace| {
+            let mut count = 0;
+            let mut result = Ok(());
+            for &gpa in gpa_list {
+                let area = aspace.areas.find(gpa).unwrap();
+                match area.backend().swap_out_page(gpa, &mut aspace.pt) {
+                    Ok(swapped) => count += swapped as usize,
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                }
+            }
+            aspace.pt.flush_tlb(None);
+            result.map(|_| count)
+        })
+    }
+
+    /// Reads the pages swapped out in the given range back into memory.
+    pub fn swap_in(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        for (area, range) in overlapping_areas(&self.areas, range) {
+            if area.backend().swap_store().is_none() {
+                continue;
+            }
+            for addr in PageIter4K::new(range.start, range.end).unwrap() {
+                if !area
+                    .backend()
+                    .swap_in_page(addr, area.flags(), &mut self.pt)
+                {
+                    return ax_err!(NoMemory, "failed to swap in page");
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Guest physical address watchpoints.
+//!
+//! The pages covering a watchpoint lose the watched permissions, so that
+//! accesses to them fault. Faults on the watched bytes are reported as hits,
+//! and faults elsewhere in the pages are false hits. In both cases the
+//! original permissions of the page are restored, so that the faulting
+//! instruction can be single-stepped, and the page is armed again by
+//! [`AddrSpace::rearm_watchpoints`].
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PageIter4K, PhysAddr};
+use page_table_multiarch::{MappingFlags, PagingHandler};
+
+use super::{AddrSpace, Backend, PageFaultOutcome};
+use crate::{GuestPhysAddr, GuestPhysAddrRange, NestedFlags};
+
+const PERM: MappingFlags = MappingFlags::READ
+    .union(MappingFlags::WRITE)
+    .union(MappingFlags::EXECUTE);
+
+/// An access to the bytes watched by a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchpointHit {
+    /// The identifier of the watchpoint.
+    pub id: usize,
+    /// The accessed address.
+    pub addr: GuestPhysAddr,
+    /// The access type.
+    pub access: MappingFlags,
+}
+
+type WatchpointCallback = Box<dyn FnMut(&WatchpointHit) + Send + Sync>;
+
+struct Watchpoint {
+    range: GuestPhysAddrRange,
+    access: MappingFlags,
+}
+
+/// A page covered by watchpoints.
+#[derive(Clone, Copy)]
+struct WatchedPage {
+    frame: PhysAddr,
+    /// The flags of the page before its permissions are removed.
+    flags: NestedFlags,
+}
+
+/// State of the watchpoints of an address space.
+#[derive(Default)]
+pub(super) struct Watchpoints {
+    next_id: usize,
+    points: BTreeMap<usize, Watchpoint>,
+    pages: BTreeMap<GuestPhysAddr, WatchedPage>,
+    /// Watched pages with the original flags restored for single-stepping.
+    stepping: Vec<GuestPhysAddr>,
+    callback: Option<WatchpointCallback>,
+}
+
+impl Watchpoints {
+    /// Removes all watchpoints, the callback is kept.
+    pub(super) fn clear(&mut self) {
+        self.points.clear();
+        self.pages.clear();
+        self.stepping.clear();
+    }
+
+    /// Returns the permissions removed from the page at `page`.
+    fn removed_flags(&self, page: GuestPhysAddr) -> MappingFlags {
+        let page_range = GuestPhysAddrRange::from_start_size(page, PAGE_SIZE_4K);
+        let mut removed = self
+            .points
+            .values()
+            .filter(|point| point.range.overlaps(page_range))
+            .fold(MappingFlags::empty(), |acc, point| acc | point.access);
+        // Write-only pages cannot be mapped on all architectures.
+        if removed.contains(MappingFlags::READ) {
+            removed |= MappingFlags::WRITE;
+        }
+        removed
+    }
+
+    fn is_armed(&self, page: GuestPhysAddr) -> bool {
+        self.pages.contains_key(&page) && !self.stepping.contains(&page)
+    }
+
+    /// Returns the frame of the watched page containing `vaddr`, which may
+    /// have no permissions left.
+    pub(super) fn frame(&self, vaddr: GuestPhysAddr) -> Option<PhysAddr> {
+        let page = vaddr.align_down_4k();
+        self.pages
+            .get(&page)
+            .map(|watched| watched.frame + (vaddr - page))
+    }
+
+    /// Returns whether any page in `range` is watched.
+    pub(super) fn overlaps(&self, range: GuestPhysAddrRange) -> bool {
+        self.pages
+            .range(range.start.align_down_4k()..range.end)
+            .next()
+            .is_some()
+    }
+}
+
+/// Returns the 4K pages covering `range`.
+fn covering_pages(range: GuestPhysAddrRange) -> PageIter4K<GuestPhysAddr> {
+    PageIter4K::new(range.start.align_down_4k(), range.end.align_up_4k()).unwrap()
+}
+
+impl<H: PagingHandler> AddrSpace<H> {
+    /// Sets the callback invoked on every watchpoint hit.
+    pub fn set_watchpoint_callback(
+        &mut self,
+        callback: impl FnMut(&WatchpointHit) + Send + Sync + 'static,
+    ) {
+        self.watch.callback = Some(Box::new(callback));
+    }
+
+    /// Watches `access` to the bytes in the given range, and returns the
+    /// identifier of the watchpoint.
+    ///
+    /// `access` is a combination of `READ`, `WRITE` and `EXECUTE`. The pages
+    /// covering the range are populated, pinned and split into 4K pages, and
+    /// the watched permissions are removed from them. Removing `READ` also
+    /// removes `WRITE`. Watchpoints are not inherited by
+    /// [`fork`](Self::fork).
+    pub fn add_watchpoint(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        access: MappingFlags,
+    ) -> AxResult<usize> {
+        if size == 0 || !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if access.is_empty() || !PERM.contains(access) {
+            return ax_err!(InvalidInput, "invalid watchpoint access");
+        }
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        for addr in covering_pages(range) {
+            match self.areas.find(addr).map(|area| area.backend()) {
+                None => return ax_err!(BadAddress, "watchpoint not mapped"),
+                Some(Backend::Mmio { .. }) => {
+                    return ax_err!(InvalidInput, "cannot watch MMIO regions");
+                }
+                Some(_) => {}
+            }
+        }
+
+        let page_start = start.align_down_4k();
+        let page_size = (start + size).align_up_4k() - page_start;
+        self.pin_pages(page_start, page_size)?;
+        if let Err(err) =
+            self.split_huge_pages(GuestPhysAddrRange::from_start_size(page_start, page_size))
+        {
+            self.unpin_pages(page_start, page_size)?;
+            return Err(err);
+        }
+
+        let id = self.watch.next_id;
+        self.watch.next_id += 1;
+        self.watch.points.insert(id, Watchpoint { range, access });
+        for page in covering_pages(range) {
+            if !self.watch.pages.contains_key(&page) {
+                let (frame, flags, _) = self.pt.query_nested(page).unwrap();
+                self.watch.pages.insert(page, WatchedPage { frame, flags });
+            }
+            self.arm_watch_page(page);
+        }
+        self.pt.flush_tlb(None);
+        Ok(id)
+    }
+
+    /// Removes the watchpoint with the given identifier.
+    ///
+    /// Pages no longer watched get back the flags they had before being
+    /// watched, and are unpinned.
+    pub fn remove_watchpoint(&mut self, id: usize) -> AxResult {
+        let Some(point) = self.watch.points.remove(&id) else {
+            return ax_err!(NotFound, "watchpoint not found");
+        };
+        for page in covering_pages(point.range) {
+            if self.watch.removed_flags(page).is_empty() {
+                let watched = self.watch.pages.remove(&page).unwrap();
+                if let Some(pos) = self.watch.stepping.iter().position(|&p| p == page) {
+                    // The original flags are already restored.
+                    self.watch.stepping.swap_remove(pos);
+                } else {
+                    self.pt.map_or_remap_4k(page, watched.frame, watched.flags);
+                }
+            } else {
+                self.arm_watch_page(page);
+            }
+        }
+        self.pt.flush_tlb(None);
+        let page_start = point.range.start.align_down_4k();
+        self.unpin_pages(page_start, point.range.end.align_up_4k() - page_start)
+    }
+
+    /// Removes the watched permissions again from the pages restored for
+    /// single-stepping.
+    ///
+    /// Should be called after the instruction that caused a
+    /// [`Watchpoint`](PageFaultOutcome::Watchpoint) or
+    /// [`WatchStep`](PageFaultOutcome::WatchStep) fault is executed.
+    pub fn rearm_watchpoints(&mut self) {
+        let stepping = core::mem::take(&mut self.watch.stepping);
+        for &page in &stepping {
+            self.refresh_watch_page(page);
+        }
+        if !stepping.is_empty() {
+            self.pt.flush_tlb(None);
+        }
+    }
+
+    /// Removes the watchpoints overlapping `range`.
+    pub(super) fn remove_watchpoints_in(&mut self, range: GuestPhysAddrRange) -> AxResult {
+        let ids: Vec<_> = self
+            .watch
+            .points
+            .iter()
+            .filter(|(_, point)| point.range.overlaps(range))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in ids {
+            self.remove_watchpoint(id)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a fault on a watched page.
+    ///
+    /// Returns `None` if the page is not armed, or the fault is not caused by
+    /// the removed permissions.
+    pub(super) fn handle_watch_fault(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        access_flags: MappingFlags,
+    ) -> Option<PageFaultOutcome> {
+        let page = vaddr.align_down_4k();
+        if !self.watch.is_armed(page)
+            || !self.watch.removed_flags(page).intersects(access_flags)
+            || !self.areas.find(vaddr)?.flags().flags.contains(access_flags)
+        {
+            return None;
+        }
+
+        let hit = self
+            .watch
+            .points
+            .iter()
+            .find(|(_, point)| point.range.contains(vaddr) && point.access.intersects(access_flags))
+            .map(|(&id, _)| WatchpointHit {
+                id,
+                addr: vaddr,
+                access: access_flags,
+            });
+        // Let the access through until the watchpoints are armed again.
+        self.disarm_watch_page(page);
+        Some(match hit {
+            Some(hit) => {
+                if let Some(callback) = self.watch.callback.as_mut() {
+                    callback(&hit);
+                }
+                PageFaultOutcome::Watchpoint {
+                    id: hit.id,
+                    addr: vaddr,
+                }
+            }
+            None => PageFaultOutcome::WatchStep { addr: vaddr },
+        })
+    }
+
+    /// Arms the page at `page` again after it was remapped by a page fault
+    /// handler.
+    pub(super) fn rearm_watch_page(&mut self, page: GuestPhysAddr) {
+        if !self.watch.is_armed(page) {
+            return;
+        }
+        let remapped = self
+            .pt
+            .query(page)
+            .is_ok_and(|(_, flags, _)| flags.intersects(self.watch.removed_flags(page)));
+        if remapped {
+            self.refresh_watch_page(page);
+        }
+    }
+
+    /// Applies `update` to the original flags of the watched pages in `range`,
+    /// after the flags of their mappings are changed.
+    pub(super) fn update_watch_flags(
+        &mut self,
+        range: GuestPhysAddrRange,
+        update: impl Fn(NestedFlags) -> NestedFlags,
+    ) {
+        let pages: Vec<_> = self
+            .watch
+            .pages
+            .range(range.start..range.end)
+            .map(|(&page, _)| page)
+            .collect();
+        for page in pages {
+            let watched = self.watch.pages.get_mut(&page).unwrap();
+            watched.flags = update(watched.flags);
+            if self.watch.stepping.contains(&page) {
+                let watched = self.watch.pages[&page];
+                self.pt.map_or_remap_4k(page, watched.frame, watched.flags);
+            } else {
+                self.arm_watch_page(page);
+            }
+        }
+    }
+
+    fn disarm_watch_page(&mut self, page: GuestPhysAddr) {
+        if self.watch.is_armed(page) {
+            let watched = self.watch.pages[&page];
+            self.pt.map_or_remap_4k(page, watched.frame, watched.flags);
+            self.watch.stepping.push(page);
+        }
+    }
+
+    /// Takes the current mapping of the page as its original mapping, and
+    /// arms it.
+    fn refresh_watch_page(&mut self, page: GuestPhysAddr) {
+        if let Ok((frame, flags, _)) = self.pt.query_nested(page) {
+            self.watch.pages.insert(page, WatchedPage { frame, flags });
+        }
+        self.arm_watch_page(page);
+    }
+
+    fn arm_watch_page(&mut self, page: GuestPhysAddr) {
+        if self.watch.stepping.contains(&page) {
+            return;
+        }
+        let watched = self.watch.pages[&page];
+        let flags = watched.flags - self.watch.removed_flags(page);
+        if flags.flags.is_empty() {
+            // A page without permissions is not mapped, its frame is kept in
+            // the watched page only.
+            let _ = self.pt.unmap(page);
+        } else {
+            self.pt.map_or_remap_4k(page, watched.frame, flags);
+        }
+    }
+}
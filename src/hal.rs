@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use page_table_multiarch::PagingHandler;
+
 use crate::{HostPhysAddr, HostVirtAddr};
 
 /// Hardware abstraction layer for memory management.
@@ -52,3 +54,24 @@ pub trait AxMmHal {
     /// * `HostPhysAddr` - The corresponding physical address.
     fn virt_to_phys(vaddr: HostVirtAddr) -> HostPhysAddr;
 }
+
+/// Hardware abstraction layer for NUMA-aware frame allocation.
+///
+/// Frames allocated by [`alloc_frames_on_node`](Self::alloc_frames_on_node)
+/// are deallocated by [`PagingHandler::dealloc_frames`].
+pub trait AxNumaHal: PagingHandler {
+    /// Allocates `num` contiguous frames aligned to `align` bytes from the
+    /// host NUMA node `node`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<HostPhysAddr>` - Some containing the physical address of the first frame, or None if the node has no such frames available.
+    fn alloc_frames_on_node(num: usize, align: usize, node: usize) -> Option<HostPhysAddr>;
+
+    /// Returns the host NUMA node of the frame at the given physical address.
+    ///
+    /// # Parameters
+    ///
+    /// * `paddr` - The physical address of the frame.
+    fn node_of(paddr: HostPhysAddr) -> Option<usize>;
+}
@@ -26,6 +26,7 @@ mod address_space;
 pub mod device;
 mod frame;
 mod hal;
+mod mem_type;
 mod memory_accessor;
 mod npt;
 
@@ -33,9 +34,13 @@ pub use addr::*;
 pub use address_space::*;
 
 pub use frame::PhysFrame;
-pub use hal::AxMmHal;
+pub use hal::{AxMmHal, AxNumaHal};
 
+pub use mem_type::{MemAttr, MemoryType, NestedFlags};
 pub use memory_accessor::GuestMemoryAccessor;
+pub use npt::Stage2Context;
+#[cfg(target_arch = "x86_64")]
+pub use npt::{InvEptType, invept};
 
 use axerrno::AxError;
 use memory_set::MappingError;
@@ -0,0 +1,161 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Memory types (cacheability) of nested mappings.
+//!
+//! The memory attributes of a mapping are given by a [`MemAttr`] next to its
+//! [`MappingFlags`], both carried by [`NestedFlags`] so that they follow the
+//! mapping through every backend and page fault handler. Mappings with the
+//! default attributes keep the behavior of the `DEVICE` and `UNCACHED` flags.
+
+use page_table_entry::MappingFlags;
+
+/// The memory type of a mapping.
+///
+/// Types an architecture cannot express are mapped to the closest type it
+/// has, and read back from the page table as that type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Write-back cacheable normal memory.
+    WriteBack,
+    /// Write-through cacheable normal memory. Normal non-cacheable on RISC-V.
+    WriteThrough,
+    /// Non-cacheable normal memory, writes may be combined.
+    WriteCombining,
+    /// Strongly ordered uncacheable memory. The same as
+    /// [`DeviceNGnRnE`](Self::DeviceNGnRnE) on AArch64, and I/O memory on
+    /// RISC-V.
+    Uncached,
+    /// Device memory without gathering, reordering and early write
+    /// acknowledgement. Uncacheable on x86, I/O memory on RISC-V.
+    DeviceNGnRnE,
+    /// Device memory without gathering and reordering. Uncacheable on x86,
+    /// I/O memory on RISC-V.
+    DeviceNGnRE,
+}
+
+impl MemoryType {
+    /// Returns the memory type given by the `DEVICE` and `UNCACHED` flags.
+    ///
+    /// `DEVICE` and `UNCACHED` together mean [`Uncached`](Self::Uncached),
+    /// `DEVICE` alone means [`DeviceNGnRnE`](Self::DeviceNGnRnE), and
+    /// [`WriteBack`](Self::WriteBack) otherwise.
+    pub fn of_flags(flags: MappingFlags) -> Self {
+        if !flags.contains(MappingFlags::DEVICE) {
+            Self::WriteBack
+        } else if flags.contains(MappingFlags::UNCACHED) {
+            Self::Uncached
+        } else {
+            Self::DeviceNGnRnE
+        }
+    }
+}
+
+/// Memory attributes of a mapping.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemAttr {
+    /// The memory type, or `None` to take it from the mapping flags (see
+    /// [`MemoryType::of_flags`]).
+    pub mem_type: Option<MemoryType>,
+    /// Whether the guest memory type (the PAT on x86) is ignored. Only EPT
+    /// supports ignoring the guest memory type.
+    pub ignore_pat: bool,
+    /// A raw architecture specific memory attribute, which takes precedence
+    /// over the memory type.
+    ///
+    /// Only AArch64 supports it, as the 4-bit stage-2 `MemAttr[3:0]` field.
+    /// Entries with an attribute not given by a [`MemoryType`] are read back
+    /// with both the raw attribute and the closest memory type.
+    pub raw: Option<u8>,
+}
+
+impl MemAttr {
+    /// Returns the attributes of the given memory type.
+    pub const fn new(mem_type: MemoryType) -> Self {
+        Self {
+            mem_type: Some(mem_type),
+            ignore_pat: false,
+            raw: None,
+        }
+    }
+
+    /// Returns the attributes with the guest memory type ignored or not.
+    pub const fn with_ignore_pat(self, ignore_pat: bool) -> Self {
+        Self { ignore_pat, ..self }
+    }
+
+    /// Returns the attributes with a raw architecture specific attribute.
+    pub const fn with_raw(self, raw: u8) -> Self {
+        Self {
+            raw: Some(raw),
+            ..self
+        }
+    }
+
+    /// Returns whether these are the default attributes, given by the mapping
+    /// flags alone.
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Flags of a nested mapping: the permissions and attributes given by
+/// [`MappingFlags`], and the memory attributes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NestedFlags {
+    /// The mapping flags.
+    pub flags: MappingFlags,
+    /// The memory attributes.
+    pub mem_attr: MemAttr,
+}
+
+impl NestedFlags {
+    /// Creates flags with the given memory attributes.
+    pub const fn new(flags: MappingFlags, mem_attr: MemAttr) -> Self {
+        Self { flags, mem_attr }
+    }
+
+    /// Returns the flags with the mapping flags replaced, keeping the memory
+    /// attributes.
+    pub const fn with_flags(self, flags: MappingFlags) -> Self {
+        Self { flags, ..self }
+    }
+
+    /// Returns the memory type of the mapping.
+    pub fn mem_type(&self) -> MemoryType {
+        self.mem_attr
+            .mem_type
+            .unwrap_or_else(|| MemoryType::of_flags(self.flags))
+    }
+}
+
+impl Default for NestedFlags {
+    fn default() -> Self {
+        MappingFlags::empty().into()
+    }
+}
+
+impl From<MappingFlags> for NestedFlags {
+    fn from(flags: MappingFlags) -> Self {
+        Self::new(flags, MemAttr::default())
+    }
+}
+
+impl core::ops::Sub<MappingFlags> for NestedFlags {
+    type Output = Self;
+
+    fn sub(self, rhs: MappingFlags) -> Self {
+        self.with_flags(self.flags - rhs)
+    }
+}
@@ -12,12 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(target_arch = "aarch64")]
 use core::arch::asm;
 use core::fmt;
 use page_table_entry::{GenericPTE, MappingFlags};
 use page_table_multiarch::PagingMetaData;
 // use memory_addr::HostPhysAddr;
-use crate::{GuestPhysAddr, HostPhysAddr};
+#[cfg(all(feature = "arm-el2", target_arch = "aarch64"))]
+use crate::GuestPhysAddrRange;
+use crate::{GuestPhysAddr, HostPhysAddr, MemAttr, MemoryType};
+#[cfg(all(feature = "arm-el2", target_arch = "aarch64"))]
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K};
 
 bitflags::bitflags! {
     /// Memory attribute fields in the VMSAv8-64 translation table format descriptors.
@@ -69,77 +74,244 @@ bitflags::bitflags! {
     }
 }
 
-#[repr(u64)]
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-enum MemType {
-    Device = 0,
-    Normal = 1,
-    NormalNonCache = 2,
+const VTTBR_VMID_SHIFT: u64 = 48;
+
+/// Invalidates the stage-2 TLB entries of `vmid` in `range`, or all entries of
+/// `vmid` if `range` is `None`.
+///
+/// `VTTBR_EL2.VMID` is switched to `vmid` for the invalidation and restored
+/// afterwards, with IRQs and FIQs masked in between so that no interrupt
+/// handler or context switch runs with the wrong VMID. The combined stage-1
+/// and stage-2 entries of the guest are also invalidated, as they may cache the
+/// old IPA translations. `HCR_EL2.TGE` must be clear, otherwise the EL1
+/// invalidations apply to the EL2&0 regime.
+#[cfg(all(feature = "arm-el2", target_arch = "aarch64"))]
+pub fn flush_stage2_tlb(vmid: u16, range: Option<GuestPhysAddrRange>) {
+    unsafe {
+        let vttbr: u64;
+        asm!("mrs {}, vttbr_el2", out(reg) vttbr);
+        let daif = vttbr_with_vmid(vttbr, vmid).map(|target| {
+            let daif: u64;
+            asm!("mrs {}, daif; msr daifset, #3", out(reg) daif);
+            asm!("msr vttbr_el2, {}; isb", in(reg) target);
+            daif
+        });
+        if let Some(range) = range {
+            asm!("dsb ishst");
+            let mut ipa = range.start.align_down_4k();
+            while ipa < range.end {
+                asm!("tlbi ipas2e1is, {}", in(reg) ipa.as_usize() >> 12);
+                ipa += PAGE_SIZE_4K;
+            }
+            asm!("dsb ish; tlbi vmalle1is; dsb ish; isb")
+        } else {
+            asm!("dsb ishst; tlbi vmalls12e1is; dsb ish; isb")
+        }
+        if let Some(daif) = daif {
+            asm!("msr vttbr_el2, {}; isb", in(reg) vttbr);
+            asm!("msr daif, {}", in(reg) daif);
+        }
+    }
+}
+
+/// Returns `vttbr` with its VMID replaced by `vmid`, or `None` if it already
+/// has that VMID and needs no switch.
+#[cfg(any(feature = "arm-el2", test))]
+fn vttbr_with_vmid(vttbr: u64, vmid: u16) -> Option<u64> {
+    ((vttbr >> VTTBR_VMID_SHIFT) as u16 != vmid)
+        .then_some((vttbr & !(0xffff << VTTBR_VMID_SHIFT)) | ((vmid as u64) << VTTBR_VMID_SHIFT))
+}
+
+/// Stage-2 translation context of a nested page table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stage2Context {
+    /// `VTTBR_EL2`, with the root table address and the VMID.
+    pub vttbr: u64,
+    /// `VTCR_EL2`, with the 4K granule, the IPA size and the starting level of
+    /// the table, write-back inner shareable table walks, and a 48-bit
+    /// physical address size. 16-bit VMIDs are enabled for VMIDs above 255.
+    pub vtcr: u64,
+}
+
+impl Stage2Context {
+    pub(crate) fn new(root_paddr: HostPhysAddr, levels: usize, vmid: u16) -> Self {
+        const VTCR_SL0_SHIFT: u64 = 6;
+        const VTCR_IRGN0_WBWA: u64 = 0b01 << 8;
+        const VTCR_ORGN0_WBWA: u64 = 0b01 << 10;
+        const VTCR_SH0_INNER: u64 = 0b11 << 12;
+        const VTCR_PS_48BIT: u64 = 0b101 << 16;
+        const VTCR_VS: u64 = 1 << 19;
+        const VTCR_RES1: u64 = 1 << 31;
+        // The root is a single table, so the IPA size is given by the levels.
+        let ipa_bits = 12 + 9 * levels as u64;
+        // With the 4K granule, SL0 is 2 for level 0 and 1 for level 1.
+        let sl0 = levels as u64 - 2;
+        let mut vtcr = (64 - ipa_bits)
+            | (sl0 << VTCR_SL0_SHIFT)
+            | VTCR_IRGN0_WBWA
+            | VTCR_ORGN0_WBWA
+            | VTCR_SH0_INNER
+            | VTCR_PS_48BIT
+            | VTCR_RES1;
+        if vmid > 0xff {
+            vtcr |= VTCR_VS;
+        }
+        Self {
+            vttbr: root_paddr.as_usize() as u64 | ((vmid as u64) << VTTBR_VMID_SHIFT),
+            vtcr,
+        }
+    }
+}
+
+/// Stage-2 `MemAttr[3:0]` encodings.
+mod mem_attr {
+    pub const DEVICE_NGNRNE: u64 = 0b0000;
+    pub const DEVICE_NGNRE: u64 = 0b0001;
+    /// Outer and inner write-back.
+    pub const NORMAL_WB: u64 = 0b1111;
+    /// Outer and inner write-through.
+    pub const NORMAL_WT: u64 = 0b1010;
+    /// Outer and inner non-cacheable, the same with FEAT_S2FWB.
+    pub const NORMAL_NC: u64 = 0b0101;
+    /// Outer non-cacheable and inner write-back.
+    pub const NORMAL_OUTER_NC: u64 = 0b0111;
+    /// Write-back forced by stage 2 with FEAT_S2FWB.
+    pub const FWB_NORMAL_WB: u64 = 0b0110;
 }
 
 impl DescriptorAttr {
     #[allow(clippy::unusual_byte_groupings)]
     const ATTR_INDEX_MASK: u64 = 0b1111_00;
-    const PTE_S2_MEM_ATTR_NORMAL_INNER_WRITE_BACK_CACHEABLE: u64 = 0b11 << 2;
-    const PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_CACHEABLE: u64 = 0b11 << 4;
-    const PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_NOCACHEABLE: u64 = 0b1 << 4;
-    const NORMAL_BIT: u64 = Self::PTE_S2_MEM_ATTR_NORMAL_INNER_WRITE_BACK_CACHEABLE
-        | Self::PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_CACHEABLE;
-
-    const fn from_mem_type(mem_type: MemType) -> Self {
-        let bits = match mem_type {
-            MemType::Normal => Self::NORMAL_BIT | Self::SHAREABLE.bits(),
-            MemType::NormalNonCache => {
-                Self::PTE_S2_MEM_ATTR_NORMAL_INNER_WRITE_BACK_CACHEABLE
-                    | Self::PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_NOCACHEABLE
-                    | Self::SHAREABLE.bits()
+
+    const fn from_mem_attr(mem_attr: u64) -> Self {
+        Self::from_bits_retain((mem_attr << 2) | Self::SHAREABLE.bits())
+    }
+
+    const fn mem_attr(&self) -> u64 {
+        (self.bits() & Self::ATTR_INDEX_MASK) >> 2
+    }
+}
+
+/// Returns the stage-2 `MemAttr` of a mapping.
+fn mem_attr_of(flags: MappingFlags, attr: MemAttr, fwb: bool) -> u64 {
+    if let Some(raw) = attr.raw {
+        return raw as u64;
+    }
+    let write_back = if fwb {
+        mem_attr::FWB_NORMAL_WB
+    } else {
+        mem_attr::NORMAL_WB
+    };
+    match attr.mem_type {
+        Some(MemoryType::WriteBack) => write_back,
+        // Stage 2 cannot give write-through with FEAT_S2FWB.
+        Some(MemoryType::WriteThrough) if fwb => mem_attr::NORMAL_NC,
+        Some(MemoryType::WriteThrough) => mem_attr::NORMAL_WT,
+        Some(MemoryType::WriteCombining) => mem_attr::NORMAL_NC,
+        Some(MemoryType::Uncached | MemoryType::DeviceNGnRnE) => mem_attr::DEVICE_NGNRNE,
+        Some(MemoryType::DeviceNGnRE) => mem_attr::DEVICE_NGNRE,
+        None if flags.contains(MappingFlags::DEVICE | MappingFlags::UNCACHED) => {
+            if fwb {
+                mem_attr::NORMAL_NC
+            } else {
+                mem_attr::NORMAL_OUTER_NC
             }
-            MemType::Device => Self::SHAREABLE.bits(),
-        };
-        Self::from_bits_retain(bits)
-    }
-
-    fn mem_type(&self) -> MemType {
-        let idx = self.bits() & Self::ATTR_INDEX_MASK;
-        match idx {
-            Self::NORMAL_BIT => MemType::Normal,
-            Self::PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_NOCACHEABLE => MemType::NormalNonCache,
-            0 => MemType::Device,
-            _ => panic!("Invalid memory attribute index"),
         }
+        None if flags.contains(MappingFlags::DEVICE) => mem_attr::DEVICE_NGNRNE,
+        None => write_back,
     }
 }
 
-impl From<DescriptorAttr> for MappingFlags {
-    fn from(attr: DescriptorAttr) -> Self {
-        let mut flags = Self::empty();
-        if attr.contains(DescriptorAttr::VALID) {
-            flags |= Self::READ;
+/// Returns the memory attributes of a stage-2 `MemAttr`, such that
+/// [`mem_attr_of`] gives it back.
+///
+/// Attributes not produced for a [`MemoryType`] are kept as raw attributes,
+/// together with the closest memory type.
+fn mem_attr_from(mem_attr: u64, fwb: bool) -> MemAttr {
+    let mem_type = match mem_attr {
+        mem_attr::DEVICE_NGNRNE => Some(MemoryType::DeviceNGnRnE),
+        mem_attr::DEVICE_NGNRE => Some(MemoryType::DeviceNGnRE),
+        mem_attr::NORMAL_NC => Some(MemoryType::WriteCombining),
+        mem_attr::FWB_NORMAL_WB if fwb => Some(MemoryType::WriteBack),
+        mem_attr::NORMAL_WB if !fwb => Some(MemoryType::WriteBack),
+        mem_attr::NORMAL_WT if !fwb => Some(MemoryType::WriteThrough),
+        _ => None,
+    };
+    if let Some(mem_type) = mem_type {
+        return MemAttr::new(mem_type);
+    }
+    let closest = if fwb {
+        // MemAttr[2] selects normal memory, MemAttr[1:0] is the device type,
+        // or 0b01 for non-cacheable, 0b10 for write-back and 0b11 to use the
+        // stage 1 attributes, which are at most write-back.
+        match (mem_attr & 0b100 != 0, mem_attr & 0b11) {
+            (false, _) => MemoryType::DeviceNGnRE,
+            (true, 0b10 | 0b11) => MemoryType::WriteBack,
+            (true, _) => MemoryType::WriteCombining,
+        }
+    } else {
+        // MemAttr[3:2] is the outer attribute, or 0b00 for device memory.
+        // MemAttr[1:0] is the inner attribute, or the device type. Normal
+        // memory takes the least cacheable of them, where 0b01 is
+        // non-cacheable, 0b10 write-through and 0b11 write-back.
+        match (mem_attr >> 2, (mem_attr >> 2).min(mem_attr & 0b11)) {
+            (0, _) => MemoryType::DeviceNGnRE,
+            (_, 0b11) => MemoryType::WriteBack,
+            (_, 0b10) => MemoryType::WriteThrough,
+            _ => MemoryType::WriteCombining,
         }
-        if !attr.contains(DescriptorAttr::S2AP_WO) {
-            flags |= Self::WRITE;
+    };
+    MemAttr::new(closest).with_raw(mem_attr as u8)
+}
+
+/// Returns the `DEVICE` and `UNCACHED` flags of a stage-2 `MemAttr`, such
+/// that [`mem_attr_of`] gives it back with the default attributes if possible.
+fn mem_flags_of(mem_attr: u64, fwb: bool) -> MappingFlags {
+    if mem_attr >> 2 == 0 {
+        // Device memory, regardless of FEAT_S2FWB.
+        MappingFlags::DEVICE
+    } else if mem_attr
+        == mem_attr_of(
+            MappingFlags::DEVICE | MappingFlags::UNCACHED,
+            MemAttr::default(),
+            fwb,
+        )
+    {
+        MappingFlags::DEVICE | MappingFlags::UNCACHED
+    } else {
+        MappingFlags::empty()
+    }
+}
+
+impl DescriptorAttr {
+    /// Returns the mapping flags of the descriptor, with `MemAttr` encoded for
+    /// FEAT_S2FWB if `fwb`.
+    fn mapping_flags(&self, fwb: bool) -> MappingFlags {
+        let mut flags = mem_flags_of(self.mem_attr(), fwb);
+        if self.contains(Self::VALID) {
+            flags |= MappingFlags::READ;
         }
-        if !attr.contains(DescriptorAttr::XN) {
-            flags |= Self::EXECUTE;
+        if !self.contains(Self::S2AP_WO) {
+            flags |= MappingFlags::WRITE;
         }
-        if attr.mem_type() == MemType::Device {
-            flags |= Self::DEVICE;
+        if !self.contains(Self::XN) {
+            flags |= MappingFlags::EXECUTE;
         }
         flags
     }
 }
 
+impl From<DescriptorAttr> for MappingFlags {
+    fn from(attr: DescriptorAttr) -> Self {
+        attr.mapping_flags(false)
+    }
+}
+
+// Descriptors are created without FEAT_S2FWB, `NestedPageTable` encodes the
+// memory attributes of tables using it afterwards.
 impl From<MappingFlags> for DescriptorAttr {
     fn from(flags: MappingFlags) -> Self {
-        let mut attr = if flags.contains(MappingFlags::DEVICE) {
-            if flags.contains(MappingFlags::UNCACHED) {
-                Self::from_mem_type(MemType::NormalNonCache)
-            } else {
-                Self::from_mem_type(MemType::Device)
-            }
-        } else {
-            Self::from_mem_type(MemType::Normal)
-        };
+        let mut attr = Self::from_mem_attr(mem_attr_of(flags, MemAttr::default(), false));
         if flags.contains(MappingFlags::READ) {
             attr |= Self::VALID | Self::S2AP_RO;
         }
@@ -150,11 +322,11 @@ impl From<MappingFlags> for DescriptorAttr {
     }
 }
 
-/// A VMSAv8-64 translation table descriptor.
+/// A VMSAv8-64 stage-2 translation table descriptor.
 ///
-/// Note that the **AttrIndx\[2:0\]** (bit\[4:2\]) field is set to `0` for device
-/// memory, and `1` for normal memory. The system must configure the MAIR_ELx
-/// system register accordingly.
+/// The **MemAttr\[3:0\]** (bit\[5:2\]) field gives the stage-2 memory type,
+/// encoded without FEAT_S2FWB unless the table is created with it (see
+/// [`MemAttrPTE`](super::MemAttrPTE)).
 #[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct A64PTEHV(u64);
@@ -168,6 +340,36 @@ impl A64PTEHV {
     }
 }
 
+impl super::AccessedFlag for A64PTEHV {
+    fn is_accessed(&self) -> bool {
+        DescriptorAttr::from_bits_truncate(self.0).contains(DescriptorAttr::AF)
+    }
+
+    fn set_accessed(&mut self, accessed: bool) {
+        if accessed {
+            self.0 |= DescriptorAttr::AF.bits();
+        } else {
+            self.0 &= !DescriptorAttr::AF.bits();
+        }
+    }
+}
+
+impl super::MemAttrPTE for A64PTEHV {
+    fn set_mem_attr(&mut self, flags: MappingFlags, attr: MemAttr, fwb: bool) {
+        let mem_attr = mem_attr_of(flags, attr, fwb);
+        self.0 = (self.0 & !DescriptorAttr::ATTR_INDEX_MASK)
+            | ((mem_attr << 2) & DescriptorAttr::ATTR_INDEX_MASK);
+    }
+
+    fn mem_attr(&self, fwb: bool) -> MemAttr {
+        mem_attr_from(DescriptorAttr::from_bits_truncate(self.0).mem_attr(), fwb)
+    }
+
+    fn flags_with(&self, fwb: bool) -> MappingFlags {
+        DescriptorAttr::from_bits_truncate(self.0).mapping_flags(fwb)
+    }
+}
+
 impl GenericPTE for A64PTEHV {
     fn bits(self) -> usize {
         self.0 as usize
@@ -193,7 +395,9 @@ impl GenericPTE for A64PTEHV {
         self.0 = (self.0 & !Self::PHYS_ADDR_MASK) | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK)
     }
     fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
-        let mut attr = DescriptorAttr::from(flags) | DescriptorAttr::AF;
+        // Keep the access flag, changing permissions is not an access.
+        let mut attr = DescriptorAttr::from(flags)
+            | (DescriptorAttr::from_bits_truncate(self.0) & DescriptorAttr::AF);
         if !is_huge {
             attr |= DescriptorAttr::NON_BLOCK;
         }
@@ -209,7 +413,7 @@ impl GenericPTE for A64PTEHV {
         !DescriptorAttr::from_bits_truncate(self.0).contains(DescriptorAttr::NON_BLOCK)
     }
     fn clear(&mut self) {
-        self.0 = 0
+        *self = Self::empty()
     }
 }
 
@@ -238,27 +442,18 @@ impl PagingMetaData for A64HVPagingMetaDataL3 {
 
     type VirtAddr = GuestPhysAddr;
 
+    // Stage-2 entries are tagged with the VMID of the table, which is not
+    // known here. With `arm-el2`, `NestedPageTable` invalidates them with
+    // `flush_stage2_tlb` instead.
+    #[allow(unused_variables)]
     fn flush_tlb(vaddr: Option<Self::VirtAddr>) {
+        #[cfg(all(not(feature = "arm-el2"), target_arch = "aarch64"))]
         unsafe {
             if let Some(vaddr) = vaddr {
-                #[cfg(not(feature = "arm-el2"))]
-                {
-                    asm!("tlbi vaae1is, {}; dsb sy; isb", in(reg) vaddr.as_usize())
-                }
-                #[cfg(feature = "arm-el2")]
-                {
-                    asm!("tlbi vae2is, {}; dsb sy; isb", in(reg) vaddr.as_usize())
-                }
+                asm!("tlbi vaae1is, {}; dsb sy; isb", in(reg) vaddr.as_usize())
             } else {
                 // flush the entire TLB
-                #[cfg(not(feature = "arm-el2"))]
-                {
-                    asm!("tlbi vmalle1; dsb sy; isb")
-                }
-                #[cfg(feature = "arm-el2")]
-                {
-                    asm!("tlbi alle2is; dsb sy; isb")
-                }
+                asm!("tlbi vmalle1; dsb sy; isb")
             }
         }
     }
@@ -278,28 +473,75 @@ impl PagingMetaData for A64HVPagingMetaDataL4 {
 
     type VirtAddr = GuestPhysAddr;
 
+    // Stage-2 entries are tagged with the VMID of the table, which is not
+    // known here. With `arm-el2`, `NestedPageTable` invalidates them with
+    // `flush_stage2_tlb` instead.
+    #[allow(unused_variables)]
     fn flush_tlb(vaddr: Option<Self::VirtAddr>) {
+        #[cfg(all(not(feature = "arm-el2"), target_arch = "aarch64"))]
         unsafe {
             if let Some(vaddr) = vaddr {
-                #[cfg(not(feature = "arm-el2"))]
-                {
-                    asm!("tlbi vaae1is, {}; dsb sy; isb", in(reg) vaddr.as_usize())
-                }
-                #[cfg(feature = "arm-el2")]
-                {
-                    asm!("tlbi vae2is, {}; dsb sy; isb", in(reg) vaddr.as_usize())
-                }
+                asm!("tlbi vaae1is, {}; dsb sy; isb", in(reg) vaddr.as_usize())
             } else {
                 // flush the entire TLB
-                #[cfg(not(feature = "arm-el2"))]
-                {
-                    asm!("tlbi vmalle1; dsb sy; isb")
-                }
-                #[cfg(feature = "arm-el2")]
-                {
-                    asm!("tlbi alle2is; dsb sy; isb")
-                }
+                asm!("tlbi vmalle1; dsb sy; isb")
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::npt::arch::MemAttrPTE;
+
+    #[test]
+    fn test_vttbr_with_vmid() {
+        let vttbr = 5 << VTTBR_VMID_SHIFT | 0x8020_0000;
+        // No switch is needed to flush the current VMID.
+        assert_eq!(vttbr_with_vmid(vttbr, 5), None);
+        assert_eq!(
+            vttbr_with_vmid(vttbr, 0x1234),
+            Some(0x1234 << VTTBR_VMID_SHIFT | 0x8020_0000)
+        );
+        assert_eq!(vttbr_with_vmid(vttbr, 0), Some(0x8020_0000));
+    }
+
+    #[test]
+    fn test_mem_attr_round_trip() {
+        let types = [
+            MemoryType::WriteBack,
+            MemoryType::WriteThrough,
+            MemoryType::WriteCombining,
+            MemoryType::Uncached,
+            MemoryType::DeviceNGnRnE,
+            MemoryType::DeviceNGnRE,
+        ];
+        for fwb in [false, true] {
+            for mem_type in types {
+                let bits = mem_attr_of(MappingFlags::empty(), MemAttr::new(mem_type), fwb);
+                let decoded = mem_attr_from(bits, fwb);
+                assert_eq!(mem_attr_of(MappingFlags::empty(), decoded, fwb), bits);
+            }
+        }
+
+        // With FEAT_S2FWB, 0b110 forces write-back, and 0b111 takes the
+        // stage 1 attributes.
+        let write_back = MemAttr::new(MemoryType::WriteBack);
+        assert_eq!(mem_attr_of(MappingFlags::empty(), write_back, true), 0b0110);
+        assert_eq!(mem_attr_from(0b0110, true), write_back);
+        assert_eq!(mem_attr_from(0b0111, true).raw, Some(0b0111));
+        assert_eq!(
+            mem_attr_of(MappingFlags::empty(), write_back, false),
+            0b1111
+        );
+        assert_eq!(mem_attr_from(0b1111, false), write_back);
+
+        // The encoded bits are stored in the descriptor.
+        let mut desc =
+            A64PTEHV::new_page(HostPhysAddr::from_usize(0x1000), MappingFlags::READ, false);
+        desc.set_mem_attr(MappingFlags::READ, write_back, true);
+        assert_eq!(desc.0 & DescriptorAttr::ATTR_INDEX_MASK, 0b0110 << 2);
+        assert_eq!(desc.mem_attr(true), write_back);
+    }
+}
@@ -14,6 +14,10 @@
 
 //! Architecture dependent structures.
 
+use page_table_entry::{GenericPTE, MappingFlags};
+
+use crate::MemAttr;
+
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
         mod x86_64;
@@ -23,6 +27,45 @@ cfg_if::cfg_if! {
         pub use self::aarch64::*;
     } else if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
         mod riscv;
+        mod riscv_context;
         pub use self::riscv::*;
+        pub use self::riscv_context::*;
+    }
+}
+
+// The AArch64 stage-2 encodings are also tested on other hosts.
+#[cfg(all(test, not(target_arch = "aarch64")))]
+#[allow(dead_code)]
+mod aarch64;
+#[cfg(all(test, not(any(target_arch = "riscv32", target_arch = "riscv64"))))]
+#[allow(dead_code)]
+mod riscv_context;
+
+/// Nested page table entries with an accessed flag.
+///
+/// The flag is set by the processor on accesses to the page if hardware
+/// management is enabled. Otherwise, an access to a page with the flag clear
+/// faults, and the fault handler sets it.
+pub trait AccessedFlag {
+    /// Whether the page has been accessed since the flag was cleared.
+    fn is_accessed(&self) -> bool;
+    /// Sets or clears the accessed flag.
+    fn set_accessed(&mut self, accessed: bool);
+}
+
+/// Nested page table entries with memory attributes beyond the mapping flags.
+///
+/// Entries created from [`MappingFlags`] alone have the default attributes,
+/// encoded without FEAT_S2FWB. `fwb` selects the FEAT_S2FWB encoding of the
+/// AArch64 stage-2 `MemAttr` field, and is ignored by other architectures.
+pub trait MemAttrPTE: GenericPTE {
+    /// Sets the memory attributes of a leaf entry mapped with `flags`.
+    fn set_mem_attr(&mut self, flags: MappingFlags, attr: MemAttr, fwb: bool);
+    /// Returns the memory attributes of a leaf entry, with the explicit memory
+    /// type closest to the encoded one.
+    fn mem_attr(&self, fwb: bool) -> MemAttr;
+    /// Returns the mapping flags of a leaf entry, like [`GenericPTE::flags`].
+    fn flags_with(&self, _fwb: bool) -> MappingFlags {
+        self.flags()
     }
 }
@@ -12,5 +12,138 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-pub use page_table_entry::riscv::Rv64PTE;
+use core::fmt;
+
+use page_table_entry::riscv::Rv64PTE;
+use page_table_entry::{GenericPTE, MappingFlags};
 pub use page_table_multiarch::riscv::{Sv39MetaData, Sv48MetaData};
+
+use crate::{HostPhysAddr, MemAttr, MemoryType};
+
+/// The accessed flag of [`Rv64PTE`].
+const PTE_ACCESSED: u64 = 1 << 6;
+
+/// The Svpbmt page-based memory type field.
+const PTE_PBMT_SHIFT: u64 = 61;
+const PTE_PBMT_MASK: u64 = 0b11 << PTE_PBMT_SHIFT;
+/// Main memory attributes of the physical memory.
+const PBMT_PMA: u64 = 0;
+/// Non-cacheable, idempotent, weakly-ordered main memory.
+const PBMT_NC: u64 = 1;
+/// Non-cacheable, non-idempotent, strongly-ordered I/O memory.
+const PBMT_IO: u64 = 2;
+
+/// A G-stage page table entry, which is an [`Rv64PTE`] with the Svpbmt memory
+/// type.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct Rv64GPTE(u64);
+
+impl Rv64GPTE {
+    fn from_pte(pte: Rv64PTE, pbmt: u64) -> Self {
+        Self(pte.bits() as u64 | (pbmt << PTE_PBMT_SHIFT))
+    }
+
+    fn pte(&self) -> Rv64PTE {
+        // SAFETY: `Rv64PTE` is a transparent wrapper of `u64`.
+        unsafe { core::mem::transmute::<u64, Rv64PTE>(self.0 & !PTE_PBMT_MASK) }
+    }
+
+    fn pbmt(&self) -> u64 {
+        (self.0 & PTE_PBMT_MASK) >> PTE_PBMT_SHIFT
+    }
+
+    fn pbmt_of(attr: MemAttr) -> u64 {
+        match attr.mem_type {
+            None | Some(MemoryType::WriteBack) => PBMT_PMA,
+            Some(MemoryType::WriteThrough | MemoryType::WriteCombining) => PBMT_NC,
+            Some(MemoryType::Uncached | MemoryType::DeviceNGnRnE | MemoryType::DeviceNGnRE) => {
+                PBMT_IO
+            }
+        }
+    }
+}
+
+impl super::AccessedFlag for Rv64GPTE {
+    fn is_accessed(&self) -> bool {
+        self.0 & PTE_ACCESSED != 0
+    }
+
+    fn set_accessed(&mut self, accessed: bool) {
+        if accessed {
+            self.0 |= PTE_ACCESSED;
+        } else {
+            self.0 &= !PTE_ACCESSED;
+        }
+    }
+}
+
+impl super::MemAttrPTE for Rv64GPTE {
+    fn set_mem_attr(&mut self, _flags: MappingFlags, attr: MemAttr, _fwb: bool) {
+        *self = Self::from_pte(self.pte(), Self::pbmt_of(attr));
+    }
+
+    fn mem_attr(&self, _fwb: bool) -> MemAttr {
+        MemAttr::new(match self.pbmt() {
+            PBMT_PMA => MemoryType::WriteBack,
+            PBMT_NC => MemoryType::WriteCombining,
+            // I/O and the reserved encoding.
+            _ => MemoryType::DeviceNGnRnE,
+        })
+    }
+}
+
+impl GenericPTE for Rv64GPTE {
+    fn new_page(paddr: HostPhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        Self::from_pte(Rv64PTE::new_page(paddr, flags, is_huge), PBMT_PMA)
+    }
+    fn new_table(paddr: HostPhysAddr) -> Self {
+        Self::from_pte(Rv64PTE::new_table(paddr), PBMT_PMA)
+    }
+    fn paddr(&self) -> HostPhysAddr {
+        self.pte().paddr()
+    }
+    fn flags(&self) -> MappingFlags {
+        self.pte().flags()
+    }
+    fn set_paddr(&mut self, paddr: HostPhysAddr) {
+        let mut pte = self.pte();
+        pte.set_paddr(paddr);
+        *self = Self::from_pte(pte, self.pbmt());
+    }
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        // Keep the accessed flag and the memory type, changing permissions is
+        // neither an access nor a change of the memory type.
+        let accessed = self.0 & PTE_ACCESSED;
+        let mut pte = self.pte();
+        pte.set_flags(flags, is_huge);
+        *self = Self::from_pte(pte, self.pbmt());
+        self.0 = (self.0 & !PTE_ACCESSED) | accessed;
+    }
+    fn bits(self) -> usize {
+        self.0 as usize
+    }
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        self.pte().is_present()
+    }
+    fn is_huge(&self) -> bool {
+        self.pte().is_huge()
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+}
+
+impl fmt::Debug for Rv64GPTE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rv64GPTE")
+            .field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .field("pbmt", &self.pbmt())
+            .finish()
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stage-2 translation context and root table of RISC-V G-stage page tables.
+//!
+//! They do not depend on the RISC-V page table entries, and are also tested on
+//! other hosts.
+
+use core::marker::PhantomData;
+
+use memory_addr::PAGE_SIZE_4K;
+use page_table_multiarch::PagingHandler;
+
+use crate::HostPhysAddr;
+
+/// The size and the alignment of the root table of Sv39x4 and Sv48x4.
+pub(crate) const ROOT_ALIGN: usize = 0x4000;
+
+/// The root table of a G-stage page table, the one `hgatp` points to.
+///
+/// The root of Sv39x4 and Sv48x4 spans four 4K frames, and translates 2 more
+/// bits than the root of the page table, a single frame translating 39 or 48
+/// bits. The first 512 entries are copies of the entries of that root, see
+/// [`store`](Self::store) and [`load`](Self::load), and the others stay zero,
+/// so that guest accesses above the translated bits fault.
+pub(crate) struct GStageRoot<H: PagingHandler> {
+    paddr: HostPhysAddr,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: PagingHandler> GStageRoot<H> {
+    /// Allocates a zeroed root table, or returns `None` if out of memory.
+    pub(crate) fn new() -> Option<Self> {
+        let paddr = H::alloc_frames(ROOT_ALIGN / PAGE_SIZE_4K, ROOT_ALIGN)?;
+        unsafe { core::ptr::write_bytes(H::phys_to_virt(paddr).as_mut_ptr(), 0, ROOT_ALIGN) };
+        Some(Self {
+            paddr,
+            _phantom: PhantomData,
+        })
+    }
+
+    pub(crate) const fn paddr(&self) -> HostPhysAddr {
+        self.paddr
+    }
+
+    /// Copies the entries of the page table root at `table_root` into the
+    /// first entries of the root table, after the page table is changed.
+    pub(crate) fn store(&self, table_root: HostPhysAddr) {
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                H::phys_to_virt(table_root).as_ptr(),
+                H::phys_to_virt(self.paddr).as_mut_ptr(),
+                PAGE_SIZE_4K,
+            )
+        };
+    }
+
+    /// Copies the first entries of the root table back into the page table
+    /// root at `table_root`, before the page table is used, so that it sees
+    /// the accessed flags set by the hardware.
+    pub(crate) fn load(&self, table_root: HostPhysAddr) {
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                H::phys_to_virt(self.paddr).as_ptr(),
+                H::phys_to_virt(table_root).as_mut_ptr(),
+                PAGE_SIZE_4K,
+            )
+        };
+    }
+}
+
+impl<H: PagingHandler> Drop for GStageRoot<H> {
+    fn drop(&mut self) {
+        H::dealloc_frames(self.paddr, ROOT_ALIGN / PAGE_SIZE_4K);
+    }
+}
+
+/// Stage-2 translation context of a G-stage page table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stage2Context {
+    /// `hgatp`, with the Sv39x4 or Sv48x4 mode of the table, the VMID and the
+    /// PPN of the 16 KiB root table.
+    pub hgatp: u64,
+}
+
+impl Stage2Context {
+    pub(crate) fn new(root_paddr: HostPhysAddr, levels: usize, vmid: u16) -> Self {
+        const HGATP_MODE_SHIFT: u64 = 60;
+        const HGATP_MODE_SV39X4: u64 = 8;
+        const HGATP_MODE_SV48X4: u64 = 9;
+        const HGATP_VMID_SHIFT: u64 = 44;
+        const HGATP_VMID_MASK: u64 = 0x3fff;
+        let mode = match levels {
+            3 => HGATP_MODE_SV39X4,
+            _ => HGATP_MODE_SV48X4,
+        };
+        Self {
+            hgatp: (mode << HGATP_MODE_SHIFT)
+                | ((vmid as u64 & HGATP_VMID_MASK) << HGATP_VMID_SHIFT)
+                | (root_paddr.as_usize() as u64 >> 12),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        alloc::Layout,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use memory_addr::{MemoryAddr, PhysAddr, VirtAddr};
+
+    use super::*;
+
+    static FREED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Allocates frames from the heap, filled with garbage as frames freed by
+    /// other users would be.
+    struct TestHal;
+
+    impl PagingHandler for TestHal {
+        fn alloc_frames(num: usize, _align: usize) -> Option<PhysAddr> {
+            let layout = Layout::from_size_align(num * PAGE_SIZE_4K, ROOT_ALIGN).unwrap();
+            let ptr = unsafe { alloc::alloc::alloc(layout) };
+            unsafe { ptr.write_bytes(0xa5, layout.size()) };
+            Some(PhysAddr::from_usize(ptr as usize))
+        }
+
+        fn dealloc_frames(paddr: PhysAddr, num: usize) {
+            let layout = Layout::from_size_align(num * PAGE_SIZE_4K, ROOT_ALIGN).unwrap();
+            unsafe { alloc::alloc::dealloc(paddr.as_usize() as *mut u8, layout) };
+            FREED_FRAMES.fetch_add(num, Ordering::SeqCst);
+        }
+
+        fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+            VirtAddr::from_usize(paddr.as_usize())
+        }
+    }
+
+    /// Returns the Sv39x4 root entry the hardware reads for `gpa`.
+    fn sv39x4_root_entry(root: HostPhysAddr, gpa: usize) -> u64 {
+        let entries = root.as_usize() as *const u64;
+        unsafe { *entries.add((gpa >> 30) & 0x7ff) }
+    }
+
+    #[test]
+    fn test_root_above_translated_bits() {
+        let table_root = TestHal::alloc_frame().unwrap();
+        let table_entries = table_root.as_usize() as *mut u64;
+        for i in 0..512 {
+            unsafe { *table_entries.add(i) = (i as u64) << 10 | 1 };
+        }
+
+        let root = GStageRoot::<TestHal>::new().unwrap();
+        assert!(root.paddr().is_aligned(ROOT_ALIGN));
+        root.store(table_root);
+        assert_eq!(sv39x4_root_entry(root.paddr(), 0x4000_0000), 1 << 10 | 1);
+        assert_eq!(
+            sv39x4_root_entry(root.paddr(), (1 << 39) - 1),
+            511 << 10 | 1
+        );
+        // The entries above 39 bits are zero, not garbage.
+        for gpa in [1 << 39, (1 << 40) + 0x4000_0000, (1 << 41) - 1] {
+            assert_eq!(sv39x4_root_entry(root.paddr(), gpa), 0);
+        }
+
+        // Flags set in the root table are copied back.
+        unsafe { *(root.paddr().as_usize() as *mut u64) |= 1 << 6 };
+        root.load(table_root);
+        assert_eq!(unsafe { *table_entries }, 1 << 6 | 1);
+
+        FREED_FRAMES.store(0, Ordering::SeqCst);
+        drop(root);
+        assert_eq!(FREED_FRAMES.load(Ordering::SeqCst), 4);
+        TestHal::dealloc_frame(table_root);
+    }
+
+    #[test]
+    fn test_hgatp() {
+        let root = HostPhysAddr::from_usize(0x8020_4000);
+        assert_eq!(
+            Stage2Context::new(root, 3, 5).hgatp,
+            (8 << 60) | (5 << 44) | 0x80204
+        );
+        assert_eq!(
+            Stage2Context::new(root, 4, 0x3fff).hgatp,
+            (9 << 60) | (0x3fff << 44) | 0x80204
+        );
+        // VMIDs are at most 14 bits.
+        assert_eq!(
+            Stage2Context::new(root, 4, 0x4001).hgatp,
+            (9 << 60) | (1 << 44) | 0x80204
+        );
+    }
+}
@@ -18,7 +18,7 @@ use bit_field::BitField;
 use page_table_entry::{GenericPTE, MappingFlags};
 use page_table_multiarch::{PageTable64, PagingMetaData};
 
-use crate::{GuestPhysAddr, HostPhysAddr};
+use crate::{GuestPhysAddr, HostPhysAddr, MemAttr, MemoryType};
 
 bitflags::bitflags! {
     /// EPT entry flags. (SDM Vol. 3C, Section 28.3.2)
@@ -69,6 +69,19 @@ impl EPTFlags {
     }
 }
 
+impl From<MemoryType> for EPTMemType {
+    fn from(mem_type: MemoryType) -> Self {
+        match mem_type {
+            MemoryType::WriteBack => Self::WriteBack,
+            MemoryType::WriteThrough => Self::WriteThrough,
+            MemoryType::WriteCombining => Self::WriteCombining,
+            MemoryType::Uncached | MemoryType::DeviceNGnRnE | MemoryType::DeviceNGnRE => {
+                Self::Uncached
+            }
+        }
+    }
+}
+
 impl From<MappingFlags> for EPTFlags {
     fn from(f: MappingFlags) -> Self {
         if f.is_empty() {
@@ -84,9 +97,7 @@ impl From<MappingFlags> for EPTFlags {
         if f.contains(MappingFlags::EXECUTE) {
             ret |= Self::EXECUTE;
         }
-        if !f.contains(MappingFlags::DEVICE) {
-            ret.set_mem_type(EPTMemType::WriteBack);
-        }
+        ret.set_mem_type(MemoryType::of_flags(f).into());
         ret
     }
 }
@@ -118,6 +129,55 @@ pub struct EPTEntry(u64);
 
 impl EPTEntry {
     const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // bits 12..52
+
+    /// Whether the page has been written since the dirty flag was cleared.
+    ///
+    /// The flag is only updated by the processor if accessed and dirty flags
+    /// are enabled in the EPTP.
+    pub fn is_dirty(&self) -> bool {
+        EPTFlags::from_bits_truncate(self.0).contains(EPTFlags::DIRTY)
+    }
+
+    /// Clears the dirty flag.
+    pub fn clear_dirty(&mut self) {
+        self.0 &= !EPTFlags::DIRTY.bits();
+    }
+}
+
+impl super::AccessedFlag for EPTEntry {
+    fn is_accessed(&self) -> bool {
+        EPTFlags::from_bits_truncate(self.0).contains(EPTFlags::ACCESSED)
+    }
+
+    fn set_accessed(&mut self, accessed: bool) {
+        if accessed {
+            self.0 |= EPTFlags::ACCESSED.bits();
+        } else {
+            self.0 &= !EPTFlags::ACCESSED.bits();
+        }
+    }
+}
+
+impl super::MemAttrPTE for EPTEntry {
+    fn set_mem_attr(&mut self, flags: MappingFlags, attr: MemAttr, _fwb: bool) {
+        let mut ept = EPTFlags::empty();
+        ept.set_mem_type(attr.mem_type.unwrap_or(MemoryType::of_flags(flags)).into());
+        ept.set(EPTFlags::IGNORE_PAT, attr.ignore_pat);
+        let mask = (EPTFlags::MEM_TYPE_MASK | EPTFlags::IGNORE_PAT).bits();
+        self.0 = (self.0 & !mask) | ept.bits();
+    }
+
+    fn mem_attr(&self, _fwb: bool) -> MemAttr {
+        let flags = EPTFlags::from_bits_truncate(self.0);
+        let mem_type = match flags.mem_type() {
+            Ok(EPTMemType::WriteBack) => MemoryType::WriteBack,
+            Ok(EPTMemType::WriteThrough) => MemoryType::WriteThrough,
+            Ok(EPTMemType::WriteCombining) => MemoryType::WriteCombining,
+            // Uncacheable, write-protected and reserved types.
+            _ => MemoryType::Uncached,
+        };
+        MemAttr::new(mem_type).with_ignore_pat(flags.contains(EPTFlags::IGNORE_PAT))
+    }
 }
 
 impl GenericPTE for EPTEntry {
@@ -147,7 +207,10 @@ impl GenericPTE for EPTEntry {
         if is_huge {
             flags |= EPTFlags::HUGE_PAGE;
         }
-        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | flags.bits()
+        // Keep the accessed and dirty flags, changing permissions is neither
+        // an access nor a write.
+        let kept = EPTFlags::ACCESSED | EPTFlags::DIRTY;
+        self.0 = (self.0 & (Self::PHYS_ADDR_MASK | kept.bits())) | flags.bits()
     }
     fn is_unused(&self) -> bool {
         self.0 == 0
@@ -188,18 +251,60 @@ impl PagingMetaData for ExtendedPageTableMetadata {
 
     type VirtAddr = GuestPhysAddr;
 
-    // Under the x86 architecture, flushing the TLB requires privileged
-    // instructions. Hosted binaries such as integration tests run in ring 3,
-    // so issue TLB invalidations only for bare-metal targets.
-    #[allow(unused_variables)]
-    fn flush_tlb(vaddr: Option<GuestPhysAddr>) {
-        #[cfg(target_os = "none")]
-        {
-            if let Some(vaddr) = vaddr {
-                unsafe { x86::tlb::flush(vaddr.into()) }
-            } else {
-                unsafe { x86::tlb::flush_all() }
-            }
+    // INVLPG does not invalidate mappings derived from EPT, which are tagged
+    // with the EPTP of the table instead. `NestedPageTable` invalidates them
+    // with `invept` after the cursor changes the entries.
+    fn flush_tlb(_vaddr: Option<GuestPhysAddr>) {}
+}
+
+/// INVEPT invalidation types. (SDM Vol. 3C, Section 29.4.3.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum InvEptType {
+    /// Invalidates the mappings associated with the given EPTP.
+    SingleContext = 1,
+    /// Invalidates the mappings associated with all EPTPs.
+    AllContext = 2,
+}
+
+/// Invalidates mappings derived from EPT with INVEPT.
+///
+/// `eptp` is ignored for [`AllContext`](InvEptType::AllContext) invalidation.
+///
+/// # Safety
+///
+/// The processor must be in VMX root operation, and support the INVEPT type.
+pub unsafe fn invept(ty: InvEptType, eptp: u64) {
+    let descriptor: [u64; 2] = [eptp, 0];
+    unsafe {
+        core::arch::asm!(
+            "invept {0}, [{1}]",
+            in(reg) ty as u64,
+            in(reg) &descriptor,
+            options(nostack),
+        );
+    }
+}
+
+/// Stage-2 translation context of an extended page table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stage2Context {
+    /// The EPT pointer, with a write-back paging structure memory type, the
+    /// page-walk length of the table and the accessed and dirty flags enabled.
+    /// (SDM Vol. 3C, Section 25.6.11)
+    pub eptp: u64,
+}
+
+impl Stage2Context {
+    pub(crate) fn new(root_paddr: HostPhysAddr, levels: usize, _vmid: u16) -> Self {
+        const EPTP_MEM_TYPE_WB: u64 = 6;
+        const EPTP_PAGE_WALK_LENGTH_SHIFT: u64 = 3;
+        const EPTP_AD_ENABLE: u64 = 1 << 6;
+        Self {
+            eptp: root_paddr.as_usize() as u64
+                | EPTP_MEM_TYPE_WB
+                | ((levels as u64 - 1) << EPTP_PAGE_WALK_LENGTH_SHIFT)
+                | EPTP_AD_ENABLE,
         }
     }
 }
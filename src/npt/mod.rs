@@ -12,23 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use axerrno::{ax_err, ax_err_type};
-use memory_addr::PhysAddr;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PhysAddr};
 use memory_set::MappingError;
-use page_table_entry::MappingFlags;
-use page_table_multiarch::{PageSize, PagingHandler};
+use page_table_entry::{GenericPTE, MappingFlags};
+use page_table_multiarch::{PageSize, PageTable64, PagingHandler, PagingMetaData};
+use spin::Mutex;
 
-use crate::GuestPhysAddr;
+use crate::{GuestPhysAddr, GuestPhysAddrRange, MemAttr, NestedFlags};
 
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
         pub type NestedPageTableL4<H> = arch::ExtendedPageTable<H>;
     } else if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
         /// RISC-V Level 3 nested page table (Sv39, x4 not supported)
-        pub type NestedPageTableL3<H> = page_table_multiarch::PageTable64<arch::Sv39MetaData<GuestPhysAddr>, arch::Rv64PTE, H>;
+        pub type NestedPageTableL3<H> = page_table_multiarch::PageTable64<arch::Sv39MetaData<GuestPhysAddr>, arch::Rv64GPTE, H>;
 
         /// RISC-V Level 4 nested page table (Sv48, x4 not supported)
-        pub type NestedPageTableL4<H> = page_table_multiarch::PageTable64<arch::Sv48MetaData<GuestPhysAddr>, arch::Rv64PTE, H>;
+        pub type NestedPageTableL4<H> = page_table_multiarch::PageTable64<arch::Sv48MetaData<GuestPhysAddr>, arch::Rv64GPTE, H>;
     } else if #[cfg(target_arch = "aarch64")] {
         /// AArch64 Level 3 nested page table type alias.
         pub type NestedPageTableL3<H> = page_table_multiarch::PageTable64<arch::A64HVPagingMetaDataL3, arch::A64PTEHV, H>;
@@ -40,61 +44,191 @@ cfg_if::cfg_if! {
 
 mod arch;
 
-pub enum NestedPageTable<H: PagingHandler> {
+pub use arch::Stage2Context;
+
+use arch::{AccessedFlag, MemAttrPTE};
+#[cfg(target_arch = "x86_64")]
+pub use arch::{InvEptType, invept};
+
+/// Regions larger than this are flushed from the TLB entirely.
+const FLUSH_ALL_THRESHOLD: usize = 64 * PAGE_SIZE_4K;
+
+/// TLB flushes collected in a batch.
+#[derive(Clone, Copy)]
+enum PendingFlush {
+    None,
+    Range(GuestPhysAddrRange),
+    All,
+}
+
+impl PendingFlush {
+    fn add(self, range: Option<GuestPhysAddrRange>) -> Self {
+        match (self, range) {
+            (Self::All, _) | (_, None) => Self::All,
+            (Self::None, Some(range)) => Self::Range(range),
+            (Self::Range(old), Some(range)) => Self::Range(GuestPhysAddrRange::new(
+                old.start.min(range.start),
+                old.end.max(range.end),
+            )),
+        }
+    }
+}
+
+enum Table<H: PagingHandler> {
     #[cfg(not(target_arch = "x86_64"))]
     L3(NestedPageTableL3<H>),
     L4(NestedPageTableL4<H>),
 }
 
+pub struct NestedPageTable<H: PagingHandler> {
+    table: Table<H>,
+    vmid: u16,
+    batch_depth: usize,
+    /// Flushes deferred to the end of the batch, behind a lock so that
+    /// flushes can be requested through a shared reference.
+    pending: Mutex<PendingFlush>,
+    /// Number of TLB flushes issued.
+    flushes: AtomicUsize,
+    /// The root table `hgatp` points to (RISC-V only).
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    root: arch::GStageRoot<H>,
+    /// Swap slots of the pages swapped out, keyed by their addresses.
+    swapped: BTreeMap<GuestPhysAddr, usize>,
+    /// Whether memory attributes are encoded for FEAT_S2FWB (AArch64 only).
+    fwb: bool,
+}
+
 impl<H: PagingHandler> NestedPageTable<H> {
     pub fn new(level: usize) -> axerrno::AxResult<Self> {
-        match level {
+        Self::new_with_fwb(level, false)
+    }
+
+    /// Creates a table whose stage-2 memory attributes use the FEAT_S2FWB
+    /// encoding if `fwb`, where stage 2 can force the write-back memory type.
+    ///
+    /// `fwb` must match `HCR_EL2.FWB` when the table is in use. It is ignored
+    /// by architectures other than AArch64.
+    pub fn new_with_fwb(level: usize, fwb: bool) -> axerrno::AxResult<Self> {
+        let table = match level {
             3 => {
                 #[cfg(not(target_arch = "x86_64"))]
                 {
                     let res = NestedPageTableL3::try_new().map_err(|_| ax_err_type!(NoMemory))?;
-                    Ok(NestedPageTable::L3(res))
+                    Table::L3(res)
                 }
                 #[cfg(target_arch = "x86_64")]
                 {
-                    ax_err!(InvalidInput, "L3 not supported on x86_64")
+                    return ax_err!(InvalidInput, "L3 not supported on x86_64");
                 }
             }
             4 => {
                 let res = NestedPageTableL4::try_new().map_err(|_| ax_err_type!(NoMemory))?;
-                Ok(NestedPageTable::L4(res))
+                Table::L4(res)
             }
-            _ => ax_err!(InvalidInput, "Invalid page table level"),
+            _ => return ax_err!(InvalidInput, "Invalid page table level"),
+        };
+        Ok(Self {
+            table,
+            vmid: 0,
+            batch_depth: 0,
+            pending: Mutex::new(PendingFlush::None),
+            flushes: AtomicUsize::new(0),
+            #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+            root: arch::GStageRoot::new().ok_or_else(|| ax_err_type!(NoMemory))?,
+            swapped: BTreeMap::new(),
+            fwb,
+        })
+    }
+
+    /// Returns the VMID the TLB entries of the table are tagged with.
+    pub const fn vmid(&self) -> u16 {
+        self.vmid
+    }
+
+    /// Sets the VMID the TLB entries of the table are tagged with.
+    ///
+    /// It is used to invalidate only the stage-2 TLB entries of this table on
+    /// AArch64, and must match the VMID programmed in `VTTBR_EL2`.
+    pub fn set_vmid(&mut self, vmid: u16) {
+        self.vmid = vmid;
+    }
+
+    /// Returns the number of levels of the page table.
+    pub const fn level(&self) -> usize {
+        match &self.table {
+            #[cfg(not(target_arch = "x86_64"))]
+            Table::L3(_) => 3,
+            Table::L4(_) => 4,
         }
     }
 
+    /// Whether memory attributes are encoded for FEAT_S2FWB, see
+    /// [`new_with_fwb`](Self::new_with_fwb).
+    pub const fn fwb(&self) -> bool {
+        self.fwb
+    }
+
+    /// Returns the number of bits of the guest physical addresses translated
+    /// by the table, 9 bits per level above the 4K page offset.
+    ///
+    /// The root is a single table, so a 3-level AArch64 table translates 39
+    /// bits, not the 40 bits concatenated root tables would.
+    pub const fn addr_bits(&self) -> usize {
+        12 + 9 * self.level()
+    }
+
+    /// Returns the physical address of the root table the hardware walks.
     pub const fn root_paddr(&self) -> PhysAddr {
-        match self {
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        {
+            self.root.paddr()
+        }
+        #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+        {
+            self.table_root()
+        }
+    }
+
+    /// Returns the physical address of the root of the page table, which only
+    /// differs from [`root_paddr`](Self::root_paddr) on RISC-V.
+    const fn table_root(&self) -> PhysAddr {
+        match &self.table {
             #[cfg(not(target_arch = "x86_64"))]
-            NestedPageTable::L3(pt) => pt.root_paddr(),
-            NestedPageTable::L4(pt) => pt.root_paddr(),
+            Table::L3(pt) => pt.root_paddr(),
+            Table::L4(pt) => pt.root_paddr(),
         }
     }
 
+    /// Runs `f` on the page table, which may change it.
+    ///
+    /// On RISC-V, the root table the hardware walks is a copy of the root of
+    /// the page table, see [`GStageRoot`](arch::GStageRoot), and is kept in
+    /// sync around `f`.
+    fn with_table<T>(&mut self, f: impl FnOnce(&mut Table<H>) -> T) -> T {
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        self.root.load(self.table_root());
+        let ret = f(&mut self.table);
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        self.root.store(self.table_root());
+        ret
+    }
+
     /// Maps a virtual address to a physical address.
     pub fn map(
         &mut self,
         vaddr: crate::GuestPhysAddr,
         paddr: PhysAddr,
         size: PageSize,
-        flags: page_table_entry::MappingFlags,
+        flags: impl Into<NestedFlags>,
     ) -> memory_set::MappingResult {
-        match self {
+        let flags = flags.into();
+        self.with_table(|table| match table {
             #[cfg(not(target_arch = "x86_64"))]
-            NestedPageTable::L3(pt) => pt
-                .cursor()
-                .map(vaddr, paddr, size, flags)
-                .map_err(|_| MappingError::BadState)?,
-            NestedPageTable::L4(pt) => pt
-                .cursor()
-                .map(vaddr, paddr, size, flags)
-                .map_err(|_| MappingError::BadState)?,
-        }
+            Table::L3(pt) => pt.cursor().map(vaddr, paddr, size, flags.flags),
+            Table::L4(pt) => pt.cursor().map(vaddr, paddr, size, flags.flags),
+        })
+        .map_err(|_| MappingError::BadState)?;
+        self.apply_mem_attr(vaddr, size as usize, flags);
         Ok(())
     }
 
@@ -103,11 +237,20 @@ impl<H: PagingHandler> NestedPageTable<H> {
         &mut self,
         vaddr: GuestPhysAddr,
     ) -> memory_set::MappingResult<(PhysAddr, MappingFlags, PageSize)> {
-        match self {
-            #[cfg(not(target_arch = "x86_64"))]
-            NestedPageTable::L3(pt) => pt.cursor().unmap(vaddr).map_err(|_| MappingError::BadState),
-            NestedPageTable::L4(pt) => pt.cursor().unmap(vaddr).map_err(|_| MappingError::BadState),
-        }
+        // The cursor decodes the memory attributes without FEAT_S2FWB.
+        let fwb_flags = self
+            .fwb
+            .then(|| self.query(vaddr).ok().map(|(_, flags, _)| flags))
+            .flatten();
+        let (paddr, flags, page_size) = self
+            .with_table(|table| match table {
+                #[cfg(not(target_arch = "x86_64"))]
+                Table::L3(pt) => pt.cursor().unmap(vaddr),
+                Table::L4(pt) => pt.cursor().unmap(vaddr),
+            })
+            .map_err(|_| MappingError::BadState)?;
+        self.flush_changed(vaddr, PAGE_SIZE_4K);
+        Ok((paddr, fwb_flags.unwrap_or(flags), page_size))
     }
 
     /// Maps a region.
@@ -116,82 +259,636 @@ impl<H: PagingHandler> NestedPageTable<H> {
         vaddr: GuestPhysAddr,
         get_paddr: impl Fn(GuestPhysAddr) -> PhysAddr,
         size: usize,
-        flags: MappingFlags,
+        flags: impl Into<NestedFlags>,
         allow_huge: bool,
     ) -> memory_set::MappingResult {
-        match self {
+        let flags = flags.into();
+        self.with_table(|table| match table {
             #[cfg(not(target_arch = "x86_64"))]
-            NestedPageTable::L3(pt) => pt
-                .cursor()
-                .map_region(vaddr, get_paddr, size, flags, allow_huge)
-                .map_err(|_| MappingError::BadState)?,
-            NestedPageTable::L4(pt) => pt
-                .cursor()
-                .map_region(vaddr, get_paddr, size, flags, allow_huge)
-                .map_err(|_| MappingError::BadState)?,
-        }
+            Table::L3(pt) => {
+                pt.cursor()
+                    .map_region(vaddr, get_paddr, size, flags.flags, allow_huge)
+            }
+            Table::L4(pt) => {
+                pt.cursor()
+                    .map_region(vaddr, get_paddr, size, flags.flags, allow_huge)
+            }
+        })
+        .map_err(|_| MappingError::BadState)?;
+        self.apply_mem_attr(vaddr, size, flags);
         Ok(())
     }
 
     /// Unmaps a region.
     pub fn unmap_region(&mut self, start: GuestPhysAddr, size: usize) -> memory_set::MappingResult {
-        match self {
+        self.with_table(|table| match table {
             #[cfg(not(target_arch = "x86_64"))]
-            NestedPageTable::L3(pt) => pt
-                .cursor()
-                .unmap_region(start, size)
-                .map_err(|_| MappingError::BadState)?,
-            NestedPageTable::L4(pt) => pt
-                .cursor()
-                .unmap_region(start, size)
-                .map_err(|_| MappingError::BadState)?,
-        }
+            Table::L3(pt) => pt.cursor().unmap_region(start, size),
+            Table::L4(pt) => pt.cursor().unmap_region(start, size),
+        })
+        .map_err(|_| MappingError::BadState)?;
+        self.flush_changed(start, size);
         Ok(())
     }
 
-    pub fn remap(&mut self, start: GuestPhysAddr, paddr: PhysAddr, flags: MappingFlags) -> bool {
-        match self {
+    pub fn remap(
+        &mut self,
+        start: GuestPhysAddr,
+        paddr: PhysAddr,
+        flags: impl Into<NestedFlags>,
+    ) -> bool {
+        let flags = flags.into();
+        let ok = self.with_table(|table| match table {
+            #[cfg(not(target_arch = "x86_64"))]
+            Table::L3(pt) => pt.cursor().remap(start, paddr, flags.flags).is_ok(),
+            Table::L4(pt) => pt.cursor().remap(start, paddr, flags.flags).is_ok(),
+        });
+        if ok {
+            self.apply_mem_attr(start, PAGE_SIZE_4K, flags);
+            self.flush_changed(start, PAGE_SIZE_4K);
+        }
+        ok
+    }
+
+    /// Maps the 4K page at `vaddr` to `paddr`.
+    ///
+    /// Unlike [`remap`](Self::remap), the page does not need to have an entry
+    /// already, missing page tables are allocated. An existing empty entry,
+    /// such as the one of an on-demand mapping, is replaced.
+    pub fn map_or_remap_4k(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        paddr: PhysAddr,
+        flags: impl Into<NestedFlags>,
+    ) -> bool {
+        let flags = flags.into();
+        let has_4k_entry = match &mut self.table {
+            #[cfg(not(target_arch = "x86_64"))]
+            Table::L3(pt) => {
+                matches!(leaf_entry(pt, vaddr), Some((_, PageSize::Size4K)))
+            }
+            Table::L4(pt) => {
+                matches!(leaf_entry(pt, vaddr), Some((_, PageSize::Size4K)))
+            }
+        };
+        if has_4k_entry {
+            self.remap(vaddr, paddr, flags)
+        } else {
+            self.map(vaddr.align_down_4k(), paddr, PageSize::Size4K, flags)
+                .is_ok()
+        }
+    }
+
+    /// Returns whether a huge page of `page_size` can be mapped at the block
+    /// containing `vaddr`, i.e., the entry of the block and the entries above
+    /// it are either present tables or unused.
+    pub fn is_block_unused(&mut self, vaddr: GuestPhysAddr, page_size: PageSize) -> bool {
+        match &mut self.table {
             #[cfg(not(target_arch = "x86_64"))]
-            NestedPageTable::L3(pt) => pt.cursor().remap(start, paddr, flags).is_ok(),
-            NestedPageTable::L4(pt) => pt.cursor().remap(start, paddr, flags).is_ok(),
+            Table::L3(pt) => is_block_unused_of(pt, vaddr, page_size),
+            Table::L4(pt) => is_block_unused_of(pt, vaddr, page_size),
         }
     }
 
+    /// Remaps the huge page containing `vaddr` with 4K pages, keeping the
+    /// physical frames, the mapping flags and the memory attributes.
+    ///
+    /// Does nothing if `vaddr` is not mapped by a huge page.
+    pub fn split_huge_page(&mut self, vaddr: GuestPhysAddr) -> bool {
+        let Ok((_, _, page_size)) = self.query(vaddr) else {
+            return true;
+        };
+        if !page_size.is_huge() {
+            return true;
+        }
+        let start = vaddr.align_down(page_size);
+        let Ok((_, flags, _)) = self.query_nested(start) else {
+            return false;
+        };
+        let Ok((paddr, ..)) = self.unmap(start) else {
+            return false;
+        };
+        self.map_region(
+            start,
+            |va| paddr + (va - start),
+            page_size as usize,
+            flags,
+            false,
+        )
+        .is_ok()
+    }
+
     /// Updates protection flags for a region.
     pub fn protect_region(
         &mut self,
         start: GuestPhysAddr,
         size: usize,
-        new_flags: page_table_entry::MappingFlags,
+        new_flags: impl Into<NestedFlags>,
     ) -> bool {
-        match self {
+        let new_flags = new_flags.into();
+        let ok = self.with_table(|table| match table {
             #[cfg(not(target_arch = "x86_64"))]
-            NestedPageTable::L3(pt) => pt
+            Table::L3(pt) => pt
                 .cursor()
-                .protect_region(start, size, new_flags) // If the TLB is refreshed immediately every time, there might be performance issues.
+                .protect_region(start, size, new_flags.flags) // If the TLB is refreshed immediately every time, there might be performance issues.
                 .is_ok(),
-            NestedPageTable::L4(pt) => pt
+            Table::L4(pt) => pt
                 .cursor()
-                .protect_region(start, size, new_flags) // If the TLB is refreshed immediately every time, there might be performance issues.
+                .protect_region(start, size, new_flags.flags) // If the TLB is refreshed immediately every time, there might be performance issues.
                 .is_ok(),
+        });
+        self.apply_mem_attr(start, size, new_flags);
+        self.flush_changed(start, size);
+        ok
+    }
+
+    /// Updates protection flags for a region without flushing the TLB.
+    ///
+    /// Unlike [`protect_region`](Self::protect_region), the caller is
+    /// responsible for flushing the TLB after a batch of updates. Returns
+    /// `false` if the region covers part of a huge page.
+    pub fn protect_region_deferred(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        new_flags: impl Into<NestedFlags>,
+    ) -> bool {
+        let new_flags = new_flags.into();
+        let fwb = self.fwb;
+        self.with_table(|table| match table {
+            #[cfg(not(target_arch = "x86_64"))]
+            Table::L3(pt) => protect_region_of(pt, start, size, new_flags, fwb),
+            Table::L4(pt) => protect_region_of(pt, start, size, new_flags, fwb),
+        })
+    }
+
+    /// Returns the memory attributes of the page mapping `vaddr`, or `None` if
+    /// the page is not mapped.
+    ///
+    /// The attributes are read back from the entry, with the explicit memory
+    /// type the architecture encodes (see [`MemAttrPTE::mem_attr`]).
+    pub fn mem_attr(&self, vaddr: GuestPhysAddr) -> Option<MemAttr> {
+        match &self.table {
+            #[cfg(not(target_arch = "x86_64"))]
+            Table::L3(pt) => mem_attr_of(pt, vaddr, self.fwb),
+            Table::L4(pt) => mem_attr_of(pt, vaddr, self.fwb),
         }
     }
 
+    /// Writes the memory attributes into the present leaf entries of a region
+    /// just mapped or protected with `flags`, as entries are created from the
+    /// mapping flags alone, without FEAT_S2FWB.
+    fn apply_mem_attr(&mut self, start: GuestPhysAddr, size: usize, flags: NestedFlags) {
+        if flags.mem_attr.is_default() && !self.fwb {
+            return;
+        }
+        let fwb = self.fwb;
+        self.with_table(|table| match table {
+            #[cfg(not(target_arch = "x86_64"))]
+            Table::L3(pt) => set_mem_attr_of(pt, start, size, flags, fwb),
+            Table::L4(pt) => set_mem_attr_of(pt, start, size, flags, fwb),
+        })
+    }
+
     /// Queries a virtual address to get physical address and mapping info.
     pub fn query(
         &self,
         vaddr: crate::GuestPhysAddr,
     ) -> page_table_multiarch::PagingResult<(PhysAddr, page_table_entry::MappingFlags, PageSize)>
     {
-        match self {
+        match &self.table {
             #[cfg(not(target_arch = "x86_64"))]
-            NestedPageTable::L3(pt) => pt.query(vaddr),
-            NestedPageTable::L4(pt) => pt.query(vaddr),
+            Table::L3(pt) => query_of(pt, vaddr, self.fwb),
+            Table::L4(pt) => query_of(pt, vaddr, self.fwb),
         }
     }
 
+    /// Like [`query`](Self::query), but also reads back the memory attributes
+    /// of the mapping (see [`mem_attr`](Self::mem_attr)).
+    pub fn query_nested(
+        &self,
+        vaddr: GuestPhysAddr,
+    ) -> page_table_multiarch::PagingResult<(PhysAddr, NestedFlags, PageSize)> {
+        let (paddr, flags, page_size) = self.query(vaddr)?;
+        let mem_attr = self.mem_attr(vaddr).unwrap_or_default();
+        Ok((paddr, NestedFlags::new(flags, mem_attr), page_size))
+    }
+
     /// Translates a virtual address to a physical address.
     pub fn translate(&self, vaddr: crate::GuestPhysAddr) -> Option<crate::HostPhysAddr> {
         self.query(vaddr).ok().map(|(paddr, _, _)| paddr)
     }
+
+    /// Returns the stage-2 translation context of the table, the values of
+    /// the hardware registers pointing to it.
+    pub fn stage2_context(&self) -> Stage2Context {
+        Stage2Context::new(self.root_paddr(), self.level(), self.vmid)
+    }
+
+    /// Returns the EPT pointer of the table.
+    #[cfg(target_arch = "x86_64")]
+    pub fn eptp(&self) -> u64 {
+        self.stage2_context().eptp
+    }
+
+    /// Flushes the TLB entries of the given address, or all entries if `vaddr`
+    /// is `None`.
+    ///
+    /// Only the entries of this table are flushed: the entries tagged with the
+    /// [VMID](Self::vmid) on AArch64, and with the [EPTP](Self::eptp) on x86,
+    /// where all entries of the table are flushed. Within a
+    /// [batch](Self::begin_batch), the flush is deferred to its end.
+    pub fn flush_tlb(&self, vaddr: Option<GuestPhysAddr>) {
+        self.request_flush(
+            vaddr.map(|vaddr| GuestPhysAddrRange::from_start_size(vaddr, PAGE_SIZE_4K)),
+        );
+    }
+
+    /// Returns the number of TLB flushes issued for this table so far. The
+    /// flushes collected in a batch count as one.
+    pub fn tlb_flushes(&self) -> usize {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    /// Starts a batch of changes. The TLB flushes requested until the matching
+    /// [`end_batch`](Self::end_batch) are collected and issued once.
+    ///
+    /// Batches can be nested.
+    pub fn begin_batch(&mut self) {
+        self.batch_depth += 1;
+    }
+
+    /// Ends a batch started by [`begin_batch`](Self::begin_batch), issuing the
+    /// collected TLB flushes if it is the outermost batch.
+    pub fn end_batch(&mut self) {
+        self.batch_depth -= 1;
+        if self.batch_depth == 0 {
+            match core::mem::replace(self.pending.get_mut(), PendingFlush::None) {
+                PendingFlush::None => {}
+                PendingFlush::Range(range) => self.issue_flush(Some(range)),
+                PendingFlush::All => self.issue_flush(None),
+            }
+        }
+    }
+
+    /// Requests a TLB flush of a region whose entries were changed by the page
+    /// table cursor.
+    ///
+    /// The cursor flushes the changed entries itself, except on x86 and on
+    /// AArch64 with `arm-el2`, where the entries are tagged with the EPTP or
+    /// the VMID of the table. On RISC-V, the root table the hardware walks is
+    /// only updated after the cursor, so it is flushed again.
+    fn flush_changed(&mut self, start: GuestPhysAddr, size: usize) {
+        if cfg!(any(
+            target_arch = "x86_64",
+            all(target_arch = "aarch64", feature = "arm-el2"),
+            target_arch = "riscv32",
+            target_arch = "riscv64"
+        )) {
+            self.request_flush(Some(GuestPhysAddrRange::from_start_size(start, size)));
+        }
+    }
+
+    fn request_flush(&self, range: Option<GuestPhysAddrRange>) {
+        if self.batch_depth > 0 {
+            let mut pending = self.pending.lock();
+            *pending = pending.add(range);
+        } else {
+            self.issue_flush(range);
+        }
+    }
+
+    /// Flushes the TLB entries of `range`, or all entries if `range` is
+    /// `None`. Large ranges are flushed entirely.
+    fn issue_flush(&self, range: Option<GuestPhysAddrRange>) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        let range = range.filter(|range| range.size() <= FLUSH_ALL_THRESHOLD);
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                // INVEPT requires VMX root operation. Hosted binaries such as
+                // integration tests run in ring 3, so issue it only for
+                // bare-metal targets.
+                let _ = range;
+                #[cfg(target_os = "none")]
+                unsafe {
+                    arch::invept(arch::InvEptType::SingleContext, self.eptp())
+                }
+            } else if #[cfg(all(target_arch = "aarch64", feature = "arm-el2"))] {
+                arch::flush_stage2_tlb(self.vmid, range);
+            } else {
+                match &self.table {
+                    Table::L3(pt) => flush_tlb_of(pt, range),
+                    Table::L4(pt) => flush_tlb_of(pt, range),
+                }
+            }
+        }
+    }
+
+    /// Tests and clears the accessed flag of the page mapping `vaddr`.
+    ///
+    /// Returns the previous flag and the size of the page, or `None` if the
+    /// page is not mapped. The TLB is not flushed, the caller should flush it
+    /// after a batch of calls so that later accesses set the flag again.
+    pub fn test_and_clear_accessed(&mut self, vaddr: GuestPhysAddr) -> Option<(bool, PageSize)> {
+        self.with_table(|table| match table {
+            #[cfg(not(target_arch = "x86_64"))]
+            Table::L3(pt) => test_and_clear_accessed_of(pt, vaddr),
+            Table::L4(pt) => test_and_clear_accessed_of(pt, vaddr),
+        })
+    }
+
+    /// Sets the accessed flag of the page mapping `vaddr`.
+    ///
+    /// Returns `true` if the page is mapped and the flag was clear.
+    pub fn set_accessed(&mut self, vaddr: GuestPhysAddr) -> bool {
+        self.with_table(|table| match table {
+            #[cfg(not(target_arch = "x86_64"))]
+            Table::L3(pt) => set_accessed_of(pt, vaddr),
+            Table::L4(pt) => set_accessed_of(pt, vaddr),
+        })
+    }
+
+    /// Returns the swap slot of the 4K page `vaddr`, or `None` if the page is
+    /// not swapped out.
+    pub fn swap_slot(&self, vaddr: GuestPhysAddr) -> Option<usize> {
+        self.swapped.get(&vaddr.align_down_4k()).copied()
+    }
+
+    /// Like [`swap_slot`](Self::swap_slot), but also forgets the slot, e.g.,
+    /// after the page is read back.
+    pub fn take_swap_slot(&mut self, vaddr: GuestPhysAddr) -> Option<usize> {
+        self.swapped.remove(&vaddr.align_down_4k())
+    }
+
+    /// Marks the 4K page `vaddr` as swapped out to `slot`.
+    ///
+    /// The page must not be mapped, and its last level table must exist. The
+    /// slot is kept aside from the entry, which stays empty.
+    pub fn set_swap_slot(&mut self, vaddr: GuestPhysAddr, slot: usize) -> bool {
+        let unmapped = match &mut self.table {
+            #[cfg(not(target_arch = "x86_64"))]
+            Table::L3(pt) => is_unmapped_4k_of(pt, vaddr),
+            Table::L4(pt) => is_unmapped_4k_of(pt, vaddr),
+        };
+        if unmapped {
+            self.swapped.insert(vaddr.align_down_4k(), slot);
+        }
+        unmapped
+    }
+
+    /// Tests and clears the hardware dirty flag of the page mapping `vaddr`.
+    ///
+    /// The TLB is not flushed, the caller should flush it after a batch of
+    /// calls so that later writes set the flag again.
+    #[cfg(target_arch = "x86_64")]
+    pub fn test_and_clear_dirty(&mut self, vaddr: GuestPhysAddr) -> bool {
+        match &mut self.table {
+            Table::L4(pt) => leaf_entry_mut(pt, vaddr)
+                .filter(|(entry, _)| entry.is_present())
+                .is_some_and(|(entry, _)| {
+                    let dirty = entry.is_dirty();
+                    entry.clear_dirty();
+                    dirty
+                }),
+        }
+    }
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    all(target_arch = "aarch64", feature = "arm-el2")
+)))]
+fn flush_tlb_of<M: PagingMetaData<VirtAddr = GuestPhysAddr>, PTE: GenericPTE, H: PagingHandler>(
+    _pt: &PageTable64<M, PTE, H>,
+    range: Option<GuestPhysAddrRange>,
+) {
+    match range {
+        Some(range) => {
+            let mut vaddr = range.start.align_down_4k();
+            while vaddr < range.end {
+                M::flush_tlb(Some(vaddr));
+                vaddr += PAGE_SIZE_4K;
+            }
+        }
+        None => M::flush_tlb(None),
+    }
+}
+
+fn protect_region_of<
+    M: PagingMetaData<VirtAddr = GuestPhysAddr>,
+    PTE: MemAttrPTE,
+    H: PagingHandler,
+>(
+    pt: &mut PageTable64<M, PTE, H>,
+    start: GuestPhysAddr,
+    size: usize,
+    new_flags: NestedFlags,
+    fwb: bool,
+) -> bool {
+    let end = start + size;
+    let mut vaddr = start;
+    while vaddr < end {
+        let page_size = match leaf_entry_mut(pt, vaddr) {
+            Some((entry, page_size)) => {
+                if !page_size.is_aligned(vaddr.as_usize()) || vaddr + page_size as usize > end {
+                    return false;
+                }
+                // Ignore if not present, as `PageTable64Cursor::protect_region` does.
+                if entry.is_present() {
+                    entry.set_flags(new_flags.flags, page_size.is_huge());
+                    if !new_flags.mem_attr.is_default() || fwb {
+                        entry.set_mem_attr(new_flags.flags, new_flags.mem_attr, fwb);
+                    }
+                }
+                page_size
+            }
+            None => PageSize::Size4K,
+        };
+        vaddr += page_size as usize;
+    }
+    true
+}
+
+fn set_mem_attr_of<
+    M: PagingMetaData<VirtAddr = GuestPhysAddr>,
+    PTE: MemAttrPTE,
+    H: PagingHandler,
+>(
+    pt: &mut PageTable64<M, PTE, H>,
+    start: GuestPhysAddr,
+    size: usize,
+    flags: NestedFlags,
+    fwb: bool,
+) {
+    let end = start + size;
+    let mut vaddr = start;
+    while vaddr < end {
+        let page_size = match leaf_entry_mut(pt, vaddr) {
+            Some((entry, page_size)) => {
+                if entry.is_present() {
+                    entry.set_mem_attr(flags.flags, flags.mem_attr, fwb);
+                }
+                page_size
+            }
+            None => PageSize::Size4K,
+        };
+        vaddr = vaddr.align_down(page_size) + page_size as usize;
+    }
+}
+
+fn mem_attr_of<M: PagingMetaData<VirtAddr = GuestPhysAddr>, PTE: MemAttrPTE, H: PagingHandler>(
+    pt: &PageTable64<M, PTE, H>,
+    vaddr: GuestPhysAddr,
+    fwb: bool,
+) -> Option<MemAttr> {
+    let (entry, _) = leaf_entry(pt, vaddr)?;
+    entry.is_present().then(|| entry.mem_attr(fwb))
+}
+
+fn query_of<M: PagingMetaData<VirtAddr = GuestPhysAddr>, PTE: MemAttrPTE, H: PagingHandler>(
+    pt: &PageTable64<M, PTE, H>,
+    vaddr: GuestPhysAddr,
+    fwb: bool,
+) -> page_table_multiarch::PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+    let (paddr, flags, page_size) = pt.query(vaddr)?;
+    if !fwb {
+        return Ok((paddr, flags, page_size));
+    }
+    // The table decodes the memory attributes without FEAT_S2FWB.
+    let (entry, _) = leaf_entry(pt, vaddr).unwrap();
+    Ok((paddr, entry.flags_with(fwb), page_size))
+}
+
+fn is_block_unused_of<
+    M: PagingMetaData<VirtAddr = GuestPhysAddr>,
+    PTE: GenericPTE,
+    H: PagingHandler,
+>(
+    pt: &mut PageTable64<M, PTE, H>,
+    vaddr: GuestPhysAddr,
+    page_size: PageSize,
+) -> bool {
+    let mut table_paddr = pt.root_paddr();
+    for level in (0..M::LEVELS).rev() {
+        let entry = unsafe { &*entry_ptr::<PTE, H>(table_paddr, vaddr, level) };
+        if entry.is_unused() {
+            return true;
+        }
+        if !entry.is_present() || entry.is_huge() || page_size as usize >= 1 << (12 + 9 * level) {
+            return false;
+        }
+        table_paddr = entry.paddr();
+    }
+    false
+}
+
+/// Returns a pointer to the entry of `vaddr` in the table at `table_paddr`,
+/// where `level` is 0 for the last level table.
+fn entry_ptr<PTE: GenericPTE, H: PagingHandler>(
+    table_paddr: PhysAddr,
+    vaddr: GuestPhysAddr,
+    level: usize,
+) -> *mut PTE {
+    const ENTRY_COUNT: usize = 512;
+    let index = (vaddr.as_usize() >> (12 + 9 * level)) % ENTRY_COUNT;
+    unsafe {
+        H::phys_to_virt(table_paddr)
+            .as_mut_ptr()
+            .cast::<PTE>()
+            .add(index)
+    }
+}
+
+fn test_and_clear_accessed_of<
+    M: PagingMetaData<VirtAddr = GuestPhysAddr>,
+    PTE: GenericPTE + AccessedFlag,
+    H: PagingHandler,
+>(
+    pt: &mut PageTable64<M, PTE, H>,
+    vaddr: GuestPhysAddr,
+) -> Option<(bool, PageSize)> {
+    let (entry, page_size) = leaf_entry_mut(pt, vaddr).filter(|(entry, _)| entry.is_present())?;
+    let accessed = entry.is_accessed();
+    entry.set_accessed(false);
+    Some((accessed, page_size))
+}
+
+fn set_accessed_of<
+    M: PagingMetaData<VirtAddr = GuestPhysAddr>,
+    PTE: GenericPTE + AccessedFlag,
+    H: PagingHandler,
+>(
+    pt: &mut PageTable64<M, PTE, H>,
+    vaddr: GuestPhysAddr,
+) -> bool {
+    match leaf_entry_mut(pt, vaddr) {
+        Some((entry, _)) if entry.is_present() && !entry.is_accessed() => {
+            entry.set_accessed(true);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn is_unmapped_4k_of<
+    M: PagingMetaData<VirtAddr = GuestPhysAddr>,
+    PTE: GenericPTE,
+    H: PagingHandler,
+>(
+    pt: &mut PageTable64<M, PTE, H>,
+    vaddr: GuestPhysAddr,
+) -> bool {
+    matches!(
+        leaf_entry(pt, vaddr),
+        Some((entry, PageSize::Size4K)) if !entry.is_present()
+    )
+}
+
+/// Returns the leaf entry that maps `vaddr` and the size of the page it maps.
+///
+/// Returns `None` if an intermediate table is not present.
+fn leaf_entry<M: PagingMetaData<VirtAddr = GuestPhysAddr>, PTE: GenericPTE, H: PagingHandler>(
+    pt: &PageTable64<M, PTE, H>,
+    vaddr: GuestPhysAddr,
+) -> Option<(&PTE, PageSize)> {
+    leaf_entry_ptr::<M, PTE, H>(pt.root_paddr(), vaddr)
+        .map(|(entry, page_size)| (unsafe { &*entry }, page_size))
+}
+
+/// Like [`leaf_entry`], but the entry can be changed.
+fn leaf_entry_mut<
+    M: PagingMetaData<VirtAddr = GuestPhysAddr>,
+    PTE: GenericPTE,
+    H: PagingHandler,
+>(
+    pt: &mut PageTable64<M, PTE, H>,
+    vaddr: GuestPhysAddr,
+) -> Option<(&mut PTE, PageSize)> {
+    leaf_entry_ptr::<M, PTE, H>(pt.root_paddr(), vaddr)
+        .map(|(entry, page_size)| (unsafe { &mut *entry }, page_size))
+}
+
+/// Walks the table at `root_paddr`, and returns a pointer to the leaf entry
+/// that maps `vaddr`.
+fn leaf_entry_ptr<
+    M: PagingMetaData<VirtAddr = GuestPhysAddr>,
+    PTE: GenericPTE,
+    H: PagingHandler,
+>(
+    root_paddr: PhysAddr,
+    vaddr: GuestPhysAddr,
+) -> Option<(*mut PTE, PageSize)> {
+    let mut table_paddr = root_paddr;
+    for level in (0..M::LEVELS).rev() {
+        let ptr = entry_ptr::<PTE, H>(table_paddr, vaddr, level);
+        let entry = unsafe { &*ptr };
+        match level {
+            0 => return Some((ptr, PageSize::Size4K)),
+            _ if !entry.is_present() => return None,
+            1 if entry.is_huge() => return Some((ptr, PageSize::Size2M)),
+            2 if entry.is_huge() => return Some((ptr, PageSize::Size1G)),
+            _ => table_paddr = entry.paddr(),
+        }
+    }
+    None
 }
@@ -14,12 +14,20 @@
 
 mod test_utils;
 
-use axaddrspace::{AddrSpace, GuestPhysAddr, MappingFlags};
+use axaddrspace::{
+    AddrSpace, BackendKind, E820_ACPI, E820_RAM, E820_RESERVED, E820Entry, GuestPhysAddr,
+    GuestPhysAddrRange, MappingFlags, MemAttr, MemoryRegionKind, MemorySwapStore, MemoryType,
+    MergeStats, NumaPolicy, PageFaultOutcome, PageMerger, SharedMemory,
+};
+use axerrno::AxError;
 use axin::axin;
 use core::sync::atomic::Ordering;
-use memory_addr::PhysAddr;
+use memory_addr::{MemoryAddr, PhysAddr};
+use page_table_multiarch::{PageSize, PagingHandler};
+use std::sync::Arc;
 use test_utils::{
-    ALLOC_COUNT, BASE_PADDR, DEALLOC_COUNT, MEMORY_LEN, MockHal, mock_hal_test, test_dealloc_count,
+    ALLOC_COUNT, BASE_PADDR, DEALLOC_COUNT, MEMORY_LEN, MockHal, MockHugeHal, mock_hal_test,
+    test_dealloc_count,
 };
 
 /// Generate an address space for the test
@@ -43,6 +51,20 @@ fn test_addrspace_creation() {
     assert_eq!(ALLOC_COUNT.load(Ordering::SeqCst), 1);
 }
 
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_addrspace_beyond_page_table() {
+    let top = |bits: usize| GuestPhysAddr::from_usize(1 << bits);
+    assert!(AddrSpace::<MockHal>::new_empty(4, top(48) - 0x1000, 0x1000).is_ok());
+    assert!(AddrSpace::<MockHal>::new_empty(4, top(48) - 0x1000, 0x2000).is_err());
+    // A 3-level table translates 39 bits only.
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        assert!(AddrSpace::<MockHal>::new_empty(3, top(39) - 0x1000, 0x1000).is_ok());
+        assert!(AddrSpace::<MockHal>::new_empty(3, top(39), 0x1000).is_err());
+    }
+}
+
 #[test]
 #[axin(decorator(mock_hal_test))]
 fn test_contains_range() {
@@ -166,6 +188,79 @@ fn test_page_fault_handling() {
     assert!(paddr.is_some());
 }
 
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_huge_pages() {
+    const BASE: GuestPhysAddr = GuestPhysAddr::from_usize(0);
+    const SIZE: usize = 0x40_0000;
+    let mut addr_space = AddrSpace::<MockHugeHal>::new_empty(4, BASE, SIZE).unwrap();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    // A populated aligned block is backed by a 2M page.
+    addr_space.map_alloc(BASE, 0x20_0000, flags, true).unwrap();
+    let (block, _, page_size) = addr_space.page_table().query(BASE).unwrap();
+    assert_eq!(page_size, PageSize::Size2M);
+    assert!(block.is_aligned(PageSize::Size2M));
+
+    // So is a lazy one on the first fault.
+    let lazy = BASE + 0x20_0000;
+    addr_space.map_alloc(lazy, 0x20_0000, flags, false).unwrap();
+    assert!(addr_space.handle_page_fault(lazy + 0x3000, MappingFlags::WRITE));
+    assert_eq!(
+        addr_space.page_table().query(lazy).unwrap().2,
+        PageSize::Size2M
+    );
+
+    // Unmapping part of a huge page splits it in place, only a page table is
+    // allocated and only the unmapped frame is freed.
+    unsafe { *MockHugeHal::phys_to_virt(block + 0x5000).as_mut_ptr() = 0x42 };
+    let allocs = ALLOC_COUNT.load(Ordering::SeqCst);
+    let deallocs = DEALLOC_COUNT.load(Ordering::SeqCst);
+    addr_space.unmap(BASE + 0x4000, 0x1000).unwrap();
+    assert_eq!(ALLOC_COUNT.load(Ordering::SeqCst), allocs + 1);
+    assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), deallocs + 1);
+    assert!(addr_space.translate(BASE + 0x4000).is_none());
+    let (paddr, _, page_size) = addr_space.page_table().query(BASE + 0x5000).unwrap();
+    assert_eq!((paddr, page_size), (block + 0x5000, PageSize::Size4K));
+    assert_eq!(unsafe { *MockHugeHal::phys_to_virt(paddr).as_ptr() }, 0x42);
+
+    // The remaining frames are freed when unmapped.
+    addr_space.unmap(BASE, SIZE).unwrap();
+    assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), deallocs + 1024);
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_huge_page_fault_fallback() {
+    const BASE: GuestPhysAddr = GuestPhysAddr::from_usize(0);
+    const SIZE: usize = 0x40_0000;
+    let mut addr_space = AddrSpace::<MockHal>::new_empty(4, BASE, SIZE).unwrap();
+    let vaddr = GuestPhysAddr::from_usize(0x20_0000);
+    let size = 0x20_0000;
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    // The aligned block is left unmapped for a huge page.
+    addr_space.map_alloc(vaddr, size, flags, false).unwrap();
+    assert_eq!(ALLOC_COUNT.load(Ordering::SeqCst), 1);
+
+    // The mock memory is too small for a 2M block, so a 4K frame is mapped.
+    assert!(addr_space.handle_page_fault(vaddr + 0x3000, MappingFlags::WRITE));
+    let (paddr, _, page_size) = addr_space.page_table().query(vaddr + 0x3000).unwrap();
+    assert_eq!(page_size, PageSize::Size4K);
+    assert!(paddr.as_usize() >= BASE_PADDR && paddr.as_usize() < BASE_PADDR + MEMORY_LEN);
+    assert!(addr_space.translate(vaddr).is_none());
+
+    // Other pages of the block fault in with 4K pages as well.
+    assert!(addr_space.handle_page_fault(vaddr, MappingFlags::READ));
+    assert_eq!(
+        addr_space.page_table().query(vaddr).unwrap().2,
+        PageSize::Size4K
+    );
+
+    addr_space.unmap(vaddr, size).unwrap();
+    test_dealloc_count(2);
+}
+
 #[test]
 #[axin(decorator(mock_hal_test))]
 fn test_unmap() {
@@ -335,3 +430,1556 @@ fn test_translate_and_get_limit() {
     let out_of_range = GuestPhysAddr::from_usize(0x30000);
     assert!(addr_space.translate_and_get_limit(out_of_range).is_none());
 }
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_fork_copy_on_write() {
+    let (mut parent, _base, _size) = setup_test_addr_space();
+    let vaddr = GuestPhysAddr::from_usize(0x1B000);
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    parent.map_alloc(vaddr, 0x1000, flags, true).unwrap();
+    let orig_paddr = parent.translate(vaddr).unwrap();
+    unsafe { *MockHal::mock_phys_to_virt(orig_paddr).as_mut_ptr() = 0x5a };
+
+    let mut child = parent.fork().unwrap();
+
+    // Both address spaces share the same frame read-only.
+    assert_eq!(child.translate(vaddr).unwrap(), orig_paddr);
+    assert!(
+        !parent
+            .page_table()
+            .query(vaddr)
+            .unwrap()
+            .1
+            .contains(MappingFlags::WRITE)
+    );
+
+    // A write fault in the child copies the frame privately.
+    assert!(child.handle_page_fault(vaddr, MappingFlags::WRITE));
+    let child_paddr = child.translate(vaddr).unwrap();
+    assert_ne!(child_paddr, orig_paddr);
+    assert_eq!(
+        unsafe { *MockHal::mock_phys_to_virt(child_paddr).as_ptr() },
+        0x5a
+    );
+
+    // The parent is now the only user, so the frame is reused writable.
+    assert!(parent.handle_page_fault(vaddr, MappingFlags::WRITE));
+    assert_eq!(parent.translate(vaddr).unwrap(), orig_paddr);
+
+    // Every frame is freed exactly once after both address spaces are gone.
+    drop(child);
+    let deallocs = DEALLOC_COUNT.load(Ordering::SeqCst);
+    drop(parent);
+    assert!(DEALLOC_COUNT.load(Ordering::SeqCst) > deallocs);
+    assert_eq!(
+        ALLOC_COUNT.load(Ordering::SeqCst),
+        DEALLOC_COUNT.load(Ordering::SeqCst)
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_fork_shared_frame_freed_by_last_user() {
+    let (mut parent, _base, _size) = setup_test_addr_space();
+    let vaddr = GuestPhysAddr::from_usize(0x1C000);
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    parent.map_alloc(vaddr, 0x1000, flags, true).unwrap();
+    let child = parent.fork().unwrap();
+
+    // Unmapping from the parent must not free the frame still used by the child.
+    let before = DEALLOC_COUNT.load(Ordering::SeqCst);
+    parent.unmap(vaddr, 0x1000).unwrap();
+    assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), before);
+    assert!(child.translate(vaddr).is_some());
+
+    drop(child);
+    drop(parent);
+    assert_eq!(
+        ALLOC_COUNT.load(Ordering::SeqCst),
+        DEALLOC_COUNT.load(Ordering::SeqCst)
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_fork_with_linear_and_mmio() {
+    let (mut parent, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    let linear_paddr = PhysAddr::from_usize(0x8000);
+
+    parent
+        .map_linear(base, linear_paddr, 0x2000, flags)
+        .unwrap();
+    parent.map_mmio(base + 0x2000, 0x1000, 7).unwrap();
+    parent
+        .map_alloc(base + 0x4000, 0x2000, flags, true)
+        .unwrap();
+    parent
+        .map_alloc(base + 0x8000, 0x2000, flags, false)
+        .unwrap();
+    let alloc_paddr = parent.translate(base + 0x4000).unwrap();
+
+    let mut child = parent.fork().unwrap();
+
+    let kinds = |space: &AddrSpace<MockHal>| -> Vec<_> {
+        space
+            .areas()
+            .map(|area| (area.range.start, area.kind))
+            .collect()
+    };
+    let expected = [
+        (
+            base,
+            BackendKind::Linear {
+                pa_va_offset: base.as_usize().wrapping_sub(linear_paddr.as_usize()),
+            },
+        ),
+        (base + 0x2000, BackendKind::Mmio { id: 7 }),
+        (base + 0x4000, BackendKind::Cow),
+        (base + 0x8000, BackendKind::Cow),
+    ];
+    assert_eq!(kinds(&parent), expected);
+    assert_eq!(kinds(&child), expected);
+
+    // Linear pages stay mapped in both, MMIO pages in neither.
+    for space in [&parent, &child] {
+        assert_eq!(space.translate(base + 0x1000), Some(linear_paddr + 0x1000));
+        assert_eq!(space.translate(base + 0x2000), None);
+        assert_eq!(space.translate(base + 0x4000), Some(alloc_paddr));
+    }
+    assert_eq!(
+        parent.handle_page_fault_ext(base + 0x2000, MappingFlags::READ),
+        PageFaultOutcome::Mmio { id: 7 }
+    );
+
+    // Both lazy and populated pages are still usable in the parent.
+    assert!(parent.handle_page_fault(base + 0x4000, MappingFlags::WRITE));
+    assert_ne!(
+        parent.translate(base + 0x4000),
+        child.translate(base + 0x4000)
+    );
+    assert!(child.handle_page_fault(base + 0x8000, MappingFlags::WRITE));
+    assert!(parent.translate(base + 0x8000).is_none());
+
+    drop(child);
+    drop(parent);
+    assert_eq!(
+        ALLOC_COUNT.load(Ordering::SeqCst),
+        DEALLOC_COUNT.load(Ordering::SeqCst)
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_map_shared() {
+    let (mut vm1, _base, _size) = setup_test_addr_space();
+    let (mut vm2, _base, _size) = setup_test_addr_space();
+    let shm = SharedMemory::<MockHal>::new(0x2000).unwrap();
+    let gpa1 = GuestPhysAddr::from_usize(0x12000);
+    let gpa2 = GuestPhysAddr::from_usize(0x1E000);
+
+    vm1.map_shared(gpa1, &shm, MappingFlags::READ | MappingFlags::WRITE)
+        .unwrap();
+    vm2.map_shared(gpa2, &shm, MappingFlags::READ).unwrap();
+    drop(shm);
+
+    // The same frames are visible at different addresses with different permissions.
+    assert_eq!(vm1.translate(gpa1 + 0x1000), vm2.translate(gpa2 + 0x1000));
+    let (_, flags, _) = vm2.page_table().query(gpa2).unwrap();
+    assert!(!flags.contains(MappingFlags::WRITE));
+
+    // Unmapping from one address space does not free the frames.
+    let before = DEALLOC_COUNT.load(Ordering::SeqCst);
+    vm1.unmap(gpa1, 0x2000).unwrap();
+    assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), before);
+    assert!(vm2.translate(gpa2).is_some());
+
+    // The frames are freed together with the last mapping.
+    vm2.unmap(gpa2, 0x2000).unwrap();
+    assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), before + 2);
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_dirty_log() {
+    const BASE: GuestPhysAddr = GuestPhysAddr::from_usize(0);
+    const SIZE: usize = 0x40_0000;
+    let mut addr_space = AddrSpace::<MockHal>::new_empty(4, BASE, SIZE).unwrap();
+    let vaddr = GuestPhysAddr::from_usize(0x20_0000);
+    let size = 0x20_0000;
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    addr_space
+        .map_linear(vaddr, PhysAddr::from_usize(0x20_0000), size, flags)
+        .unwrap();
+    assert_eq!(
+        addr_space.page_table().query(vaddr).unwrap().2,
+        PageSize::Size2M
+    );
+
+    // Nothing can be harvested before logging starts.
+    assert!(addr_space.get_and_clear_dirty_log(vaddr, size).is_err());
+
+    // Huge pages are split while logging is enabled.
+    addr_space.start_dirty_log(vaddr, size).unwrap();
+    assert!(addr_space.start_dirty_log(vaddr, size).is_err());
+    assert_eq!(
+        addr_space.page_table().query(vaddr).unwrap().2,
+        PageSize::Size4K
+    );
+
+    let bitmap = addr_space.get_and_clear_dirty_log(vaddr, size).unwrap();
+    assert_eq!(bitmap.len(), size / 0x1000 / 64);
+    assert!(bitmap.iter().all(|&word| word == 0));
+    assert!(
+        addr_space
+            .get_and_clear_dirty_log(vaddr - 0x1000, 0x2000)
+            .is_err()
+    );
+
+    addr_space.stop_dirty_log().unwrap();
+    assert!(addr_space.stop_dirty_log().is_err());
+    assert_eq!(
+        addr_space.translate(vaddr + 0x1234).unwrap(),
+        PhysAddr::from_usize(0x20_1234)
+    );
+}
+
+#[cfg(target_arch = "x86_64")]
+const EPT_ACCESSED: u64 = 1 << 8;
+#[cfg(target_arch = "x86_64")]
+const EPT_DIRTY: u64 = 1 << 9;
+
+/// Sets `flag` in the EPT entry mapping the 4K page `vaddr`, as the processor
+/// does on a guest access.
+#[cfg(target_arch = "x86_64")]
+fn set_ept_flag(addr_space: &AddrSpace<MockHal>, vaddr: GuestPhysAddr, flag: u64) {
+    const PADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+    let mut table = addr_space.page_table_root();
+    for level in (0..4).rev() {
+        let index = (vaddr.as_usize() >> (12 + level * 9)) & 0x1ff;
+        let entry = (MockHal::phys_to_virt(table).as_usize() as *mut u64).wrapping_add(index);
+        if level == 0 {
+            unsafe { *entry |= flag };
+        } else {
+            table = PhysAddr::from_usize((unsafe { *entry } & PADDR_MASK) as usize);
+        }
+    }
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_dirty_log_reports_writes() {
+    let (mut addr_space, base, size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    addr_space
+        .map_linear(base, PhysAddr::from_usize(base.as_usize()), size, flags)
+        .unwrap();
+    addr_space.start_dirty_log(base, size).unwrap();
+
+    // Write to the second and the last page.
+    for vaddr in [base + 0x1000, base + size - 0x1000] {
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                set_ept_flag(&addr_space, vaddr, EPT_DIRTY);
+            } else {
+                assert!(addr_space.handle_page_fault(vaddr, MappingFlags::WRITE));
+            }
+        }
+    }
+
+    // Written pages are reported once, then cleared.
+    let bitmap = addr_space.get_and_clear_dirty_log(base, size).unwrap();
+    assert_eq!(bitmap, [0b10 | 1 << (size / 0x1000 - 1)]);
+    let bitmap = addr_space.get_and_clear_dirty_log(base, size).unwrap();
+    assert_eq!(bitmap, [0]);
+
+    addr_space.stop_dirty_log().unwrap();
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_snapshot_restore() {
+    let (mut addr_space, base, size) = setup_test_addr_space();
+    let linear_vaddr = GuestPhysAddr::from_usize(0x10000);
+    let alloc_vaddr = GuestPhysAddr::from_usize(0x14000);
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    addr_space
+        .map_linear(linear_vaddr, PhysAddr::from_usize(0x8000), 0x2000, flags)
+        .unwrap();
+    addr_space
+        .map_alloc(alloc_vaddr, 0x3000, flags, false)
+        .unwrap();
+    // Populate the second page only.
+    assert!(addr_space.handle_page_fault(alloc_vaddr + 0x1000, MappingFlags::WRITE));
+    let paddr = addr_space.translate(alloc_vaddr + 0x1000).unwrap();
+    unsafe { *MockHal::mock_phys_to_virt(paddr).as_mut_ptr().add(0x10) = 0xa5 };
+
+    let mut snapshot = Vec::new();
+    addr_space.snapshot(&mut snapshot).unwrap();
+    drop(addr_space);
+
+    let restored = AddrSpace::<MockHal>::restore(&mut snapshot.as_slice()).unwrap();
+    assert_eq!(restored.base(), base);
+    assert_eq!(restored.size(), size);
+    assert_eq!(
+        restored.translate(linear_vaddr + 0x1000).unwrap(),
+        PhysAddr::from_usize(0x9000)
+    );
+    // Unpopulated pages stay lazy, populated pages keep their contents.
+    assert!(restored.translate(alloc_vaddr).is_none());
+    let paddr = restored.translate(alloc_vaddr + 0x1000).unwrap();
+    assert_eq!(
+        unsafe { *MockHal::mock_phys_to_virt(paddr).as_ptr().add(0x10) },
+        0xa5
+    );
+
+    // Corrupted or truncated streams are rejected.
+    let mut bad_magic = snapshot.clone();
+    bad_magic[0] = 0;
+    assert!(AddrSpace::<MockHal>::restore(&mut bad_magic.as_slice()).is_err());
+    let truncated = &snapshot[..snapshot.len() - 1];
+    assert!(AddrSpace::<MockHal>::restore(&mut &truncated[..]).is_err());
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_restore_rejects_bad_counts() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    addr_space.map_alloc(base, 0x2000, flags, true).unwrap();
+    let mut snapshot = Vec::new();
+    addr_space.snapshot(&mut snapshot).unwrap();
+    drop(addr_space);
+
+    // Counts larger than the address space can hold are rejected. The area
+    // count ends the 36-byte header, and the page count of the area follows
+    // 30 bytes of it.
+    for offset in [28, 36 + 30] {
+        let mut bad_count = snapshot.clone();
+        bad_count[offset..offset + 8].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert_eq!(
+            AddrSpace::<MockHal>::restore(&mut bad_count.as_slice()).err(),
+            Some(AxError::InvalidData)
+        );
+    }
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_restore_rejects_bad_rom_size() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    addr_space
+        .map_rom(base, &[0x5a; 0x1000], MappingFlags::READ)
+        .unwrap();
+    let mut snapshot = Vec::new();
+    addr_space.snapshot(&mut snapshot).unwrap();
+    drop(addr_space);
+
+    // The size of the area follows its start at the end of the 36-byte
+    // header. A ROM area larger than its data runs out of stream, and one not
+    // made of whole pages is rejected, with no frame left behind.
+    for (bad_size, err) in [
+        (0x8000, AxError::UnexpectedEof),
+        (0x1800, AxError::InvalidData),
+    ] {
+        let mut bad_rom = snapshot.clone();
+        bad_rom[44..52].copy_from_slice(&(bad_size as u64).to_le_bytes());
+        let allocs = ALLOC_COUNT.load(Ordering::SeqCst);
+        let deallocs = DEALLOC_COUNT.load(Ordering::SeqCst);
+        assert_eq!(
+            AddrSpace::<MockHal>::restore(&mut bad_rom.as_slice()).err(),
+            Some(err)
+        );
+        assert_eq!(
+            ALLOC_COUNT.load(Ordering::SeqCst) - allocs,
+            DEALLOC_COUNT.load(Ordering::SeqCst) - deallocs
+        );
+    }
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_snapshot_unsupported_state() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    addr_space.map_alloc(base, 0x2000, flags, true).unwrap();
+
+    // Private pages are rejected, until they are converted back.
+    addr_space.convert_to_private(base, 0x1000).unwrap();
+    assert_eq!(
+        addr_space.snapshot(&mut Vec::new()),
+        Err(AxError::Unsupported)
+    );
+    addr_space.convert_to_shared(base, 0x1000).unwrap();
+    addr_space.snapshot(&mut Vec::new()).unwrap();
+
+    addr_space
+        .map_hotplug(base + 0x4000, 0x4000, 0x1000, flags)
+        .unwrap();
+    assert_eq!(
+        addr_space.snapshot(&mut Vec::new()),
+        Err(AxError::Unsupported)
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_protect() {
+    let (mut addr_space, _base, _size) = setup_test_addr_space();
+    let vaddr = GuestPhysAddr::from_usize(0x10000);
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+
+    addr_space.map_alloc(vaddr, 0x3000, rw, true).unwrap();
+    addr_space
+        .map_alloc(vaddr + 0x3000, 0x1000, rw, false)
+        .unwrap();
+
+    // Invalid ranges are rejected.
+    assert!(
+        addr_space
+            .protect(vaddr + 0x800, 0x1000, MappingFlags::READ)
+            .is_err()
+    );
+    assert!(
+        addr_space
+            .protect(vaddr, 0x20000, MappingFlags::READ)
+            .is_err()
+    );
+    // Pages without permissions would lose track of their frames.
+    let frame = addr_space.translate(vaddr);
+    assert_eq!(
+        addr_space.protect(vaddr, 0x1000, MappingFlags::USER),
+        Err(AxError::InvalidInput)
+    );
+    assert_eq!(addr_space.translate(vaddr), frame);
+
+    // Protect the tail of the first area and the whole lazy area.
+    addr_space
+        .protect(vaddr + 0x1000, 0x3000, MappingFlags::READ)
+        .unwrap();
+    let flags_of =
+        |addr_space: &AddrSpace<MockHal>, addr| addr_space.page_table().query(addr).unwrap().1;
+    assert!(flags_of(&addr_space, vaddr).contains(MappingFlags::WRITE));
+    assert!(!flags_of(&addr_space, vaddr + 0x1000).contains(MappingFlags::WRITE));
+    assert!(!flags_of(&addr_space, vaddr + 0x2000).contains(MappingFlags::WRITE));
+
+    // The area was split at the range boundaries.
+    assert_eq!(addr_space.translate_and_get_limit(vaddr).unwrap().1, 0x1000);
+
+    // Lazy pages are faulted in with the new permissions.
+    assert!(!addr_space.handle_page_fault(vaddr + 0x3000, MappingFlags::WRITE));
+    assert!(addr_space.handle_page_fault(vaddr + 0x3000, MappingFlags::READ));
+    assert!(!flags_of(&addr_space, vaddr + 0x3000).contains(MappingFlags::WRITE));
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_protect_splits_huge_pages() {
+    let mut addr_space =
+        AddrSpace::<MockHal>::new_empty(4, GuestPhysAddr::from_usize(0), 0x40_0000).unwrap();
+    let vaddr = GuestPhysAddr::from_usize(0x20_0000);
+    let rwx = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE;
+
+    addr_space
+        .map_linear(vaddr, PhysAddr::from_usize(0x20_0000), 0x20_0000, rwx)
+        .unwrap();
+    addr_space
+        .protect(vaddr + 0x1000, 0x1000, MappingFlags::READ)
+        .unwrap();
+
+    let (paddr, flags, page_size) = addr_space.page_table().query(vaddr + 0x1000).unwrap();
+    assert_eq!(paddr, PhysAddr::from_usize(0x20_1000));
+    assert_eq!(page_size, PageSize::Size4K);
+    assert!(!flags.contains(MappingFlags::WRITE));
+    let (_, flags, _) = addr_space.page_table().query(vaddr).unwrap();
+    assert!(flags.contains(MappingFlags::WRITE));
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_balloon() {
+    let (mut addr_space, _base, _size) = setup_test_addr_space();
+    let vaddr = GuestPhysAddr::from_usize(0x10000);
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    addr_space.map_alloc(vaddr, 0x3000, flags, true).unwrap();
+    addr_space
+        .map_linear(vaddr + 0x4000, PhysAddr::from_usize(0x4000), 0x1000, flags)
+        .unwrap();
+
+    // Linear mappings and unaligned addresses cannot be ballooned.
+    assert!(addr_space.balloon_inflate(&[vaddr + 0x4000]).is_err());
+    assert!(addr_space.balloon_inflate(&[vaddr + 0x10]).is_err());
+    assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), 0);
+
+    addr_space
+        .balloon_inflate(&[vaddr, vaddr + 0x2000, vaddr + 0x2000])
+        .unwrap();
+    test_dealloc_count(2);
+    assert_eq!(addr_space.ballooned_pages(vaddr + 0x1000), 2);
+    assert!(addr_space.translate(vaddr).is_none());
+    assert!(addr_space.translate(vaddr + 0x1000).is_some());
+
+    // Deflated pages are faulted in again on access.
+    addr_space.balloon_deflate(&[vaddr]).unwrap();
+    assert_eq!(addr_space.ballooned_pages(vaddr), 1);
+    assert!(addr_space.handle_page_fault(vaddr, MappingFlags::WRITE));
+    assert!(addr_space.translate(vaddr).is_some());
+
+    // So are ballooned pages, which leave the balloon.
+    assert!(addr_space.handle_page_fault(vaddr + 0x2000, MappingFlags::READ));
+    assert_eq!(addr_space.ballooned_pages(vaddr), 0);
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_discard() {
+    let (mut addr_space, _base, _size) = setup_test_addr_space();
+    let vaddr = GuestPhysAddr::from_usize(0x10000);
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    addr_space.map_alloc(vaddr, 0x2000, flags, true).unwrap();
+    addr_space
+        .map_linear(vaddr + 0x2000, PhysAddr::from_usize(0x4000), 0x1000, flags)
+        .unwrap();
+    let paddr = addr_space.translate(vaddr).unwrap();
+    unsafe { *MockHal::mock_phys_to_virt(paddr).as_mut_ptr() = 0xaa };
+
+    // Nothing is discarded if the range covers a linear mapping.
+    assert!(addr_space.discard(vaddr, 0x3000).is_err());
+    assert_eq!(addr_space.translate(vaddr), Some(paddr));
+
+    addr_space.discard(vaddr, 0x2000).unwrap();
+    test_dealloc_count(2);
+    assert!(addr_space.translate(vaddr).is_none());
+    assert!(addr_space.translate(vaddr + 0x1000).is_none());
+
+    // The next access maps a new zeroed frame.
+    assert!(addr_space.handle_page_fault(vaddr, MappingFlags::READ));
+    let new_paddr = addr_space.translate(vaddr).unwrap();
+    assert_ne!(new_paddr, paddr);
+    assert_eq!(
+        unsafe { *MockHal::mock_phys_to_virt(new_paddr).as_ptr() },
+        0
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_mmio_region() {
+    let (mut addr_space, _base, _size) = setup_test_addr_space();
+    let vaddr = GuestPhysAddr::from_usize(0x10000);
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    addr_space.map_mmio(vaddr, 0x2000, 7).unwrap();
+    addr_space
+        .map_alloc(vaddr + 0x2000, 0x1000, flags, false)
+        .unwrap();
+    assert!(addr_space.map_mmio(vaddr + 0x1000, 0x2000, 8).is_err());
+
+    assert_eq!(
+        addr_space.handle_page_fault_ext(vaddr + 0x1008, MappingFlags::WRITE),
+        PageFaultOutcome::Mmio { id: 7 }
+    );
+    assert!(addr_space.translate(vaddr + 0x1008).is_none());
+    assert!(!addr_space.handle_page_fault(vaddr, MappingFlags::READ));
+
+    assert_eq!(
+        addr_space.handle_page_fault_ext(vaddr + 0x2000, MappingFlags::READ),
+        PageFaultOutcome::Handled
+    );
+    assert_eq!(
+        addr_space.handle_page_fault_ext(vaddr + 0x3000, MappingFlags::READ),
+        PageFaultOutcome::Unhandled
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_numa_placement() {
+    let (mut addr_space, _base, _size) = setup_test_addr_space();
+    let vaddr = GuestPhysAddr::from_usize(0x10000);
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    addr_space
+        .map_alloc_numa(vaddr, 0x1000, flags, true, NumaPolicy::Bind(1))
+        .unwrap();
+    assert_eq!(addr_space.gpa_to_node(vaddr), Some(1));
+
+    // Node 0 is exhausted now: a preferred node falls back to any node, and a
+    // bound node fails.
+    addr_space
+        .map_alloc_numa(
+            vaddr + 0x1000,
+            0x1000,
+            flags,
+            false,
+            NumaPolicy::Preferred(0),
+        )
+        .unwrap();
+    assert_eq!(addr_space.gpa_to_node(vaddr + 0x1000), None);
+    assert!(addr_space.handle_page_fault(vaddr + 0x1000, MappingFlags::READ));
+    assert_eq!(addr_space.gpa_to_node(vaddr + 0x1000), Some(1));
+
+    addr_space
+        .map_alloc_numa(vaddr + 0x2000, 0x1000, flags, false, NumaPolicy::Bind(0))
+        .unwrap();
+    assert!(!addr_space.handle_page_fault(vaddr + 0x2000, MappingFlags::READ));
+    assert!(
+        addr_space
+            .map_alloc_numa(vaddr + 0x3000, 0x1000, flags, true, NumaPolicy::Bind(0))
+            .is_err()
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_hotplug() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    assert!(addr_space.map_hotplug(base, 0x8000, 0x3000, flags).is_err());
+    addr_space.map_hotplug(base, 0x8000, 0x2000, flags).unwrap();
+    assert_eq!(addr_space.plugged_size(base), Some(0));
+    assert_eq!(addr_space.plugged_size(base + 0x8000), None);
+
+    // Unplugged blocks are not backed.
+    assert!(!addr_space.handle_page_fault(base, MappingFlags::READ));
+    addr_space.plug_blocks(base + 0x2000, 2).unwrap();
+    assert_eq!(addr_space.plugged_size(base), Some(0x4000));
+    assert!(addr_space.translate(base + 0x5000).is_some());
+    assert!(!addr_space.handle_page_fault(base + 0x6000, MappingFlags::READ));
+    assert!(addr_space.plug_blocks(base + 0x6000, 2).is_err());
+    assert!(addr_space.plug_blocks(base + 0x1000, 1).is_err());
+
+    // Only plugged blocks are described to the guest.
+    let range = |start: usize, end: usize| GuestPhysAddrRange::new(base + start, base + end);
+    assert_eq!(
+        addr_space.memory_regions(&[]).unwrap(),
+        [(range(0x2000, 0x6000), MemoryRegionKind::Ram)]
+    );
+    assert_eq!(
+        addr_space
+            .memory_regions(&[(range(0x4000, 0x8000), MemoryRegionKind::Reserved)])
+            .unwrap(),
+        [
+            (range(0x2000, 0x4000), MemoryRegionKind::Ram),
+            (range(0x4000, 0x6000), MemoryRegionKind::Reserved),
+        ]
+    );
+
+    // Nothing is plugged if the backing cannot be allocated.
+    MockHal::set_alloc_fail(true);
+    assert!(addr_space.plug_blocks(base, 1).is_err());
+    MockHal::set_alloc_fail(false);
+    assert_eq!(addr_space.plugged_size(base), Some(0x4000));
+
+    // Blocks with pinned pages cannot be unplugged.
+    addr_space.pin_pages(base + 0x3000, 0x1000).unwrap();
+    assert_eq!(
+        addr_space.unplug_blocks(base + 0x2000, 2),
+        Err(AxError::ResourceBusy)
+    );
+    addr_space.unplug_blocks(base + 0x4000, 1).unwrap();
+    addr_space.unpin_pages(base + 0x3000, 0x1000).unwrap();
+    let dealloc_count = DEALLOC_COUNT.load(Ordering::SeqCst);
+    addr_space.unplug_blocks(base + 0x2000, 2).unwrap();
+    assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), dealloc_count + 2);
+    assert_eq!(addr_space.plugged_size(base), Some(0));
+    assert!(addr_space.translate(base + 0x2000).is_none());
+    assert!(!addr_space.handle_page_fault(base + 0x2000, MappingFlags::READ));
+
+    // Hotplug regions are only removed as a whole.
+    assert_eq!(
+        addr_space.unmap(base + 0x2000, 0x2000),
+        Err(AxError::InvalidInput)
+    );
+    assert_eq!(addr_space.plugged_size(base), Some(0));
+    addr_space.unmap(base, 0x8000).unwrap();
+    assert_eq!(addr_space.plugged_size(base), None);
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_hotplug_blocks_not_mapped_by_huge_pages() {
+    const BASE: GuestPhysAddr = GuestPhysAddr::from_usize(0);
+    const SIZE: usize = 0x40_0000;
+    let mut addr_space = AddrSpace::<MockHugeHal>::new_empty(4, BASE, SIZE).unwrap();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    // Plugging a block of an aligned region maps only the block.
+    addr_space
+        .map_hotplug(BASE, 0x20_0000, 0x1000, flags)
+        .unwrap();
+    addr_space.plug_blocks(BASE + 0x1000, 1).unwrap();
+    let (_, _, page_size) = addr_space.page_table().query(BASE + 0x1000).unwrap();
+    assert_eq!(page_size, PageSize::Size4K);
+    assert!(addr_space.page_table().query(BASE).is_err());
+    assert!(addr_space.page_table().query(BASE + 0x2000).is_err());
+
+    // Blocks containing watched pages cannot be unplugged.
+    let id = addr_space
+        .add_watchpoint(BASE + 0x1000, 4, MappingFlags::WRITE)
+        .unwrap();
+    assert_eq!(
+        addr_space.unplug_blocks(BASE + 0x1000, 1),
+        Err(AxError::ResourceBusy)
+    );
+    assert_eq!(addr_space.plugged_size(BASE), Some(0x1000));
+    addr_space.remove_watchpoint(id).unwrap();
+    addr_space.unplug_blocks(BASE + 0x1000, 1).unwrap();
+    assert_eq!(addr_space.plugged_size(BASE), Some(0));
+    assert!(addr_space.page_table().query(BASE + 0x1000).is_err());
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_pinned_pages_are_kept() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    addr_space.map_alloc(base, 0x2000, flags, true).unwrap();
+    addr_space.pin_pages(base + 0x1000, 0x1000).unwrap();
+    let frame = addr_space.translate(base + 0x1000);
+
+    assert_eq!(
+        addr_space.balloon_inflate(&[base, base + 0x1000]),
+        Err(AxError::ResourceBusy)
+    );
+    assert_eq!(addr_space.ballooned_pages(base), 0);
+    assert_eq!(addr_space.discard(base, 0x2000), Err(AxError::ResourceBusy));
+    assert!(
+        addr_space
+            .fork()
+            .is_err_and(|err| err == AxError::ResourceBusy)
+    );
+    assert_eq!(addr_space.translate(base + 0x1000), frame);
+    assert!(addr_space.areas().all(|area| area.kind != BackendKind::Cow));
+
+    addr_space.unpin_pages(base + 0x1000, 0x1000).unwrap();
+    addr_space.balloon_inflate(&[base + 0x1000]).unwrap();
+    assert_eq!(addr_space.ballooned_pages(base), 1);
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_areas() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    addr_space
+        .map_linear(base, PhysAddr::from_usize(0x8000), 0x2000, flags)
+        .unwrap();
+    addr_space
+        .map_alloc(base + 0x4000, 0x3000, flags, false)
+        .unwrap();
+    addr_space.map_mmio(base + 0x8000, 0x1000, 3).unwrap();
+    assert!(addr_space.handle_page_fault(base + 0x5000, MappingFlags::READ));
+
+    let areas: Vec<_> = addr_space.areas().collect();
+    assert_eq!(areas.len(), 3);
+    assert_eq!(areas[0].range.start, base);
+    assert_eq!(areas[0].flags, flags);
+    assert_eq!(
+        areas[0].kind,
+        BackendKind::Linear {
+            pa_va_offset: 0x8000
+        }
+    );
+    assert_eq!(areas[0].populated, 2);
+    assert_eq!(areas[1].range.size(), 0x3000);
+    assert_eq!(areas[1].kind, BackendKind::Alloc { populate: false });
+    assert_eq!(areas[1].populated, 1);
+    assert_eq!(areas[2].kind, BackendKind::Mmio { id: 3 });
+    assert_eq!(areas[2].populated, 0);
+
+    let table = addr_space.to_string();
+    let lines: Vec<_> = table.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[2].starts_with("0x00000000014000-0x00000000017000 rw--- alloc(lazy)"));
+    assert!(lines[2].ends_with(" 4K"));
+    assert!(lines[3].contains("mmio(3)"));
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_firmware_memory_map() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    let range = |start: usize, end: usize| GuestPhysAddrRange::new(base + start, base + end);
+
+    addr_space
+        .map_linear(base, PhysAddr::from_usize(0x8000), 0x4000, flags)
+        .unwrap();
+    addr_space
+        .map_alloc(base + 0x4000, 0x2000, flags, false)
+        .unwrap();
+    addr_space.map_mmio(base + 0x8000, 0x1000, 0).unwrap();
+    // Passthrough device memory is not RAM either.
+    addr_space
+        .map_linear(
+            base + 0xa000,
+            PhysAddr::from_usize(0xc000),
+            0x1000,
+            flags | MappingFlags::DEVICE,
+        )
+        .unwrap();
+    let tags = [
+        (range(0x5000, 0x6000), MemoryRegionKind::Reserved),
+        (range(0x3000, 0x4000), MemoryRegionKind::Acpi),
+    ];
+
+    assert_eq!(
+        addr_space.memory_regions(&tags).unwrap(),
+        [
+            (range(0, 0x3000), MemoryRegionKind::Ram),
+            (range(0x3000, 0x4000), MemoryRegionKind::Acpi),
+            (range(0x4000, 0x5000), MemoryRegionKind::Ram),
+            (range(0x5000, 0x6000), MemoryRegionKind::Reserved),
+            (range(0x8000, 0x9000), MemoryRegionKind::Mmio),
+            (range(0xa000, 0xb000), MemoryRegionKind::Mmio),
+        ]
+    );
+
+    let e820 = addr_space.e820_table(&tags).unwrap();
+    let entry = |start: usize, size: u64, kind| E820Entry {
+        addr: (base + start).as_usize() as u64,
+        size,
+        kind,
+    };
+    assert_eq!(
+        e820,
+        [
+            entry(0, 0x3000, E820_RAM),
+            entry(0x3000, 0x1000, E820_ACPI),
+            entry(0x4000, 0x1000, E820_RAM),
+            entry(0x5000, 0x1000, E820_RESERVED),
+            entry(0x8000, 0x1000, E820_RESERVED),
+            entry(0xa000, 0x1000, E820_RESERVED),
+        ]
+    );
+
+    let nodes = addr_space.fdt_memory_nodes(&tags).unwrap();
+    assert_eq!(nodes.memory, [range(0, 0x6000)]);
+    assert_eq!(
+        nodes.reserved,
+        [range(0x3000, 0x4000), range(0x5000, 0x6000)]
+    );
+    assert_eq!(
+        nodes.memory_reg(),
+        [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0x60, 0]
+    );
+    let dts = nodes.to_string();
+    assert!(dts.contains("reg = <0x0 0x10000 0x0 0x6000>;"));
+    assert!(dts.contains("reserved@15000 {"));
+
+    // A tag may span adjacent areas, but not unmapped memory or other tags.
+    let tag = |start, end| [(range(start, end), MemoryRegionKind::Reserved)];
+    assert!(addr_space.memory_regions(&tag(0x3000, 0x5000)).is_ok());
+    assert!(addr_space.memory_regions(&tag(0x5000, 0x7000)).is_err());
+    assert!(addr_space.memory_regions(&tag(0x6000, 0x6000)).is_err());
+    let overlapping = [tag(0, 0x2000)[0], tag(0x1000, 0x3000)[0]];
+    assert!(addr_space.memory_regions(&overlapping).is_err());
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_swap() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    let store = Arc::new(MemorySwapStore::new(2));
+
+    addr_space
+        .map_alloc_swappable(base, 0x4000, flags, true, store.clone())
+        .unwrap();
+    addr_space
+        .map_alloc(base + 0x4000, 0x1000, flags, true)
+        .unwrap();
+    let page = |addr_space: &AddrSpace<MockHal>, gpa| {
+        let paddr = addr_space.translate(gpa).unwrap();
+        MockHal::mock_phys_to_virt(paddr).as_mut_ptr()
+    };
+    unsafe { page(&addr_space, base + 0x1000).write_bytes(0x5a, 0x1000) };
+
+    assert!(addr_space.swap_out(&[base + 0x4000]).is_err());
+    addr_space.pin_pages(base + 0x3000, 0x1000).unwrap();
+    assert_eq!(
+        addr_space.swap_out(&[base, base + 0x3000]),
+        Err(AxError::ResourceBusy)
+    );
+    assert!(addr_space.translate(base).is_some());
+
+    // Private pages of confidential guests are not swapped out.
+    addr_space.convert_to_private(base, 0x1000).unwrap();
+    assert_eq!(addr_space.swap_out(&[base]), Err(AxError::PermissionDenied));
+    addr_space.convert_to_shared(base, 0x1000).unwrap();
+
+    let dealloc_count = DEALLOC_COUNT.load(Ordering::SeqCst);
+    assert_eq!(
+        addr_space.swap_out(&[base + 0x1000, base + 0x2000, base + 0x1000]),
+        Ok(2)
+    );
+    assert_eq!(DEALLOC_COUNT.load(Ordering::SeqCst), dealloc_count + 2);
+    assert_eq!(store.used_slots(), 2);
+    assert!(addr_space.translate(base + 0x1000).is_none());
+    assert!(addr_space.translated_byte_buffer(base, 0x2000).is_none());
+    // Swapped out pages are recorded aside, their entries are left empty.
+    let pt = addr_space.page_table();
+    assert!(pt.swap_slot(base + 0x1000).is_some());
+    assert_ne!(pt.swap_slot(base + 0x1000), pt.swap_slot(base + 0x2000));
+    assert_eq!(pt.swap_slot(base), None);
+    // The store is full.
+    assert_eq!(addr_space.swap_out(&[base]), Err(AxError::StorageFull));
+    assert!(addr_space.translate(base).is_some());
+
+    // Swapped out pages are read back on access.
+    assert!(addr_space.handle_page_fault(base + 0x1234, MappingFlags::WRITE));
+    assert_eq!(store.used_slots(), 1);
+    assert_eq!(addr_space.page_table().swap_slot(base + 0x1000), None);
+    let restored = unsafe { core::slice::from_raw_parts(page(&addr_space, base + 0x1000), 0x1000) };
+    assert!(restored.iter().all(|&b| b == 0x5a));
+
+    // Unmapping frees the slots of swapped out pages.
+    addr_space.unmap(base, 0x4000).unwrap();
+    assert_eq!(store.used_slots(), 0);
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_page_merging() {
+    let (mut space_a, base, _size) = setup_test_addr_space();
+    let (mut space_b, _base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    let fill = |space: &AddrSpace<MockHal>, gpa: GuestPhysAddr, byte: u8| {
+        let paddr = space.translate(gpa).unwrap();
+        unsafe {
+            MockHal::mock_phys_to_virt(paddr)
+                .as_mut_ptr()
+                .write_bytes(byte, 0x1000)
+        };
+    };
+
+    space_a.map_alloc(base, 0x2000, flags, true).unwrap();
+    space_b.map_alloc(base, 0x3000, flags, true).unwrap();
+    fill(&space_a, base + 0x1000, 0x11);
+    fill(&space_b, base + 0x1000, 0x11);
+    fill(&space_b, base + 0x2000, 0x22);
+
+    let merger = PageMerger::new();
+    // Joining allocates and frees scratch page tables, count the net frames.
+    let freed_frames = || {
+        DEALLOC_COUNT.load(Ordering::SeqCst) as isize - ALLOC_COUNT.load(Ordering::SeqCst) as isize
+    };
+    let freed_before = freed_frames();
+    assert_eq!(
+        merger.merge(&mut [&mut space_a, &mut space_b]),
+        Ok(MergeStats {
+            merged_pages: 2,
+            saved_bytes: 0x2000,
+        })
+    );
+    assert_eq!(freed_frames(), freed_before + 2);
+    assert_eq!(merger.shared_frames(), 2);
+    for offset in [0, 0x1000] {
+        assert_eq!(
+            space_a.translate(base + offset),
+            space_b.translate(base + offset)
+        );
+    }
+    assert_ne!(
+        space_b.translate(base + 0x1000),
+        space_b.translate(base + 0x2000)
+    );
+    let merged = space_a.areas().next().unwrap();
+    assert_eq!(merged.kind, BackendKind::Cow);
+
+    // Nothing more to merge.
+    assert_eq!(
+        merger.merge(&mut [&mut space_a, &mut space_b]),
+        Ok(MergeStats::default())
+    );
+
+    // A write splits the merged page again.
+    assert!(space_b.handle_page_fault(base + 0x1000, MappingFlags::WRITE));
+    assert_ne!(
+        space_a.translate(base + 0x1000),
+        space_b.translate(base + 0x1000)
+    );
+    assert_eq!(merger.shared_frames(), 1);
+    let page = space_b.translate(base + 0x1000).unwrap();
+    assert_eq!(
+        unsafe { *MockHal::mock_phys_to_virt(page).as_ptr().add(0x123) },
+        0x11
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_page_merging_with_other_areas() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    let linear_paddr = PhysAddr::from_usize(0x8000);
+
+    addr_space
+        .map_linear(base, linear_paddr, 0x1000, flags)
+        .unwrap();
+    addr_space.map_mmio(base + 0x1000, 0x1000, 3).unwrap();
+    addr_space
+        .map_alloc(base + 0x2000, 0x1000, flags, true)
+        .unwrap();
+    addr_space
+        .map_alloc(base + 0x4000, 0x1000, flags, true)
+        .unwrap();
+    let ranges: Vec<_> = addr_space.areas().map(|area| area.range).collect();
+
+    let merger = PageMerger::new();
+    assert_eq!(
+        merger.merge(&mut [&mut addr_space]),
+        Ok(MergeStats {
+            merged_pages: 1,
+            saved_bytes: 0x1000,
+        })
+    );
+
+    let areas: Vec<_> = addr_space.areas().collect();
+    assert_eq!(
+        areas.iter().map(|area| area.range).collect::<Vec<_>>(),
+        ranges
+    );
+    assert_eq!(
+        areas.iter().map(|area| area.kind).collect::<Vec<_>>(),
+        [
+            BackendKind::Linear {
+                pa_va_offset: base.as_usize() - linear_paddr.as_usize(),
+            },
+            BackendKind::Mmio { id: 3 },
+            BackendKind::Cow,
+            BackendKind::Cow,
+        ]
+    );
+    assert_eq!(addr_space.translate(base), Some(linear_paddr));
+    assert_eq!(
+        addr_space.translate(base + 0x2000),
+        addr_space.translate(base + 0x4000)
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_private_pages() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    let page = base + 0x1000;
+
+    addr_space.map_alloc(base, 0x3000, flags, true).unwrap();
+    addr_space
+        .map_linear(base + 0x4000, PhysAddr::from_usize(0x8000), 0x1000, flags)
+        .unwrap();
+    let old_frame = addr_space.translate(page).unwrap();
+    unsafe {
+        MockHal::mock_phys_to_virt(old_frame)
+            .as_mut_ptr()
+            .write_bytes(0xaa, 0x1000)
+    };
+
+    assert!(
+        addr_space
+            .convert_to_private(base + 0x4000, 0x1000)
+            .is_err()
+    );
+    assert!(
+        addr_space
+            .convert_to_private(base + 0x3000, 0x1000)
+            .is_err()
+    );
+    // Nothing is converted if a page in the range cannot be.
+    addr_space.pin_pages(page + 0x1000, 0x1000).unwrap();
+    assert_eq!(
+        addr_space.convert_to_private(page, 0x2000),
+        Err(AxError::ResourceBusy)
+    );
+    assert!(!addr_space.is_private(page));
+    assert_eq!(addr_space.translate(page), Some(old_frame));
+    addr_space.unpin_pages(page + 0x1000, 0x1000).unwrap();
+
+    addr_space.convert_to_private(page, 0x1000).unwrap();
+    assert!(addr_space.is_private(page + 0x123));
+    assert!(!addr_space.is_private(base));
+
+    // The page is remapped to a zeroed frame.
+    let new_frame = addr_space.translate(page).unwrap();
+    assert_ne!(new_frame, old_frame);
+    let contents = unsafe {
+        core::slice::from_raw_parts(MockHal::mock_phys_to_virt(new_frame).as_ptr(), 0x1000)
+    };
+    assert!(contents.iter().all(|&b| b == 0));
+
+    // The host cannot access the private page.
+    assert!(addr_space.translate_and_get_limit(page).is_none());
+    assert_eq!(
+        addr_space.translate_and_get_limit(base + 0x800).unwrap().1,
+        0x800
+    );
+    assert!(addr_space.translated_byte_buffer(base, 0x2000).is_none());
+    assert!(addr_space.translated_byte_buffer(base, 0x1000).is_some());
+
+    // The private page is not merged with the zeroed pages of this and other
+    // address spaces.
+    let (mut other, _base, _size) = setup_test_addr_space();
+    other.map_alloc(page, 0x1000, flags, true).unwrap();
+    let merger = PageMerger::new();
+    assert_eq!(
+        merger
+            .merge(&mut [&mut addr_space, &mut other])
+            .unwrap()
+            .merged_pages,
+        2
+    );
+    assert_eq!(addr_space.translate(base), other.translate(page));
+    assert_ne!(addr_space.translate(page), other.translate(page));
+
+    addr_space.convert_to_shared(page, 0x1000).unwrap();
+    assert!(!addr_space.is_private(page));
+    assert!(addr_space.translated_byte_buffer(base, 0x2000).is_some());
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_rom_region() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let image: Vec<u8> = (0..0x1800).map(|i| i as u8).collect();
+    let read_byte = |addr_space: &AddrSpace<MockHal>, gpa| {
+        let paddr = addr_space.translate(gpa).unwrap();
+        unsafe { *MockHal::mock_phys_to_virt(paddr).as_ptr() }
+    };
+
+    addr_space
+        .map_rom(
+            base,
+            &image,
+            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+        )
+        .unwrap();
+    let (_, flags, _) = addr_space.page_table().query(base + 0x1000).unwrap();
+    assert!(!flags.contains(MappingFlags::WRITE));
+    assert_eq!(read_byte(&addr_space, base + 0x1234), 0x34);
+    assert_eq!(read_byte(&addr_space, base + 0x1800), 0);
+
+    assert_eq!(
+        addr_space.handle_page_fault_ext(base + 0x1008, MappingFlags::WRITE),
+        PageFaultOutcome::RomWrite {
+            addr: base + 0x1008
+        }
+    );
+    assert_eq!(
+        addr_space.handle_page_fault_ext(base + 0x1008, MappingFlags::READ),
+        PageFaultOutcome::Unhandled
+    );
+
+    // Write permission cannot be granted later.
+    addr_space
+        .protect(base, 0x2000, MappingFlags::READ | MappingFlags::WRITE)
+        .unwrap();
+    let (_, flags, _) = addr_space.page_table().query(base).unwrap();
+    assert_eq!(flags & MappingFlags::WRITE, MappingFlags::empty());
+    assert!(addr_space.discard(base, 0x1000).is_err());
+
+    let mut snapshot = Vec::new();
+    addr_space.snapshot(&mut snapshot).unwrap();
+    let restored = AddrSpace::<MockHal>::restore(&mut snapshot.as_slice()).unwrap();
+    assert_eq!(restored.areas().next().unwrap().kind, BackendKind::Rom);
+    assert_eq!(read_byte(&restored, base + 0x1234), 0x34);
+
+    // A ROM area larger than the address space is rejected before its
+    // contents are read. The size of the first area follows the 36-byte
+    // header and its start.
+    snapshot[44..52].copy_from_slice(&(1u64 << 40).to_le_bytes());
+    assert_eq!(
+        AddrSpace::<MockHal>::restore(&mut snapshot.as_slice()).err(),
+        Some(AxError::InvalidData)
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_watchpoints() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+    let page = base + 0x1000;
+    let page_flags = |addr_space: &AddrSpace<MockHal>| {
+        addr_space
+            .page_table()
+            .query(page)
+            .map(|(_, flags, _)| flags)
+    };
+    addr_space.map_alloc(base, 0x2000, rw, false).unwrap();
+    assert!(
+        addr_space
+            .add_watchpoint(base + 0x2000, 4, MappingFlags::WRITE)
+            .is_err()
+    );
+
+    let hits = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let hits2 = hits.clone();
+    addr_space.set_watchpoint_callback(move |hit| hits2.lock().unwrap().push(*hit));
+    let id = addr_space
+        .add_watchpoint(base + 0x1100, 8, MappingFlags::WRITE)
+        .unwrap();
+    let orig_flags = addr_space.areas().next().unwrap().flags;
+    assert_eq!(
+        page_flags(&addr_space).unwrap(),
+        orig_flags - MappingFlags::WRITE
+    );
+
+    // A write elsewhere in the page is let through for single-stepping.
+    assert_eq!(
+        addr_space.handle_page_fault_ext(base + 0x1800, MappingFlags::WRITE),
+        PageFaultOutcome::WatchStep {
+            addr: base + 0x1800
+        }
+    );
+    assert_eq!(page_flags(&addr_space).unwrap(), orig_flags);
+    addr_space.rearm_watchpoints();
+    assert!(hits.lock().unwrap().is_empty());
+
+    assert_eq!(
+        addr_space.handle_page_fault_ext(base + 0x1104, MappingFlags::WRITE),
+        PageFaultOutcome::Watchpoint {
+            id,
+            addr: base + 0x1104
+        }
+    );
+    addr_space.rearm_watchpoints();
+    assert_eq!(hits.lock().unwrap().len(), 1);
+    assert_eq!(hits.lock().unwrap()[0].addr, base + 0x1104);
+    assert_eq!(hits.lock().unwrap()[0].access, MappingFlags::WRITE);
+
+    // Watching reads leaves no permissions, the page is still translated.
+    let paddr = addr_space.translate(page).unwrap();
+    let read_id = addr_space
+        .add_watchpoint(base + 0x1ff0, 0x10, MappingFlags::READ)
+        .unwrap();
+    assert!(page_flags(&addr_space).is_err());
+    assert_eq!(addr_space.translate(page), Some(paddr));
+    let buffer = addr_space.translated_byte_buffer(page, 0x1000).unwrap();
+    assert_eq!(
+        buffer[0].as_ptr(),
+        MockHal::mock_phys_to_virt(paddr).as_ptr()
+    );
+    assert!(addr_space.discard(page, 0x1000).is_err());
+    assert_eq!(
+        addr_space.balloon_inflate(&[page]),
+        Err(AxError::ResourceBusy)
+    );
+    // A failed fork leaves the watchpoints armed.
+    assert!(
+        addr_space
+            .fork()
+            .is_err_and(|err| err == AxError::ResourceBusy)
+    );
+    assert!(page_flags(&addr_space).is_err());
+    assert_eq!(
+        addr_space.handle_page_fault_ext(base + 0x1ff8, MappingFlags::READ),
+        PageFaultOutcome::Watchpoint {
+            id: read_id,
+            addr: base + 0x1ff8
+        }
+    );
+    addr_space.rearm_watchpoints();
+
+    addr_space.remove_watchpoint(read_id).unwrap();
+    assert_eq!(
+        page_flags(&addr_space).unwrap(),
+        orig_flags - MappingFlags::WRITE
+    );
+    addr_space.remove_watchpoint(id).unwrap();
+    assert_eq!(page_flags(&addr_space).unwrap(), orig_flags);
+    assert_eq!(addr_space.translate(page), Some(paddr));
+    assert_eq!(addr_space.remove_watchpoint(id), Err(AxError::NotFound));
+    addr_space.discard(page, 0x1000).unwrap();
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_watched_pages_freed_on_drop() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE;
+    addr_space.map_alloc(base, 0x4000, flags, true).unwrap();
+
+    // The watched page has no permissions left and is not mapped.
+    addr_space
+        .add_watchpoint(base + 0x1000, 8, MappingFlags::READ | MappingFlags::EXECUTE)
+        .unwrap();
+    assert!(addr_space.page_table().query(base + 0x1000).is_err());
+    drop(addr_space);
+    assert_eq!(
+        DEALLOC_COUNT.load(Ordering::SeqCst),
+        ALLOC_COUNT.load(Ordering::SeqCst)
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_mem_type() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+    let mem_attr =
+        |addr_space: &AddrSpace<MockHal>, gpa| addr_space.page_table().mem_attr(gpa).unwrap();
+    assert_eq!(MemoryType::of_flags(rw), MemoryType::WriteBack);
+    assert_eq!(
+        MemoryType::of_flags(rw | MappingFlags::DEVICE),
+        MemoryType::DeviceNGnRnE
+    );
+    assert!(MemAttr::default().is_default());
+    let raw = MemAttr::new(MemoryType::WriteBack).with_raw(0b0110);
+    assert_eq!(raw.raw, Some(0b0110));
+    assert!(!raw.is_default());
+
+    // EPT has no device types, they are uncacheable.
+    let cases = [
+        (MemoryType::WriteBack, MemoryType::WriteBack),
+        (MemoryType::WriteThrough, MemoryType::WriteThrough),
+        (MemoryType::WriteCombining, MemoryType::WriteCombining),
+        (MemoryType::Uncached, MemoryType::Uncached),
+        (MemoryType::DeviceNGnRnE, MemoryType::Uncached),
+        (MemoryType::DeviceNGnRE, MemoryType::Uncached),
+    ];
+    for (i, (mem_type, decoded)) in cases.into_iter().enumerate() {
+        let gpa = base + i * 0x1000;
+        addr_space
+            .map_linear(gpa, PhysAddr::from_usize(0x1000 + i * 0x1000), 0x1000, rw)
+            .unwrap();
+        assert_eq!(
+            mem_attr(&addr_space, gpa),
+            MemAttr::new(MemoryType::WriteBack)
+        );
+        let attr = MemAttr::new(mem_type).with_ignore_pat(i % 2 == 0);
+        addr_space.set_mem_attr(gpa, 0x1000, attr).unwrap();
+        assert_eq!(
+            mem_attr(&addr_space, gpa),
+            MemAttr::new(decoded).with_ignore_pat(i % 2 == 0)
+        );
+        let (_, flags, _) = addr_space.page_table().query(gpa).unwrap();
+        assert!(flags.contains(rw));
+    }
+    let area = addr_space
+        .areas()
+        .find(|area| area.range.start == base + 0x1000);
+    assert_eq!(
+        area.unwrap().mem_attr,
+        MemAttr::new(MemoryType::WriteThrough)
+    );
+
+    // Changing permissions keeps the memory type.
+    addr_space
+        .protect(base + 0x1000, 0x1000, MappingFlags::READ)
+        .unwrap();
+    assert_eq!(
+        mem_attr(&addr_space, base + 0x1000).mem_type,
+        Some(MemoryType::WriteThrough)
+    );
+    let (_, flags, _) = addr_space.page_table().query(base + 0x1000).unwrap();
+    assert!(!flags.contains(MappingFlags::WRITE));
+    for (i, (_, decoded)) in cases.into_iter().enumerate() {
+        let gpa = base + i * 0x1000;
+        addr_space.protect(gpa, 0x1000, MappingFlags::READ).unwrap();
+        addr_space.protect(gpa, 0x1000, rw).unwrap();
+        assert_eq!(
+            mem_attr(&addr_space, gpa),
+            MemAttr::new(decoded).with_ignore_pat(i % 2 == 0)
+        );
+    }
+
+    // Back to the default attributes.
+    addr_space
+        .set_mem_attr(base + 0x1000, 0x1000, MemAttr::default())
+        .unwrap();
+    assert_eq!(
+        mem_attr(&addr_space, base + 0x1000),
+        MemAttr::new(MemoryType::WriteBack)
+    );
+
+    let mut snapshot = Vec::new();
+    addr_space.snapshot(&mut snapshot).unwrap();
+    let restored = AddrSpace::<MockHal>::restore(&mut snapshot.as_slice()).unwrap();
+    assert_eq!(
+        mem_attr(&restored, base + 0x2000),
+        MemAttr::new(MemoryType::WriteCombining).with_ignore_pat(true)
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_get_and_clear_accessed() {
+    let (mut addr_space, _base, _size) = setup_test_addr_space();
+    let vaddr = GuestPhysAddr::from_usize(0x10000);
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    addr_space.map_alloc(vaddr, 0x3000, flags, false).unwrap();
+    assert!(addr_space.handle_page_fault(vaddr + 0x1000, MappingFlags::READ));
+
+    // The faulted page is reported, pages never mapped are not.
+    #[cfg(target_arch = "x86_64")]
+    set_ept_flag(&addr_space, vaddr + 0x1000, EPT_ACCESSED);
+    let bitmap = addr_space.get_and_clear_accessed(vaddr, 0x3000).unwrap();
+    assert_eq!(bitmap, [0b10]);
+
+    // The flags are cleared by the scan.
+    let bitmap = addr_space.get_and_clear_accessed(vaddr, 0x3000).unwrap();
+    assert_eq!(bitmap, [0]);
+
+    // Changing permissions does not mark the page accessed.
+    addr_space
+        .protect(vaddr, 0x3000, MappingFlags::READ)
+        .unwrap();
+    let bitmap = addr_space.get_and_clear_accessed(vaddr, 0x3000).unwrap();
+    assert_eq!(bitmap, [0]);
+
+    // Without hardware management, the fault handler sets the flag again.
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        assert!(addr_space.handle_page_fault(vaddr + 0x1000, MappingFlags::READ));
+        let bitmap = addr_space.get_and_clear_accessed(vaddr, 0x3000).unwrap();
+        assert_eq!(bitmap, [0b10]);
+    }
+
+    assert!(addr_space.get_and_clear_accessed(vaddr, 0x100).is_err());
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_vmid() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    assert_eq!(addr_space.vmid(), 0);
+    addr_space.set_vmid(5);
+    assert_eq!(addr_space.vmid(), 5);
+
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    addr_space.map_alloc(base, 0x2000, flags, true).unwrap();
+    addr_space
+        .protect(base, 0x2000, MappingFlags::READ)
+        .unwrap();
+    // Each flush of the VMID is issued, and the VMID is the one the guest
+    // runs with.
+    let flushes = addr_space.page_table().tlb_flushes();
+    addr_space.flush_tlb(Some(base));
+    addr_space.flush_tlb(None);
+    assert_eq!(addr_space.page_table().tlb_flushes(), flushes + 2);
+    #[cfg(target_arch = "aarch64")]
+    assert_eq!(addr_space.stage2_context().vttbr >> 48, 5);
+    addr_space.unmap(base, 0x2000).unwrap();
+    assert_eq!(addr_space.vmid(), 5);
+
+    // The VMID belongs to the VM, a forked address space needs its own.
+    let child = addr_space.fork().unwrap();
+    assert_eq!(child.vmid(), 0);
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_batch() {
+    let (mut addr_space, base, _size) = setup_test_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+
+    // The flushes of a batch, including nested ones, are issued once at the
+    // end of the outermost batch.
+    let flushes = addr_space.page_table().tlb_flushes();
+    let res = addr_space.batch(|aspace| {
+        aspace.map_alloc(base, 0x4000, flags, true)?;
+        aspace.protect(base, 0x2000, MappingFlags::READ)?;
+        aspace.unmap(base + 0x2000, 0x2000)?;
+        aspace.flush_tlb(Some(base));
+        aspace.batch(|aspace| aspace.protect(base, 0x1000, flags))?;
+        assert_eq!(aspace.page_table().tlb_flushes(), flushes);
+        Ok::<_, AxError>(())
+    });
+    assert!(res.is_ok());
+    assert_eq!(addr_space.page_table().tlb_flushes(), flushes + 1);
+
+    // Outside a batch, flushes are issued immediately.
+    addr_space.flush_tlb(Some(base));
+    assert_eq!(addr_space.page_table().tlb_flushes(), flushes + 2);
+    assert_eq!(addr_space.page_table().query(base).unwrap().1, flags);
+    assert!(addr_space.page_table().query(base + 0x2000).is_err());
+
+    // Errors in a batch are returned after the batch ends.
+    let res = addr_space.batch(|aspace| aspace.unmap(base + 0x1234, 0x1000));
+    assert!(res.is_err());
+    addr_space.unmap(base, 0x2000).unwrap();
+
+    // Releasing several pages flushes once.
+    addr_space.map_alloc(base, 0x8000, flags, true).unwrap();
+    let flushes = addr_space.page_table().tlb_flushes();
+    addr_space.discard(base, 0x4000).unwrap();
+    assert_eq!(addr_space.page_table().tlb_flushes(), flushes + 1);
+    let pages: Vec<_> = (4..8).map(|i| base + i * 0x1000).collect();
+    addr_space.balloon_inflate(&pages).unwrap();
+    assert_eq!(addr_space.page_table().tlb_flushes(), flushes + 2);
+    assert_eq!(addr_space.ballooned_pages(base), 4);
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_stage2_context() {
+    let (mut addr_space, _base, _size) = setup_test_addr_space();
+    addr_space.set_vmid(3);
+    let root = addr_space.page_table_root().as_usize() as u64;
+    let context = addr_space.stage2_context();
+
+    // Write-back, 4-level page walk, accessed and dirty flags enabled.
+    #[cfg(target_arch = "x86_64")]
+    {
+        assert_eq!(context.eptp, root | 0x5e);
+        assert_eq!(addr_space.page_table().eptp(), context.eptp);
+    }
+    // 48-bit IPA starting at level 0.
+    #[cfg(target_arch = "aarch64")]
+    {
+        assert_eq!(context.vttbr, root | (3 << 48));
+        assert_eq!(context.vtcr & 0x3f, 16);
+        assert_eq!((context.vtcr >> 6) & 0b11, 0b10);
+    }
+    // Sv48x4 with the VMID.
+    #[cfg(target_arch = "riscv64")]
+    assert_eq!(context.hgatp, (9 << 60) | (3 << 44) | (root >> 12));
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_stage2_fwb() {
+    let (base, size) = (GuestPhysAddr::from_usize(0x10000), 0x10000);
+    let mut addr_space = AddrSpace::<MockHal>::new_empty_with_fwb(4, base, size, true).unwrap();
+    assert!(addr_space.page_table().fwb());
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+    addr_space.map_alloc(base, 0x1000, rw, true).unwrap();
+    addr_space
+        .map_linear(base + 0x1000, PhysAddr::from_usize(0x1000), 0x1000, rw)
+        .unwrap();
+    addr_space
+        .set_mem_attr(base, 0x1000, MemAttr::new(MemoryType::WriteCombining))
+        .unwrap();
+    let (_, flags, _) = addr_space.page_table().query(base).unwrap();
+    assert!(flags.contains(rw) && !flags.contains(MappingFlags::DEVICE));
+    assert_eq!(
+        addr_space.page_table().mem_attr(base + 0x1000),
+        Some(MemAttr::new(MemoryType::WriteBack))
+    );
+
+    // Forked and restored address spaces keep the encoding.
+    let child = addr_space.fork().unwrap();
+    assert!(child.page_table().fwb());
+    drop(child);
+    let mut snapshot = Vec::new();
+    addr_space.snapshot(&mut snapshot).unwrap();
+    let restored = AddrSpace::<MockHal>::restore_with_fwb(&mut snapshot.as_slice(), true).unwrap();
+    assert!(restored.page_table().fwb());
+    assert_eq!(
+        restored.page_table().mem_attr(base).unwrap().mem_type,
+        Some(MemoryType::WriteCombining)
+    );
+}
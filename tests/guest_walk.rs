@@ -0,0 +1,309 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod test_utils;
+
+use axaddrspace::{AddrSpace, GuestPagingMode, GuestPhysAddr, GuestVirtAddr, MappingFlags};
+use axerrno::AxError;
+use axin::axin;
+use test_utils::{MockHal, mock_hal_test};
+
+const ROOT: usize = 0x10000;
+const DATA: usize = 0x14000;
+
+/// Creates an address space whose first pages hold guest page tables.
+fn setup_guest_memory() -> AddrSpace<MockHal> {
+    let base = GuestPhysAddr::from_usize(ROOT);
+    let mut addr_space = AddrSpace::<MockHal>::new_empty(4, base, 0x10000).unwrap();
+    addr_space
+        .map_alloc(base, 0x5000, MappingFlags::READ | MappingFlags::WRITE, true)
+        .unwrap();
+    addr_space
+}
+
+fn entry_ptr(addr_space: &AddrSpace<MockHal>, table: usize, index: usize) -> *mut u64 {
+    let paddr = addr_space
+        .translate(GuestPhysAddr::from_usize(table + index * 8))
+        .unwrap();
+    MockHal::mock_phys_to_virt(paddr).as_mut_ptr() as *mut u64
+}
+
+fn write_entry(addr_space: &AddrSpace<MockHal>, table: usize, index: usize, value: u64) {
+    unsafe { entry_ptr(addr_space, table, index).write(value) };
+}
+
+fn read_entry(addr_space: &AddrSpace<MockHal>, table: usize, index: usize) -> u64 {
+    unsafe { entry_ptr(addr_space, table, index).read() }
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_walk_x86() {
+    const P: u64 = 1;
+    const RW: u64 = 1 << 1;
+    const US: u64 = 1 << 2;
+    const A: u64 = 1 << 5;
+    const D: u64 = 1 << 6;
+    const PS: u64 = 1 << 7;
+
+    let mut addr_space = setup_guest_memory();
+    // GVA 0x80_4020_3123: PML4 1, PDPT 1, PD 1, PT 3.
+    write_entry(&addr_space, ROOT, 1, 0x11000 | P | RW | US);
+    write_entry(&addr_space, 0x11000, 1, 0x12000 | P | RW | US);
+    write_entry(&addr_space, 0x12000, 1, 0x13000 | P | RW | US);
+    write_entry(&addr_space, 0x13000, 3, DATA as u64 | P | US);
+    // GVA 0x80_4040_5678: a writable 2M page at PD 2.
+    write_entry(&addr_space, 0x12000, 2, 0x20_0000 | P | RW | PS);
+
+    let root = GuestPhysAddr::from_usize(ROOT);
+    let walk =
+        |addr_space: &mut AddrSpace<MockHal>, gva: usize, access: MappingFlags, update_ad: bool| {
+            addr_space.translate_guest_virt(
+                root,
+                GuestPagingMode::X86Level4,
+                GuestVirtAddr::from_usize(gva),
+                access,
+                update_ad,
+            )
+        };
+
+    assert_eq!(
+        walk(
+            &mut addr_space,
+            0x80_4020_3123,
+            MappingFlags::READ | MappingFlags::USER,
+            false
+        ),
+        Ok(GuestPhysAddr::from_usize(DATA + 0x123))
+    );
+    assert_eq!(read_entry(&addr_space, 0x13000, 3) & A, 0);
+    assert_eq!(
+        walk(&mut addr_space, 0x80_4020_3123, MappingFlags::WRITE, true),
+        Err(AxError::PermissionDenied)
+    );
+
+    // Accessed flags are set on every level, the dirty flag on the leaf only.
+    assert_eq!(
+        walk(&mut addr_space, 0x80_4040_5678, MappingFlags::WRITE, true),
+        Ok(GuestPhysAddr::from_usize(0x20_5678))
+    );
+    assert_ne!(read_entry(&addr_space, ROOT, 1) & A, 0);
+    assert_eq!(read_entry(&addr_space, ROOT, 1) & D, 0);
+    assert_ne!(read_entry(&addr_space, 0x12000, 2) & (A | D), 0);
+    assert_eq!(
+        walk(
+            &mut addr_space,
+            0x80_4040_5678,
+            MappingFlags::READ | MappingFlags::USER,
+            false
+        ),
+        Err(AxError::PermissionDenied)
+    );
+
+    assert_eq!(
+        walk(&mut addr_space, 0x80_4060_0000, MappingFlags::READ, false),
+        Err(AxError::NotFound)
+    );
+    assert_eq!(
+        walk(&mut addr_space, 0x8000_0000_0000, MappingFlags::READ, false),
+        Err(AxError::InvalidInput)
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_walk_sv39() {
+    const V: u64 = 1;
+    const R: u64 = 1 << 1;
+    const W: u64 = 1 << 2;
+    const U: u64 = 1 << 4;
+    const A: u64 = 1 << 6;
+    const D: u64 = 1 << 7;
+    let pte = |paddr: usize, flags: u64| ((paddr as u64 >> 12) << 10) | flags;
+
+    let mut addr_space = setup_guest_memory();
+    // GVA 0x4020_1123: VPN[2] 1, VPN[1] 1, VPN[0] 1.
+    write_entry(&addr_space, ROOT, 1, pte(0x11000, V));
+    write_entry(&addr_space, 0x11000, 1, pte(0x12000, V));
+    write_entry(&addr_space, 0x12000, 1, pte(DATA, V | R | W));
+    // A misaligned 2M superpage at VPN[1] 2.
+    write_entry(&addr_space, 0x11000, 2, pte(0x20_1000, V | R));
+
+    let walk = |addr_space: &mut AddrSpace<MockHal>, gva: usize, access: MappingFlags| {
+        addr_space.translate_guest_virt(
+            GuestPhysAddr::from_usize(ROOT),
+            GuestPagingMode::Sv39,
+            GuestVirtAddr::from_usize(gva),
+            access,
+            true,
+        )
+    };
+
+    assert_eq!(
+        walk(&mut addr_space, 0x4020_1123, MappingFlags::WRITE),
+        Ok(GuestPhysAddr::from_usize(DATA + 0x123))
+    );
+    assert_eq!(read_entry(&addr_space, 0x12000, 1) & (A | D), A | D);
+    assert_eq!(
+        walk(
+            &mut addr_space,
+            0x4020_1123,
+            MappingFlags::READ | MappingFlags::USER
+        ),
+        Err(AxError::PermissionDenied)
+    );
+    assert_eq!(
+        walk(&mut addr_space, 0x4040_0000, MappingFlags::READ),
+        Err(AxError::InvalidData)
+    );
+
+    write_entry(&addr_space, 0x12000, 1, pte(DATA, V | R | U));
+    assert!(
+        walk(
+            &mut addr_space,
+            0x4020_1123,
+            MappingFlags::READ | MappingFlags::USER
+        )
+        .is_ok()
+    );
+    assert_eq!(
+        walk(&mut addr_space, 0x4020_1123, MappingFlags::EXECUTE),
+        Err(AxError::PermissionDenied)
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_walk_aarch64() {
+    const VALID: u64 = 1;
+    const TABLE: u64 = 1 << 1;
+    const AP_EL0: u64 = 1 << 6;
+    const AF: u64 = 1 << 10;
+    const AP_RO_TABLE: u64 = 1 << 62;
+
+    let mut addr_space = setup_guest_memory();
+    // GVA 0x4020_1123 with 39-bit addresses: L1 1, L2 1, L3 1.
+    write_entry(&addr_space, ROOT, 1, 0x11000 | VALID | TABLE);
+    write_entry(
+        &addr_space,
+        0x11000,
+        1,
+        0x12000 | VALID | TABLE | AP_RO_TABLE,
+    );
+    write_entry(
+        &addr_space,
+        0x12000,
+        1,
+        DATA as u64 | VALID | TABLE | AP_EL0,
+    );
+    // A 2M block at L2 2.
+    write_entry(&addr_space, 0x11000, 2, 0x20_0000 | VALID | AF);
+
+    let walk = |addr_space: &mut AddrSpace<MockHal>, gva: usize, access: MappingFlags| {
+        addr_space.translate_guest_virt(
+            GuestPhysAddr::from_usize(ROOT),
+            GuestPagingMode::Aarch64 { va_bits: 39 },
+            GuestVirtAddr::from_usize(gva),
+            access,
+            true,
+        )
+    };
+
+    assert_eq!(
+        walk(
+            &mut addr_space,
+            0x4020_1123,
+            MappingFlags::READ | MappingFlags::USER
+        ),
+        Ok(GuestPhysAddr::from_usize(DATA + 0x123))
+    );
+    assert_ne!(read_entry(&addr_space, 0x12000, 1) & AF, 0);
+    // Write permission is removed by the table descriptor.
+    assert_eq!(
+        walk(&mut addr_space, 0x4020_1123, MappingFlags::WRITE),
+        Err(AxError::PermissionDenied)
+    );
+
+    assert_eq!(
+        walk(&mut addr_space, 0x4041_0000, MappingFlags::WRITE),
+        Ok(GuestPhysAddr::from_usize(0x21_0000))
+    );
+    assert_eq!(
+        walk(
+            &mut addr_space,
+            0x4041_0000,
+            MappingFlags::READ | MappingFlags::USER
+        ),
+        Err(AxError::PermissionDenied)
+    );
+    assert_eq!(
+        walk(&mut addr_space, 0x4060_0000, MappingFlags::READ),
+        Err(AxError::NotFound)
+    );
+}
+
+#[test]
+#[axin(decorator(mock_hal_test))]
+fn test_walk_read_only_tables() {
+    const V: u64 = 1;
+    const R: u64 = 1 << 1;
+    const W: u64 = 1 << 2;
+    const A: u64 = 1 << 6;
+    const D: u64 = 1 << 7;
+    let pte = |paddr: usize, flags: u64| ((paddr as u64 >> 12) << 10) | flags;
+    let walk = |addr_space: &mut AddrSpace<MockHal>, root: usize, gva: usize, update_ad: bool| {
+        addr_space.translate_guest_virt(
+            GuestPhysAddr::from_usize(root),
+            GuestPagingMode::Sv39,
+            GuestVirtAddr::from_usize(gva),
+            MappingFlags::WRITE,
+            update_ad,
+        )
+    };
+
+    let mut addr_space = setup_guest_memory();
+    write_entry(&addr_space, ROOT, 1, pte(0x11000, V));
+    write_entry(&addr_space, 0x11000, 1, pte(0x12000, V));
+    write_entry(&addr_space, 0x12000, 1, pte(DATA, V | R | W));
+
+    // The guest page tables of a forked address space are shared
+    // copy-on-write, flags are only set in the private copy of the child.
+    let mut child = addr_space.fork().unwrap();
+    assert_eq!(
+        walk(&mut child, ROOT, 0x4020_1123, true),
+        Ok(GuestPhysAddr::from_usize(DATA + 0x123))
+    );
+    assert_eq!(read_entry(&child, 0x12000, 1) & (A | D), A | D);
+    assert_eq!(read_entry(&addr_space, 0x12000, 1) & (A | D), 0);
+    let table = GuestPhysAddr::from_usize(0x12000);
+    assert_ne!(child.translate(table), addr_space.translate(table));
+    drop(child);
+
+    // Guest page tables in ROM are never written.
+    const ROM: usize = 0x15000;
+    let mut rom = [0u8; 0x1000];
+    rom[8..16].copy_from_slice(&pte(0x4000_0000, V | R | W).to_le_bytes());
+    addr_space
+        .map_rom(GuestPhysAddr::from_usize(ROM), &rom, MappingFlags::READ)
+        .unwrap();
+    assert_eq!(
+        walk(&mut addr_space, ROM, 0x4000_1000, false),
+        Ok(GuestPhysAddr::from_usize(0x4000_1000))
+    );
+    assert_eq!(
+        walk(&mut addr_space, ROM, 0x4000_1000, true),
+        Err(AxError::BadAddress)
+    );
+    assert_eq!(read_entry(&addr_space, ROM, 1) & (A | D), 0);
+}
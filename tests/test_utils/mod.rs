@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use axaddrspace::{AxMmHal, HostPhysAddr, HostVirtAddr};
+use axaddrspace::{AxMmHal, AxNumaHal, HostPhysAddr, HostVirtAddr};
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use memory_addr::{PhysAddr, VirtAddr};
@@ -90,18 +90,22 @@ impl PagingHandler for MockHal {
         Self::mock_alloc_frame()
     }
 
-    fn alloc_frames(count: usize, _align: usize) -> Option<PhysAddr> {
+    fn alloc_frames(count: usize, align: usize) -> Option<PhysAddr> {
         if count == 0 {
             return Some(PhysAddr::from(0));
         }
-        // For simplicity, just allocate frames sequentially
-        let first = Self::mock_alloc_frame()?;
-        for _ in 1..count {
-            if Self::mock_alloc_frame().is_none() {
-                return None;
-            }
+        if ALLOC_SHOULD_FAIL.load(Ordering::SeqCst) {
+            return None;
+        }
+        // For simplicity, just allocate frames sequentially, and only if all
+        // of them are available.
+        let first = NEXT_PADDR.load(Ordering::SeqCst).next_multiple_of(align);
+        if first + count * PAGE_SIZE > MEMORY_LEN + BASE_PADDR {
+            return None;
         }
-        Some(first)
+        NEXT_PADDR.store(first + count * PAGE_SIZE, Ordering::SeqCst);
+        ALLOC_COUNT.fetch_add(count, Ordering::SeqCst);
+        Some(PhysAddr::from_usize(first))
     }
 
     fn dealloc_frame(_paddr: PhysAddr) {
@@ -120,6 +124,99 @@ impl PagingHandler for MockHal {
     }
 }
 
+/// The simulated memory is split into two host NUMA nodes of equal size.
+pub const NODE_LEN: usize = MEMORY_LEN / 2;
+
+impl AxNumaHal for MockHal {
+    fn alloc_frames_on_node(count: usize, align: usize, node: usize) -> Option<HostPhysAddr> {
+        if ALLOC_SHOULD_FAIL.load(Ordering::SeqCst) || node > 1 {
+            return None;
+        }
+        // Skip to the node if needed; frames of a node already passed are lost.
+        let node_start = BASE_PADDR + node * NODE_LEN;
+        let node_end = node_start + NODE_LEN;
+        let first = NEXT_PADDR
+            .load(Ordering::SeqCst)
+            .max(node_start)
+            .next_multiple_of(align);
+        if first + count * PAGE_SIZE > node_end {
+            return None;
+        }
+        NEXT_PADDR.store(first + count * PAGE_SIZE, Ordering::SeqCst);
+        ALLOC_COUNT.fetch_add(count, Ordering::SeqCst);
+        Some(PhysAddr::from_usize(first))
+    }
+
+    fn node_of(paddr: HostPhysAddr) -> Option<usize> {
+        let paddr = paddr.as_usize();
+        (BASE_PADDR..BASE_PADDR + MEMORY_LEN)
+            .contains(&paddr)
+            .then(|| (paddr - BASE_PADDR) / NODE_LEN)
+    }
+}
+
+/// The starting physical address of the simulated memory of [`MockHugeHal`],
+/// aligned to 2M.
+pub const HUGE_BASE_PADDR: usize = 0x4000_0000;
+
+/// Total length of the simulated memory of [`MockHugeHal`], enough for two 2M
+/// blocks and the page tables allocated between them.
+pub const HUGE_MEMORY_LEN: usize = 0xa0_0000;
+
+/// Static variable to simulate the allocator of [`MockHugeHal`].
+pub static NEXT_HUGE_PADDR: AtomicUsize = AtomicUsize::new(HUGE_BASE_PADDR);
+
+lazy_static! {
+    /// The simulated memory of [`MockHugeHal`], aligned to 2M.
+    static ref HUGE_MEMORY: usize = {
+        let layout = std::alloc::Layout::from_size_align(HUGE_MEMORY_LEN, 0x20_0000).unwrap();
+        unsafe { std::alloc::alloc_zeroed(layout) as usize }
+    };
+}
+
+/// A mock paging handler with enough memory for 2M huge pages.
+///
+/// It shares the allocation counters of [`MockHal`], and is reset with it.
+pub struct MockHugeHal;
+
+impl PagingHandler for MockHugeHal {
+    fn alloc_frame() -> Option<PhysAddr> {
+        Self::alloc_frames(1, PAGE_SIZE)
+    }
+
+    fn alloc_frames(count: usize, align: usize) -> Option<PhysAddr> {
+        if ALLOC_SHOULD_FAIL.load(Ordering::SeqCst) {
+            return None;
+        }
+        let first = NEXT_HUGE_PADDR
+            .load(Ordering::SeqCst)
+            .next_multiple_of(align);
+        if first + count * PAGE_SIZE > HUGE_BASE_PADDR + HUGE_MEMORY_LEN {
+            return None;
+        }
+        NEXT_HUGE_PADDR.store(first + count * PAGE_SIZE, Ordering::SeqCst);
+        ALLOC_COUNT.fetch_add(count, Ordering::SeqCst);
+        Some(PhysAddr::from_usize(first))
+    }
+
+    fn dealloc_frame(_paddr: PhysAddr) {
+        DEALLOC_COUNT.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn dealloc_frames(_paddr: PhysAddr, count: usize) {
+        DEALLOC_COUNT.fetch_add(count, Ordering::SeqCst);
+    }
+
+    fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+        let paddr = paddr.as_usize();
+        assert!(
+            (HUGE_BASE_PADDR..HUGE_BASE_PADDR + HUGE_MEMORY_LEN).contains(&paddr),
+            "Physical address {paddr:#x} out of bounds"
+        );
+        VirtAddr::from_usize(*HUGE_MEMORY + paddr - HUGE_BASE_PADDR)
+    }
+}
+
 /// A utility decorator for test functions that require the MockHal state to be reset before execution.
 pub fn mock_hal_test<F, R>(test_fn: F) -> R
 where
@@ -131,6 +228,7 @@ where
 }
 
 /// A utility function to verify the number of deallocations performed by the MockHal.
+#[allow(dead_code)]
 pub fn test_dealloc_count(expected: usize) {
     let actual_dealloc_count = DEALLOC_COUNT.load(Ordering::SeqCst);
     assert_eq!(
@@ -188,6 +286,7 @@ impl MockHal {
     }
 
     /// Helper function to control the simulated allocation failure.
+    #[allow(dead_code)]
     pub fn set_alloc_fail(fail: bool) {
         ALLOC_SHOULD_FAIL.store(fail, Ordering::SeqCst);
     }
@@ -196,6 +295,7 @@ impl MockHal {
     /// This is crucial for ensuring test isolation between individual test functions.
     pub fn reset_state() {
         NEXT_PADDR.store(BASE_PADDR, Ordering::SeqCst);
+        NEXT_HUGE_PADDR.store(HUGE_BASE_PADDR, Ordering::SeqCst);
         ALLOC_SHOULD_FAIL.store(false, Ordering::SeqCst);
         ALLOC_COUNT.store(0, Ordering::SeqCst);
         DEALLOC_COUNT.store(0, Ordering::SeqCst);